@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS call;
+DROP TABLE IF EXISTS category;
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS category (
+    id SERIAL PRIMARY KEY,
+    title VARCHAR(255) NOT NULL,
+    points TEXT[]
+);
+
+CREATE TABLE IF NOT EXISTS call (
+    id UUID PRIMARY KEY NOT NULL,
+    name VARCHAR(255),
+    location VARCHAR(255),
+    emotional_tone VARCHAR(50),
+    text TEXT NOT NULL,
+    categories TEXT[]
+);
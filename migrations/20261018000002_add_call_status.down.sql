@@ -0,0 +1,7 @@
+DELETE FROM call WHERE text IS NULL;
+ALTER TABLE call ALTER COLUMN text SET NOT NULL;
+ALTER TABLE call DROP COLUMN IF EXISTS updated_at;
+ALTER TABLE call DROP COLUMN IF EXISTS created_at;
+ALTER TABLE call DROP COLUMN IF EXISTS error;
+ALTER TABLE call DROP COLUMN IF EXISTS status;
+ALTER TABLE call DROP COLUMN IF EXISTS audio_url;
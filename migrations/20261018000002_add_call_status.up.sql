@@ -0,0 +1,8 @@
+-- Calls created before processing moved to the background workers are already done
+ALTER TABLE call ADD COLUMN IF NOT EXISTS audio_url TEXT;
+ALTER TABLE call ADD COLUMN IF NOT EXISTS status VARCHAR(20) NOT NULL DEFAULT 'done';
+ALTER TABLE call ADD COLUMN IF NOT EXISTS error TEXT;
+ALTER TABLE call ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE call ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE call ALTER COLUMN status SET DEFAULT 'queued';
+ALTER TABLE call ALTER COLUMN text DROP NOT NULL;
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS reindex (
+    job_id UUID PRIMARY KEY NOT NULL REFERENCES job (id) ON DELETE CASCADE,
+    category_id INT REFERENCES category (id) ON DELETE SET NULL,
+    dry_run BOOLEAN NOT NULL DEFAULT FALSE,
+    status VARCHAR(20) NOT NULL DEFAULT 'queued',
+    error TEXT,
+    total INT NOT NULL DEFAULT 0,
+    processed INT NOT NULL DEFAULT 0,
+    changed INT NOT NULL DEFAULT 0,
+    gained UUID[] NOT NULL DEFAULT '{}',
+    lost UUID[] NOT NULL DEFAULT '{}',
+    cancel_requested BOOLEAN NOT NULL DEFAULT FALSE,
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,13 @@
+ALTER TABLE call ADD COLUMN IF NOT EXISTS categories TEXT[];
+
+UPDATE call
+SET categories = assigned.titles
+FROM (
+    SELECT call_category.call_id, array_agg(category.title::text) AS titles
+    FROM call_category
+    JOIN category ON category.id = call_category.category_id
+    GROUP BY call_category.call_id
+) AS assigned
+WHERE call.id = assigned.call_id;
+
+DROP TABLE IF EXISTS call_category;
@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS call_category (
+    call_id UUID NOT NULL REFERENCES call (id) ON DELETE CASCADE,
+    category_id INT NOT NULL REFERENCES category (id) ON DELETE CASCADE,
+    score DOUBLE PRECISION,
+    matched_label TEXT,
+    source VARCHAR(20) NOT NULL,
+    model_version VARCHAR(100),
+    PRIMARY KEY (call_id, category_id)
+);
+CREATE INDEX IF NOT EXISTS call_category_category_id_idx ON call_category (category_id);
+
+-- Move categories stored as TEXT[] of titles into call_category
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'call' AND column_name = 'categories'
+    ) THEN
+        INSERT INTO call_category (call_id, category_id, matched_label, source)
+        SELECT DISTINCT ON (call.id, category.id) call.id, category.id, category.title, 'migration'
+        FROM call
+        CROSS JOIN LATERAL unnest(call.categories) AS assigned (title)
+        JOIN category ON category.title = assigned.title
+        ON CONFLICT DO NOTHING;
+
+        ALTER TABLE call DROP COLUMN categories;
+    END IF;
+END $$;
@@ -0,0 +1,2 @@
+-- Removed duplicates are not restored
+SELECT 1;
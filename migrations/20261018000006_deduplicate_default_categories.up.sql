@@ -0,0 +1,32 @@
+-- Every boot used to run the seed again, leaving copies of the default categories. Keep the
+-- oldest copy of each and move call assignments over to it.
+CREATE TEMPORARY TABLE duplicate_category ON COMMIT DROP AS
+SELECT id, keep_id
+FROM (
+    SELECT id, MIN(id) OVER (PARTITION BY title, points) AS keep_id
+    FROM category
+    WHERE title IN (
+        'Visa and Passport Services',
+        'Diplomatic Inquiries',
+        'Travel Advisories',
+        'Consular Assistance',
+        'Trade and Economic Cooperation'
+    )
+) AS copies
+WHERE id <> keep_id;
+
+INSERT INTO call_category (call_id, category_id, score, matched_label, source, model_version)
+SELECT call_category.call_id, duplicate_category.keep_id, call_category.score,
+    call_category.matched_label, call_category.source, call_category.model_version
+FROM call_category
+JOIN duplicate_category ON duplicate_category.id = call_category.category_id
+ON CONFLICT (call_id, category_id) DO NOTHING;
+
+UPDATE reindex
+SET category_id = duplicate_category.keep_id
+FROM duplicate_category
+WHERE reindex.category_id = duplicate_category.id;
+
+DELETE FROM category
+USING duplicate_category
+WHERE category.id = duplicate_category.id;
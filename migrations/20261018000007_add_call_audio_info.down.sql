@@ -0,0 +1,4 @@
+ALTER TABLE call DROP COLUMN IF EXISTS duration_secs;
+ALTER TABLE call DROP COLUMN IF EXISTS channels;
+ALTER TABLE call DROP COLUMN IF EXISTS sample_rate;
+ALTER TABLE call DROP COLUMN IF EXISTS codec;
@@ -0,0 +1,5 @@
+-- Properties of the original audio, recorded when it is decoded
+ALTER TABLE call ADD COLUMN IF NOT EXISTS codec VARCHAR(50);
+ALTER TABLE call ADD COLUMN IF NOT EXISTS sample_rate INT;
+ALTER TABLE call ADD COLUMN IF NOT EXISTS channels INT;
+ALTER TABLE call ADD COLUMN IF NOT EXISTS duration_secs DOUBLE PRECISION;
@@ -0,0 +1,11 @@
+-- Timed pieces of a transcript, in the order Whisper produced them
+CREATE TABLE IF NOT EXISTS call_segment (
+    id SERIAL PRIMARY KEY,
+    call_id UUID NOT NULL REFERENCES call (id) ON DELETE CASCADE,
+    position INT NOT NULL,
+    start_ms INT NOT NULL,
+    end_ms INT NOT NULL,
+    text TEXT NOT NULL,
+    avg_probability DOUBLE PRECISION,
+    UNIQUE (call_id, position)
+);
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS call_speaker;
+ALTER TABLE call DROP COLUMN IF EXISTS diarization;
+ALTER TABLE call_segment DROP COLUMN IF EXISTS speaker;
@@ -0,0 +1,15 @@
+-- Speaker of each transcript segment, numbered from 1 in order of first appearance
+ALTER TABLE call_segment ADD COLUMN IF NOT EXISTS speaker INT;
+-- How the speakers were told apart: channels, clustering or single
+ALTER TABLE call ADD COLUMN IF NOT EXISTS diarization VARCHAR(20);
+
+-- Sentiment and entities of what each speaker said
+CREATE TABLE IF NOT EXISTS call_speaker (
+    call_id UUID NOT NULL REFERENCES call (id) ON DELETE CASCADE,
+    speaker INT NOT NULL,
+    talk_time_ms INT NOT NULL,
+    emotional_tone VARCHAR(50),
+    name TEXT,
+    location TEXT,
+    PRIMARY KEY (call_id, speaker)
+);
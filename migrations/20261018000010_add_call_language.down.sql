@@ -0,0 +1,2 @@
+ALTER TABLE call DROP COLUMN IF EXISTS language_probability;
+ALTER TABLE call DROP COLUMN IF EXISTS language;
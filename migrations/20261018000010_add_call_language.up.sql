@@ -0,0 +1,4 @@
+-- Spoken language of the call as a Whisper language code. The probability is only known
+-- when the language was detected rather than requested.
+ALTER TABLE call ADD COLUMN IF NOT EXISTS language VARCHAR(10);
+ALTER TABLE call ADD COLUMN IF NOT EXISTS language_probability DOUBLE PRECISION;
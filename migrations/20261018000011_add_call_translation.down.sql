@@ -0,0 +1 @@
+ALTER TABLE call DROP COLUMN IF EXISTS translation;
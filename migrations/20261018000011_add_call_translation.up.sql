@@ -0,0 +1,2 @@
+-- English translation of calls in other languages
+ALTER TABLE call ADD COLUMN IF NOT EXISTS translation TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE call DROP COLUMN IF EXISTS chunks_total;
+ALTER TABLE call DROP COLUMN IF EXISTS chunks_done;
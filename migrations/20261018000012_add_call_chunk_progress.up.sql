@@ -0,0 +1,3 @@
+-- Progress of chunked transcription and translation
+ALTER TABLE call ADD COLUMN IF NOT EXISTS chunks_done INT;
+ALTER TABLE call ADD COLUMN IF NOT EXISTS chunks_total INT;
@@ -0,0 +1,18 @@
+-- Named entities found in a call, one row per mention
+CREATE TABLE IF NOT EXISTS call_entity (
+    id SERIAL PRIMARY KEY,
+    call_id UUID NOT NULL REFERENCES call (id) ON DELETE CASCADE,
+    segment_id INT REFERENCES call_segment (id) ON DELETE CASCADE,
+    -- PER, LOC, ORG or MISC
+    entity_type VARCHAR(10) NOT NULL,
+    text TEXT NOT NULL,
+    normalized TEXT NOT NULL,
+    -- Character offsets into the segment text, end exclusive. Calls analyzed through their
+    -- translation index the English translation of the segment instead.
+    start_char INT NOT NULL,
+    end_char INT NOT NULL,
+    translation BOOLEAN NOT NULL DEFAULT FALSE,
+    score DOUBLE PRECISION NOT NULL
+);
+CREATE INDEX IF NOT EXISTS call_entity_call_id_idx ON call_entity (call_id);
+CREATE INDEX IF NOT EXISTS call_entity_normalized_idx ON call_entity (entity_type, normalized);
@@ -0,0 +1,3 @@
+ALTER TABLE call_entity DROP COLUMN IF EXISTS entity_id;
+DROP TABLE IF EXISTS entity_alias;
+DROP TABLE IF EXISTS entity;
@@ -0,0 +1,20 @@
+-- Canonical people, organizations and locations the mentions in call_entity are linked to
+CREATE TABLE IF NOT EXISTS entity (
+    id SERIAL PRIMARY KEY,
+    entity_type VARCHAR(10) NOT NULL,
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Matching keys of the names an entity is mentioned by, see `api::registry`. A key belongs to
+-- a single entity of its type.
+CREATE TABLE IF NOT EXISTS entity_alias (
+    entity_type VARCHAR(10) NOT NULL,
+    key TEXT NOT NULL,
+    entity_id INT NOT NULL REFERENCES entity (id) ON DELETE CASCADE,
+    PRIMARY KEY (entity_type, key)
+);
+CREATE INDEX IF NOT EXISTS entity_alias_entity_id_idx ON entity_alias (entity_id);
+
+ALTER TABLE call_entity ADD COLUMN IF NOT EXISTS entity_id INT REFERENCES entity (id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS call_entity_entity_id_idx ON call_entity (entity_id);
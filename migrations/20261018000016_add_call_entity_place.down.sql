@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS call_entity_geoname_id_idx;
+ALTER TABLE call_entity DROP COLUMN IF EXISTS longitude;
+ALTER TABLE call_entity DROP COLUMN IF EXISTS latitude;
+ALTER TABLE call_entity DROP COLUMN IF EXISTS admin_region;
+ALTER TABLE call_entity DROP COLUMN IF EXISTS country_code;
+ALTER TABLE call_entity DROP COLUMN IF EXISTS place;
+ALTER TABLE call_entity DROP COLUMN IF EXISTS geoname_id;
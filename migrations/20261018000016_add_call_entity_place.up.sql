@@ -0,0 +1,8 @@
+-- Gazetteer place a location mention resolved to, see `api::gazetteer`
+ALTER TABLE call_entity ADD COLUMN IF NOT EXISTS geoname_id BIGINT;
+ALTER TABLE call_entity ADD COLUMN IF NOT EXISTS place TEXT;
+ALTER TABLE call_entity ADD COLUMN IF NOT EXISTS country_code VARCHAR(2);
+ALTER TABLE call_entity ADD COLUMN IF NOT EXISTS admin_region TEXT;
+ALTER TABLE call_entity ADD COLUMN IF NOT EXISTS latitude DOUBLE PRECISION;
+ALTER TABLE call_entity ADD COLUMN IF NOT EXISTS longitude DOUBLE PRECISION;
+CREATE INDEX IF NOT EXISTS call_entity_geoname_id_idx ON call_entity (geoname_id);
@@ -0,0 +1,2 @@
+ALTER TABLE call DROP COLUMN IF EXISTS extraction;
+ALTER TABLE category DROP COLUMN IF EXISTS extraction_schema;
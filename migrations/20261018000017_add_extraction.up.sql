@@ -0,0 +1,4 @@
+-- Fields answered over the transcript of calls in the category, a list of {name, question}
+ALTER TABLE category ADD COLUMN IF NOT EXISTS extraction_schema JSONB;
+-- Answers to the fields of the call's categories with their confidence
+ALTER TABLE call ADD COLUMN IF NOT EXISTS extraction JSONB;
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS call_streaming_idx;
@@ -0,0 +1,2 @@
+-- Streams whose heartbeat stopped are looked up on every worker poll
+CREATE INDEX IF NOT EXISTS call_streaming_idx ON call (updated_at) WHERE status = 'streaming';
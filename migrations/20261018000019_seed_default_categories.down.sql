@@ -0,0 +1,2 @@
+-- Seeded categories are kept, calls may be assigned to them
+SELECT 1;
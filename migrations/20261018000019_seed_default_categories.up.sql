@@ -0,0 +1,14 @@
+-- Default categories, inserted once. Databases seeded on every boot before this migration
+-- already have them, and categories an admin deleted or renamed since are not brought back
+-- by later boots.
+INSERT INTO category (title, points)
+SELECT seed.title, seed.points
+FROM (
+    VALUES
+        ('Visa and Passport Services', ARRAY['Border crossing', 'International documentation']),
+        ('Diplomatic Inquiries', ARRAY['Embassy services', 'Foreign relations']),
+        ('Travel Advisories', ARRAY['Travel restrictions', 'Health and safety guidelines']),
+        ('Consular Assistance', ARRAY['Emergency assistance', 'Legal aid']),
+        ('Trade and Economic Cooperation', ARRAY['Bilateral trade', 'Investment opportunities'])
+) AS seed (title, points)
+WHERE NOT EXISTS (SELECT 1 FROM category WHERE category.title = seed.title);
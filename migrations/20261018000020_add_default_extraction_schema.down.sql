@@ -0,0 +1,2 @@
+-- The schema may have been edited since, it is kept
+SELECT 1;
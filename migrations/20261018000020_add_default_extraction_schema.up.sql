@@ -0,0 +1,10 @@
+-- Fields extracted from visa calls, set once. A schema edited or emptied since, or a
+-- deleted visa category, is left alone.
+UPDATE category
+SET extraction_schema = '[
+    {"name": "applicant_name", "question": "What is the name of the visa applicant?"},
+    {"name": "destination_country", "question": "Which country is the caller traveling to?"},
+    {"name": "visa_type", "question": "What type of visa is the caller applying for?"},
+    {"name": "travel_date", "question": "When is the caller traveling?"}
+]'::jsonb
+WHERE title = 'Visa and Passport Services' AND extraction_schema IS NULL;
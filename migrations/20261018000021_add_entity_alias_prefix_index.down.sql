@@ -0,0 +1 @@
+DROP INDEX IF EXISTS entity_alias_prefix_idx;
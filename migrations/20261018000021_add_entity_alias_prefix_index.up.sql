@@ -0,0 +1,4 @@
+-- Candidates of the fuzzy match in `api::registry` share the first letter of the key and are
+-- close in length
+CREATE INDEX IF NOT EXISTS entity_alias_prefix_idx
+ON entity_alias (entity_type, LEFT(key, 1), LENGTH(key));
@@ -1,106 +1,426 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use rust_bert::pipelines::common::{ModelResource, ModelType};
-use rust_bert::pipelines::ner::NERModel;
+use rust_bert::pipelines::question_answering::{QuestionAnsweringConfig, QuestionAnsweringModel};
 use rust_bert::pipelines::sentiment::{SentimentConfig, SentimentModel};
-use rust_bert::pipelines::token_classification::TokenClassificationConfig;
+use rust_bert::pipelines::sequence_classification::{
+    SequenceClassificationConfig, SequenceClassificationModel,
+};
+use rust_bert::pipelines::token_classification::{
+    TokenClassificationConfig, TokenClassificationModel,
+};
 use rust_bert::pipelines::zero_shot_classification::{
     ZeroShotClassificationConfig, ZeroShotClassificationModel,
 };
-use simple_transcribe_rs::transcriber::Transcriber;
-use simple_transcribe_rs::{model_handler, transcriber};
+use simple_transcribe_rs::model_handler;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use whisper_rs::{WhisperContext, WhisperContextParameters};
+
+use crate::api::gazetteer::Gazetteer;
+use crate::config::env_or;
+use crate::errors::PanicError;
+
+// Recorded with the category assignments it produced
+pub const ZERO_SHOT_MODEL: &str = "bart-large-mnli";
+pub const MULTILINGUAL_ZERO_SHOT_MODEL: &str = "xlm-roberta-large-xnli";
 
+// Languages the multilingual models are used for, as Whisper language codes
+const DEFAULT_MULTILINGUAL_LANGUAGES: &str = "uk,ru,pl,de,fr,es,it,pt,nl";
+
+// Every model runs on its own replica threads, so inference never blocks the actix executor
+// and a transcription doesn't hold up zero-shot classification.
+#[derive(Clone)]
 pub struct AppState {
-    pub sentiment: SentimentModel,
-    pub ner: NERModel,
-    pub zero_shot: ZeroShotClassificationModel,
-    pub transcriber: Transcriber,
+    pub english: AnalysisModels,
+    // Only loaded with MULTILINGUAL_MODELS=true
+    pub multilingual: Option<AnalysisModels>,
+    multilingual_languages: Arc<Vec<String>>,
+    pub transcriber: ModelPool<WhisperContext>,
+    pub gazetteer: Arc<Gazetteer>,
 }
 impl AppState {
-    pub async fn new() -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(Self {
-            sentiment: sentiment_model()
+    pub async fn new() -> Self {
+        let english = AnalysisModels {
+            sentiment: ModelPool::start("sentiment", sentiment_model)
                 .await
                 .expect("sentiment model config error"),
-            ner: ner_model().await.expect("ner model config error"),
-            zero_shot: zero_shot_model()
+            ner: ModelPool::start("ner", ner_model)
+                .await
+                .expect("ner model config error"),
+            zero_shot: ModelPool::start("zero_shot", zero_shot_model)
                 .await
                 .expect("zero shot model config error"),
-            transcriber: trancriber_model().await,
-        }))
+            zero_shot_name: ZERO_SHOT_MODEL,
+            qa: ModelPool::start("qa", qa_model)
+                .await
+                .expect("question answering model config error"),
+        };
+        let multilingual = if env_or("MULTILINGUAL_MODELS", false) {
+            Some(AnalysisModels {
+                sentiment: ModelPool::start("multilingual_sentiment", multilingual_sentiment_model)
+                    .await
+                    .expect("multilingual sentiment model config error"),
+                ner: ModelPool::start("multilingual_ner", multilingual_ner_model)
+                    .await
+                    .expect("multilingual ner model config error"),
+                zero_shot: ModelPool::start("multilingual_zero_shot", multilingual_zero_shot_model)
+                    .await
+                    .expect("multilingual zero shot model config error"),
+                zero_shot_name: MULTILINGUAL_ZERO_SHOT_MODEL,
+                qa: ModelPool::start("multilingual_qa", multilingual_qa_model)
+                    .await
+                    .expect("multilingual question answering model config error"),
+            })
+        } else {
+            None
+        };
+        let multilingual_languages = env_or(
+            "MULTILINGUAL_LANGUAGES",
+            DEFAULT_MULTILINGUAL_LANGUAGES.to_string(),
+        )
+        .split(',')
+        .map(|language| language.trim().to_ascii_lowercase())
+        .filter(|language| !language.is_empty())
+        .collect();
+
+        Self {
+            english,
+            multilingual,
+            multilingual_languages: Arc::new(multilingual_languages),
+            transcriber: ModelPool::start("transcriber", trancriber_model)
+                .await
+                .expect("transcriber model config error"),
+            gazetteer: Arc::new(Gazetteer::from_env()),
+        }
     }
+
+    // Models able to analyze a transcript in `language`. None when no loaded model supports
+    // it, English models would only produce noise on such text.
+    pub fn models(&self, language: Option<&str>) -> Option<&AnalysisModels> {
+        match language {
+            // Calls transcribed before languages were detected are English
+            None | Some("en") => Some(&self.english),
+            Some(language) => self.multilingual.as_ref().filter(|_| {
+                self.multilingual_languages
+                    .iter()
+                    .any(|known| known == language)
+            }),
+        }
+    }
+}
+
+// Sentiment, NER, zero-shot and question answering models covering a set of languages
+#[derive(Clone)]
+pub struct AnalysisModels {
+    pub sentiment: ModelPool<SentimentClassifier>,
+    // Used without sub-token consolidation, see `api::entities`
+    pub ner: ModelPool<TokenClassificationModel>,
+    pub zero_shot: ModelPool<ZeroShotClassificationModel>,
+    // Recorded with the category assignments
+    pub zero_shot_name: &'static str,
+    // Answers the extraction schemas of categories
+    pub qa: ModelPool<QuestionAnsweringModel>,
 }
 
-async fn trancriber_model() -> Transcriber {
-    let m = model_handler::ModelHandler::new("small", "models").await;
-    transcriber::Transcriber::new(m)
+// The English model is a binary SST-2 classifier, multilingual models label text
+// negative, neutral or positive
+pub enum SentimentClassifier {
+    Binary(SentimentModel),
+    Labeled(SequenceClassificationModel),
 }
 
-async fn sentiment_model() -> Result<SentimentModel> {
-    let sentiment_classifier = actix_web::web::block(move || {
-        let sentiment_config = SentimentConfig {
-            model_type: ModelType::DistilBert,
-            model_resource: ModelResource::Torch(Box::from(PathBuf::from(
-                "./models/distilbert-base-uncased-finetuned-sst-2-english/rust_model.ot",
-            ))),
-            config_resource: PathBuf::from(
-                "./models/distilbert-base-uncased-finetuned-sst-2-english/config.json",
-            )
-            .to_path_buf()
+// Returns true when the job panicked and the model has to be rebuilt
+type ModelJob<M> = Box<dyn FnOnce(&M) -> bool + Send>;
+
+const RELOAD_DELAY: Duration = Duration::from_secs(5);
+
+// Replicas of a model living on dedicated OS threads. Requests go through a bounded channel
+// shared by the replicas, so callers wait asynchronously when every replica is busy.
+pub struct ModelPool<M> {
+    name: &'static str,
+    replicas: usize,
+    sender: mpsc::Sender<ModelJob<M>>,
+}
+
+impl<M> Clone for ModelPool<M> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            replicas: self.replicas,
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<M: 'static> ModelPool<M> {
+    // Load `<NAME>_REPLICAS` copies of the model (1 by default), each on its own thread
+    async fn start<F>(name: &'static str, load: F) -> Result<Self>
+    where
+        F: Fn() -> Result<M> + Send + Sync + 'static,
+    {
+        let replicas = env_or(&format!("{}_REPLICAS", name.to_uppercase()), 1usize).max(1);
+        let capacity = env_or("MODEL_QUEUE_CAPACITY", 64usize).max(1);
+
+        let (sender, receiver) = mpsc::channel::<ModelJob<M>>(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let load = Arc::new(load);
+
+        let mut loading = Vec::with_capacity(replicas);
+        for replica in 0..replicas {
+            let (ready, loaded) = oneshot::channel::<Result<()>>();
+            let receiver = receiver.clone();
+            let load = load.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, replica))
+                .spawn(move || {
+                    let mut model = match load_model(name, load.as_ref()) {
+                        Ok(model) => model,
+                        Err(err) => {
+                            let _ = ready.send(Err(err));
+                            return;
+                        }
+                    };
+                    let _ = ready.send(Ok(()));
+
+                    loop {
+                        // The receiver is only held while waiting, idle replicas queue up behind it
+                        let job = receiver
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .blocking_recv();
+                        let Some(job) = job else {
+                            break;
+                        };
+                        if !job(&model) {
+                            continue;
+                        }
+
+                        // A panic can leave the model half-updated, so the replica is rebuilt
+                        // before it takes the next request. Other replicas keep serving meanwhile.
+                        log::warn!("{} replica {} panicked, reloading the model", name, replica);
+                        model = loop {
+                            match load_model(name, load.as_ref()) {
+                                Ok(model) => break model,
+                                Err(err) => {
+                                    log::error!("failed to reload {} model: {:?}", name, err);
+                                    thread::sleep(RELOAD_DELAY);
+                                }
+                            }
+                        };
+                    }
+                })?;
+            loading.push(loaded);
+        }
+
+        for loaded in loading {
+            loaded
+                .await
+                .map_err(|_| anyhow!("{} replica exited while loading", name))??;
+        }
+        log::info!("{} model ready with {} replica(s)", name, replicas);
+
+        Ok(Self {
+            name,
+            replicas,
+            sender,
+        })
+    }
+
+    // Number of requests the pool works on at the same time
+    pub fn replicas(&self) -> usize {
+        self.replicas
+    }
+
+    // Run `f` on the first free replica and wait for its result. A panic inside `f` is
+    // returned as a `PanicError` instead of taking the replica down.
+    pub async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&M) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let name = self.name;
+        let (result, received) = oneshot::channel();
+        let job: ModelJob<M> = Box::new(move |model: &M| {
+            let output = panic::catch_unwind(AssertUnwindSafe(|| f(model)))
+                .map_err(|panic| PanicError::new(format!("{} model", name), panic.as_ref()));
+            let panicked = output.is_err();
+            let _ = result.send(output);
+            panicked
+        });
+        self.sender
+            .send(job)
+            .await
+            .map_err(|_| anyhow!("{} model is not running", name))?;
+        let output = received
+            .await
+            .map_err(|_| anyhow!("{} model stopped before answering", name))?;
+        Ok(output?)
+    }
+}
+
+// Build a model, turning a panic in its constructor into an error
+fn load_model<M>(name: &str, load: &(dyn Fn() -> Result<M> + Send + Sync)) -> Result<M> {
+    panic::catch_unwind(AssertUnwindSafe(load))
+        .map_err(|panic| PanicError::new(format!("loading {} model", name), panic.as_ref()))?
+}
+
+fn trancriber_model() -> Result<WhisperContext> {
+    // The model handler downloads the weights asynchronously when they are missing
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let m = runtime.block_on(model_handler::ModelHandler::new("small", "models"));
+    // Whisper is fed decoded samples directly, see `api::audio`
+    Ok(WhisperContext::new_with_params(
+        &m.get_model_dir(),
+        WhisperContextParameters::default(),
+    )?)
+}
+
+fn sentiment_model() -> Result<SentimentClassifier> {
+    let sentiment_config = SentimentConfig {
+        model_type: ModelType::DistilBert,
+        model_resource: ModelResource::Torch(Box::from(PathBuf::from(
+            "./models/distilbert-base-uncased-finetuned-sst-2-english/rust_model.ot",
+        ))),
+        config_resource: PathBuf::from(
+            "./models/distilbert-base-uncased-finetuned-sst-2-english/config.json",
+        )
+        .to_path_buf()
+        .into(),
+        vocab_resource: PathBuf::from(
+            "./models/distilbert-base-uncased-finetuned-sst-2-english/vocab.txt",
+        )
+        .to_path_buf()
+        .into(),
+        merges_resource: None,
+        ..Default::default()
+    };
+    Ok(SentimentClassifier::Binary(SentimentModel::new(
+        sentiment_config,
+    )?))
+}
+
+fn ner_model() -> Result<TokenClassificationModel> {
+    let ner_config = TokenClassificationConfig {
+        model_type: ModelType::Bert,
+        model_resource: ModelResource::Torch(Box::from(PathBuf::from(
+            "./models/bert-large-cased-finetuned-conll03-english/rust_model.ot",
+        ))),
+        config_resource: PathBuf::from(
+            "./models/bert-large-cased-finetuned-conll03-english/config.json",
+        )
+        .into(),
+        vocab_resource: PathBuf::from(
+            "./models/bert-large-cased-finetuned-conll03-english/vocab.txt",
+        )
+        .into(),
+        merges_resource: None, // Not needed for BERT-based models
+        ..Default::default()
+    };
+    Ok(TokenClassificationModel::new(ner_config)?)
+}
+
+fn zero_shot_model() -> Result<ZeroShotClassificationModel> {
+    let zero_shot_config = ZeroShotClassificationConfig {
+        model_resource: ModelResource::Torch(Box::from(PathBuf::from(
+            "./models/bart-large-mnli/rust_model.ot",
+        ))),
+        config_resource: PathBuf::from("./models/bart-large-mnli/config.json").into(),
+        vocab_resource: PathBuf::from("./models/bart-large-mnli/vocab.json").into(),
+        merges_resource: Some(PathBuf::from("./models/bart-large-mnli/merges.txt").into()),
+        ..Default::default()
+    };
+
+    Ok(ZeroShotClassificationModel::new(zero_shot_config)?)
+}
+
+fn qa_model() -> Result<QuestionAnsweringModel> {
+    let qa_config = QuestionAnsweringConfig {
+        model_type: ModelType::DistilBert,
+        model_resource: ModelResource::Torch(Box::from(PathBuf::from(
+            "./models/distilbert-base-cased-distilled-squad/rust_model.ot",
+        ))),
+        config_resource: PathBuf::from(
+            "./models/distilbert-base-cased-distilled-squad/config.json",
+        )
+        .into(),
+        vocab_resource: PathBuf::from("./models/distilbert-base-cased-distilled-squad/vocab.txt")
             .into(),
-            vocab_resource: PathBuf::from(
-                "./models/distilbert-base-uncased-finetuned-sst-2-english/vocab.txt",
-            )
-            .to_path_buf()
+        merges_resource: None,
+        ..Default::default()
+    };
+    Ok(QuestionAnsweringModel::new(qa_config)?)
+}
+
+// Multilingual models are not published in the rust-bert format, convert them into these
+// folders with rust-bert's `utils/convert_model.py`
+fn multilingual_sentiment_model() -> Result<SentimentClassifier> {
+    let sentiment_config = SequenceClassificationConfig {
+        model_type: ModelType::XLMRoberta,
+        model_resource: ModelResource::Torch(Box::from(PathBuf::from(
+            "./models/twitter-xlm-roberta-base-sentiment/rust_model.ot",
+        ))),
+        config_resource: PathBuf::from("./models/twitter-xlm-roberta-base-sentiment/config.json")
             .into(),
-            merges_resource: None,
-            ..Default::default()
-        };
-        SentimentModel::new(sentiment_config)
-    })
-    .await??;
-    Ok(sentiment_classifier)
-}
-
-async fn ner_model() -> Result<NERModel> {
-    let ner_model = actix_web::web::block(move || {
-        let ner_config = TokenClassificationConfig {
-            model_type: ModelType::Bert,
-            model_resource: ModelResource::Torch(Box::from(PathBuf::from(
-                "./models/bert-large-cased-finetuned-conll03-english/rust_model.ot",
-            ))),
-            config_resource: PathBuf::from(
-                "./models/bert-large-cased-finetuned-conll03-english/config.json",
-            )
+        vocab_resource: PathBuf::from(
+            "./models/twitter-xlm-roberta-base-sentiment/sentencepiece.bpe.model",
+        )
+        .into(),
+        merges_resource: None,
+        ..Default::default()
+    };
+    Ok(SentimentClassifier::Labeled(
+        SequenceClassificationModel::new(sentiment_config)?,
+    ))
+}
+
+fn multilingual_ner_model() -> Result<TokenClassificationModel> {
+    let ner_config = TokenClassificationConfig {
+        model_type: ModelType::XLMRoberta,
+        model_resource: ModelResource::Torch(Box::from(PathBuf::from(
+            "./models/xlm-roberta-large-ner-hrl/rust_model.ot",
+        ))),
+        config_resource: PathBuf::from("./models/xlm-roberta-large-ner-hrl/config.json").into(),
+        vocab_resource: PathBuf::from("./models/xlm-roberta-large-ner-hrl/sentencepiece.bpe.model")
             .into(),
-            vocab_resource: PathBuf::from(
-                "./models/bert-large-cased-finetuned-conll03-english/vocab.txt",
-            )
+        merges_resource: None,
+        ..Default::default()
+    };
+    Ok(TokenClassificationModel::new(ner_config)?)
+}
+
+fn multilingual_zero_shot_model() -> Result<ZeroShotClassificationModel> {
+    let zero_shot_config = ZeroShotClassificationConfig {
+        model_type: ModelType::XLMRoberta,
+        model_resource: ModelResource::Torch(Box::from(PathBuf::from(
+            "./models/xlm-roberta-large-xnli/rust_model.ot",
+        ))),
+        config_resource: PathBuf::from("./models/xlm-roberta-large-xnli/config.json").into(),
+        vocab_resource: PathBuf::from("./models/xlm-roberta-large-xnli/sentencepiece.bpe.model")
             .into(),
-            merges_resource: None, // Not needed for BERT-based models
-            ..Default::default()
-        };
-        NERModel::new(ner_config)
-    })
-    .await??;
-    Ok(ner_model)
-}
-
-async fn zero_shot_model() -> Result<ZeroShotClassificationModel> {
-    let zero_shot_model = actix_web::web::block(move || {
-        let zero_shot_config = ZeroShotClassificationConfig {
-            model_resource: ModelResource::Torch(Box::from(PathBuf::from(
-                "./models/bart-large-mnli/rust_model.ot",
-            ))),
-            config_resource: PathBuf::from("./models/bart-large-mnli/config.json").into(),
-            vocab_resource: PathBuf::from("./models/bart-large-mnli/vocab.json").into(),
-            merges_resource: Some(PathBuf::from("./models/bart-large-mnli/merges.txt").into()),
-            ..Default::default()
-        };
+        merges_resource: None,
+        ..Default::default()
+    };
 
-        ZeroShotClassificationModel::new(zero_shot_config)
-    })
-    .await??;
-    Ok(zero_shot_model)
+    Ok(ZeroShotClassificationModel::new(zero_shot_config)?)
+}
+
+fn multilingual_qa_model() -> Result<QuestionAnsweringModel> {
+    let qa_config = QuestionAnsweringConfig {
+        model_type: ModelType::XLMRoberta,
+        model_resource: ModelResource::Torch(Box::from(PathBuf::from(
+            "./models/xlm-roberta-base-squad2/rust_model.ot",
+        ))),
+        config_resource: PathBuf::from("./models/xlm-roberta-base-squad2/config.json").into(),
+        vocab_resource: PathBuf::from("./models/xlm-roberta-base-squad2/sentencepiece.bpe.model")
+            .into(),
+        merges_resource: None,
+        ..Default::default()
+    };
+    Ok(QuestionAnsweringModel::new(qa_config)?)
 }
@@ -0,0 +1,117 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde_json::json;
+use sqlx::PgPool;
+
+use super::models::{LocationCount, LocationsOptions};
+use crate::db::establish_connection;
+use crate::errors::{AppError, AppResult};
+
+// Places mentioned in calls as a GeoJSON FeatureCollection of points, most mentioned first
+#[get("/analytics/locations")]
+pub async fn get_locations(
+    pool: web::Data<PgPool>,
+    options: web::Query<LocationsOptions>,
+) -> AppResult<impl Responder> {
+    let country = match options.country.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(country) if country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()) => {
+            Some(country.to_ascii_uppercase())
+        }
+        Some(country) => {
+            return Err(AppError::Invalid(format!(
+                "country must be a two letter code, not {:?}",
+                country
+            )))
+        }
+    };
+
+    // The place columns of all mentions of one geoname are equal, MIN picks any of them
+    let locations = sqlx::query_as::<_, LocationCount>(
+        r#"
+    SELECT m.geoname_id, MIN(m.place) AS place, MIN(m.country_code) AS country_code,
+        MIN(m.admin_region) AS admin_region, MIN(m.latitude) AS latitude,
+        MIN(m.longitude) AS longitude, COUNT(*) AS mentions, COUNT(DISTINCT m.call_id) AS calls
+    FROM call_entity m
+    JOIN call c ON c.id = m.call_id
+    WHERE m.geoname_id IS NOT NULL
+        AND ($1::timestamptz IS NULL OR c.created_at >= $1)
+        AND ($2::timestamptz IS NULL OR c.created_at < $2)
+        AND ($3::text IS NULL OR m.country_code = $3)
+    GROUP BY m.geoname_id
+    ORDER BY mentions DESC, m.geoname_id
+    "#,
+    )
+    .bind(options.from)
+    .bind(options.to)
+    .bind(country)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let features = locations
+        .into_iter()
+        .map(|location| {
+            json!({
+                "type": "Feature",
+                "id": location.geoname_id,
+                // GeoJSON puts the longitude first
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [location.longitude, location.latitude],
+                },
+                "properties": {
+                    "geoname_id": location.geoname_id,
+                    "name": location.place,
+                    "country_code": location.country_code,
+                    "admin_region": location.admin_region,
+                    "mentions": location.mentions,
+                    "calls": location.calls,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/geo+json")
+        .json(json!({ "type": "FeatureCollection", "features": features })))
+}
+
+use actix_web::{http::StatusCode, test, App};
+
+// Test GET /analytics/locations returns a FeatureCollection
+#[actix_web::test]
+async fn test_get_locations() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .service(get_locations),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/analytics/locations?country=ua")
+        .to_request();
+
+    let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp["type"], "FeatureCollection");
+    assert!(resp["features"].is_array());
+}
+
+// Test GET /analytics/locations with a country name instead of a code
+#[actix_web::test]
+async fn test_get_locations_invalid_country() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .service(get_locations),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/analytics/locations?country=Ukraine")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
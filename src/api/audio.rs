@@ -0,0 +1,412 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::errors::DecodeError;
+
+// Whisper expects 16 kHz mono PCM
+pub const SAMPLE_RATE: u32 = 16_000;
+
+// Length of the frames the per-channel energy is measured over
+pub const ENERGY_FRAME_MS: usize = 10;
+
+// Zero crossings of the resampling filter on each side of a sample
+const FILTER_HALF_WIDTH: f64 = 16.0;
+
+// Properties of the original audio, stored on the call
+#[derive(Serialize, Clone, Debug)]
+pub struct AudioInfo {
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub duration_secs: f64,
+}
+
+pub struct DecodedAudio {
+    pub info: AudioInfo,
+    // Mono at SAMPLE_RATE
+    pub samples: Vec<f32>,
+    // Mean power of every channel per ENERGY_FRAME_MS frame, empty for mono audio. Dual-channel
+    // recordings keep each party on its own channel, which is enough to tell them apart.
+    pub channel_energy: Vec<Vec<f32>>,
+}
+
+// Decode any supported container (WAV, MP3, OGG/Vorbis, FLAC, M4A/AAC), then downmix and
+// resample it for transcription. Packets are downmixed and resampled as they are decoded, so
+// only the output is kept in memory. Audio longer than `max_duration` is refused.
+pub fn decode_audio(path: &Path, max_duration: Duration) -> Result<DecodedAudio, DecodeError> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|_| DecodeError::UnknownFormat)?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(DecodeError::NoAudioTrack)?;
+    let track_id = track.id;
+    let codec = symphonia::default::get_codecs()
+        .get_codec(track.codec_params.codec)
+        .map(|descriptor| descriptor.short_name.to_string())
+        .unwrap_or_else(|| track.codec_params.codec.to_string());
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|_| DecodeError::UnsupportedCodec(codec.clone()))?;
+
+    let mut sample_rate = track.codec_params.sample_rate;
+    let too_long =
+        |frames: u64, rate: u32| frames as f64 > max_duration.as_secs_f64() * rate as f64;
+    // Most containers know their length, the others are stopped once decoding passes the limit
+    if let (Some(frames), Some(rate)) = (track.codec_params.n_frames, sample_rate) {
+        if too_long(frames, rate) {
+            return Err(DecodeError::TooLong(max_duration.as_secs()));
+        }
+    }
+    let mut mix: Option<Mix> = None;
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // End of the stream
+            Err(SymphoniaError::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            // A chained OGG stream starts over, the first one is enough
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(err) => return Err(DecodeError::Corrupt(err.to_string())),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A damaged packet only loses a few milliseconds of audio
+            Err(SymphoniaError::DecodeError(err)) => {
+                log::debug!("skipping undecodable packet: {}", err);
+                continue;
+            }
+            Err(err) => return Err(DecodeError::Corrupt(err.to_string())),
+        };
+
+        let spec = *decoded.spec();
+        let channel_count = spec.channels.count();
+        let rate = *sample_rate.get_or_insert(spec.rate);
+        if rate == 0 {
+            return Err(DecodeError::Empty);
+        }
+        let mix = mix.get_or_insert_with(|| Mix::new(channel_count, rate));
+        let buffer = match &mut buffer {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * channel_count => buffer,
+            buffer => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        mix.push(buffer.samples(), channel_count);
+        if too_long(mix.frames as u64, rate) {
+            return Err(DecodeError::TooLong(max_duration.as_secs()));
+        }
+    }
+
+    let (Some(mix), Some(sample_rate)) = (mix, sample_rate) else {
+        return Err(DecodeError::Empty);
+    };
+    if mix.frames == 0 {
+        return Err(DecodeError::Empty);
+    }
+    let info = AudioInfo {
+        codec,
+        sample_rate,
+        channels: mix.energy.len() as u16,
+        duration_secs: mix.frames as f64 / sample_rate as f64,
+    };
+    let (samples, channel_energy) = mix.finish();
+    Ok(DecodedAudio {
+        info,
+        samples,
+        channel_energy,
+    })
+}
+
+// Running downmix of the decoded packets, with the energy of every channel summed per frame
+struct Mix {
+    frames: usize,
+    resampler: Resampler,
+    energy: Vec<ChannelEnergy>,
+}
+
+#[derive(Clone, Default)]
+struct ChannelEnergy {
+    frames: Vec<f32>,
+    sum: f32,
+    count: usize,
+}
+
+impl Mix {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            frames: 0,
+            resampler: Resampler::new(sample_rate, SAMPLE_RATE),
+            energy: vec![ChannelEnergy::default(); channels],
+        }
+    }
+
+    // Add interleaved samples. The channel count of the first packet is kept, later packets
+    // with more channels lose the extra ones.
+    fn push(&mut self, interleaved: &[f32], channel_count: usize) {
+        let frame_len = (self.resampler.from as usize * ENERGY_FRAME_MS / 1000).max(1);
+        let scale = 1.0 / self.energy.len() as f32;
+        let mono = interleaved
+            .chunks(channel_count)
+            .map(|frame| {
+                for (channel, sample) in self.energy.iter_mut().zip(frame) {
+                    channel.sum += sample * sample;
+                    channel.count += 1;
+                    if channel.count == frame_len {
+                        channel.frames.push(channel.sum / frame_len as f32);
+                        channel.sum = 0.0;
+                        channel.count = 0;
+                    }
+                }
+                frame.iter().take(self.energy.len()).sum::<f32>() * scale
+            })
+            .collect::<Vec<_>>();
+        self.frames += mono.len();
+        self.resampler.push(&mono);
+    }
+
+    // Mono samples at SAMPLE_RATE and the energy of each channel, none for mono audio
+    fn finish(self) -> (Vec<f32>, Vec<Vec<f32>>) {
+        let channel_energy = match self.energy.len() {
+            1 => Vec::new(),
+            _ => self
+                .energy
+                .into_iter()
+                .map(|mut channel| {
+                    // The last frame is shorter
+                    if channel.count > 0 {
+                        channel.frames.push(channel.sum / channel.count as f32);
+                    }
+                    channel.frames
+                })
+                .collect(),
+        };
+        (self.resampler.finish(), channel_energy)
+    }
+}
+
+// Band-limited resampling with a Hann-windowed sinc filter. The cutoff follows the lower of
+// the two rates so downsampling doesn't alias. Output samples fall on a fixed set of
+// positions between input samples, so the filter is computed once per position. Input is fed
+// in pieces and only the samples the next outputs still need are kept.
+struct Resampler {
+    from: u32,
+    up: usize,
+    down: usize,
+    half_width: isize,
+    // One per position, none when the rates match
+    filters: Vec<Vec<f32>>,
+    // Input still needed, starting at input sample `offset`
+    input: Vec<f32>,
+    offset: usize,
+    received: usize,
+    output: Vec<f32>,
+}
+
+impl Resampler {
+    fn new(from: u32, to: u32) -> Self {
+        let divisor = gcd(from, to);
+        let (up, down) = ((to / divisor) as usize, (from / divisor) as usize);
+        let cutoff = (to as f64 / from as f64).min(1.0);
+        let half_width = (FILTER_HALF_WIDTH / cutoff).ceil() as isize;
+
+        let filters = if from == to {
+            Vec::new()
+        } else {
+            (0..up)
+                .map(|phase| {
+                    let fraction = phase as f64 / up as f64;
+                    let taps: Vec<f64> = (-half_width..=half_width)
+                        .map(|tap| {
+                            let distance = tap as f64 - fraction;
+                            let window =
+                                0.5 + 0.5 * (PI * distance / (half_width + 1) as f64).cos();
+                            cutoff * sinc(cutoff * distance) * window
+                        })
+                        .collect();
+                    // Unity gain
+                    let sum: f64 = taps.iter().sum();
+                    taps.iter().map(|tap| (tap / sum) as f32).collect()
+                })
+                .collect()
+        };
+        Self {
+            from,
+            up,
+            down,
+            half_width,
+            filters,
+            input: Vec::new(),
+            offset: 0,
+            received: 0,
+            output: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        if self.filters.is_empty() {
+            self.output.extend_from_slice(samples);
+            return;
+        }
+        self.input.extend_from_slice(samples);
+        self.received += samples.len();
+        self.drain(false);
+    }
+
+    fn finish(mut self) -> Vec<f32> {
+        self.drain(true);
+        self.output
+    }
+
+    // Compute the outputs whose input is complete. At the end of the input the missing
+    // samples past it count as silence.
+    fn drain(&mut self, end: bool) {
+        let output_len = self.received * self.up / self.down;
+        while self.output.len() < output_len {
+            let position = self.output.len() * self.down;
+            let (base, phase) = (position / self.up, position % self.up);
+            if !end && base as isize + self.half_width >= self.received as isize {
+                break;
+            }
+            let first = base as isize - self.half_width;
+            let sample = self.filters[phase]
+                .iter()
+                .zip(first..)
+                .filter_map(|(weight, input)| {
+                    let index = usize::try_from(input).ok()?.checked_sub(self.offset)?;
+                    Some(self.input.get(index)? * weight)
+                })
+                .sum();
+            self.output.push(sample);
+        }
+
+        // Input before the window of the next output is no longer needed
+        let base = self.output.len() * self.down / self.up;
+        let needed = usize::try_from(base as isize - self.half_width).unwrap_or(0);
+        if needed > self.offset {
+            let consumed = (needed - self.offset).min(self.input.len());
+            self.input.drain(..consumed);
+            self.offset += consumed;
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|index| {
+                (2.0 * std::f32::consts::PI * frequency * index as f32 / sample_rate as f32).sin()
+            })
+            .collect()
+    }
+
+    fn resample(samples: &[f32], from: u32, pieces: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(from, SAMPLE_RATE);
+        for piece in samples.chunks(pieces) {
+            resampler.push(piece);
+        }
+        resampler.finish()
+    }
+
+    #[test]
+    fn test_resample_in_pieces() {
+        let samples = tone(440.0, 44_100, 44_100);
+        let whole = resample(&samples, 44_100, samples.len());
+        assert_eq!(whole.len(), 16_000);
+        // Packets of any size give the same output
+        for pieces in [1, 1152, 4096] {
+            let streamed = resample(&samples, 44_100, pieces);
+            assert_eq!(streamed.len(), whole.len());
+            assert!(streamed.iter().zip(&whole).all(|(a, b)| a == b));
+        }
+        // The kept input stays within the filter window
+        let mut resampler = Resampler::new(48_000, SAMPLE_RATE);
+        for piece in tone(440.0, 48_000, 48_000).chunks(1024) {
+            resampler.push(piece);
+            assert!(resampler.input.len() <= 1024 + 2 * resampler.half_width as usize + 3);
+        }
+    }
+
+    #[test]
+    fn test_resample_keeps_tone() {
+        let resampled = resample(&tone(440.0, 48_000, 48_000), 48_000, 1024);
+        let expected = tone(440.0, SAMPLE_RATE, SAMPLE_RATE as usize);
+        // Away from the edges the filter passes the tone unchanged
+        let error = resampled[1000..15_000]
+            .iter()
+            .zip(&expected[1000..15_000])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(error < 0.01, "error {}", error);
+    }
+
+    #[test]
+    fn test_mix_downmix_and_energy() {
+        // Left loud, right silent
+        let mut mix = Mix::new(2, SAMPLE_RATE);
+        let interleaved = (0..250).flat_map(|_| [0.5, 0.0]).collect::<Vec<f32>>();
+        mix.push(&interleaved[..100], 2);
+        mix.push(&interleaved[100..], 2);
+        assert_eq!(mix.frames, 250);
+        let (samples, energy) = mix.finish();
+        assert_eq!(samples, vec![0.25; 250]);
+        // 160 samples per frame, the last one is shorter
+        assert_eq!(energy, vec![vec![0.25, 0.25], vec![0.0, 0.0]]);
+
+        let mut mix = Mix::new(1, SAMPLE_RATE);
+        mix.push(&[0.5; 10], 1);
+        assert_eq!(mix.finish(), (vec![0.5; 10], Vec::<Vec<f32>>::new()));
+    }
+}
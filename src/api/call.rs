@@ -1,98 +1,317 @@
-use std::sync::{Arc, Mutex};
-
-use super::models::{Call, CallId, Category};
-use super::utils::{
-    categories, download_audio_file, emotional_tone, name_and_locations, transcribe_audio,
+use super::download::validate_audio_url;
+use super::events::{publish, CallEvent};
+use super::models::{
+    Call, CallCategory, CallEntity, CallId, CallOptions, CallSegment, CallSpeaker, CallStatus,
 };
-use crate::ai_config::AppState;
+use super::utils::store_upload;
+use super::worker::{Task, Workers};
+use crate::config::env_or;
 use crate::db::establish_connection;
-use crate::errors::AppResult;
+use crate::errors::{AppError, AppResult, UploadError};
+use actix_multipart::Multipart;
+use actix_web::guard::GuardContext;
+use actix_web::http::header;
 use actix_web::{get, post, web, HttpResponse, Responder};
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
+use std::path::Path;
 use uuid::Uuid;
 
+// 100 MiB, override with UPLOAD_MAX_BYTES
+const DEFAULT_UPLOAD_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+// Longest accepted value of a text form field
+const FIELD_MAX_BYTES: usize = 1024;
+
 #[derive(Deserialize, Serialize)]
 struct CreateCallRequest {
     audio_url: String,
+    // Whisper language code, e.g. `uk`. Detected from the audio when missing.
+    #[serde(default)]
+    language: Option<String>,
 }
 
-// Create a new call
+#[derive(Deserialize)]
+struct UploadOptions {
+    #[serde(default)]
+    language: Option<String>,
+}
+
+// Create a new call and queue it for processing
 #[post("/call")]
 pub async fn create_call(
     pool: web::Data<PgPool>,
-    app_state: web::Data<Arc<Mutex<AppState>>>,
+    workers: web::Data<Workers>,
     new_call: web::Json<CreateCallRequest>,
 ) -> AppResult<impl Responder> {
-    let audio_url = &new_call.audio_url;
-    let file_path = match download_audio_file(audio_url).await {
-        Ok(path) => path,
-        Err(_) => return Ok(HttpResponse::UnprocessableEntity().finish()),
+    // Answers 422 with the reason. Only http(s) URLs pass, file:// is reserved for uploaded
+    // audio and files ingested through the CLI.
+    let language = requested_language(new_call.language.as_deref())?;
+    validate_audio_url(&new_call.audio_url).await?;
+
+    queue_call(&pool, &workers, &new_call.audio_url, language.as_deref()).await
+}
+
+// Create a call from a multipart/form-data upload with the audio in an `audio` file field and
+// an optional `language` text field
+#[post("/call", guard = "is_multipart")]
+pub async fn upload_call(
+    pool: web::Data<PgPool>,
+    workers: web::Data<Workers>,
+    mut form: Multipart,
+) -> AppResult<impl Responder> {
+    let max_bytes = env_or("UPLOAD_MAX_BYTES", DEFAULT_UPLOAD_MAX_BYTES);
+    let mut stored = None;
+    let fields = async {
+        let mut language = None;
+        while let Some(field) = form.next().await {
+            let field =
+                field.map_err(|err| UploadError::Invalid(format!("invalid form: {}", err)))?;
+            match field.name() {
+                Some("audio") if stored.is_none() => {
+                    stored = Some(store_upload(field, max_bytes).await?)
+                }
+                Some("language") => language = requested_language(Some(&read_field(field).await?))?,
+                _ => {}
+            }
+        }
+        AppResult::Ok(language)
     };
+    let language = match fields.await {
+        Ok(language) => language,
+        Err(err) => {
+            // The audio may be stored before the rest of the form turns out invalid
+            if let Some(path) = &stored {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+            return Err(err);
+        }
+    };
+    let path = stored.ok_or_else(|| UploadError::Invalid("missing `audio` field".to_string()))?;
+
+    queue_call(&pool, &workers, &upload_url(&path)?, language.as_deref()).await
+}
 
-    let app_state = match app_state.lock() {
-        Ok(state) => state,
-        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+// Create a call from a raw request body with an audio/* content type, the language goes in
+// the query string
+#[post("/call", guard = "is_audio")]
+pub async fn upload_call_raw(
+    pool: web::Data<PgPool>,
+    workers: web::Data<Workers>,
+    options: web::Query<UploadOptions>,
+    body: web::Payload,
+) -> AppResult<impl Responder> {
+    let language = requested_language(options.language.as_deref())?;
+    let max_bytes = env_or("UPLOAD_MAX_BYTES", DEFAULT_UPLOAD_MAX_BYTES);
+    let path = store_upload(body, max_bytes).await?;
+
+    queue_call(&pool, &workers, &upload_url(&path)?, language.as_deref()).await
+}
+
+// Whisper language code of a submitted call. Empty and `auto` leave it to detection.
+pub fn requested_language(language: Option<&str>) -> AppResult<Option<String>> {
+    let Some(language) = language
+        .map(|language| language.trim().to_ascii_lowercase())
+        .filter(|language| !language.is_empty() && language != "auto")
+    else {
+        return Ok(None);
     };
+    match whisper_rs::get_lang_id(&language) {
+        Some(_) => Ok(Some(language)),
+        None => Err(AppError::Invalid(format!(
+            "unknown language `{}`",
+            language
+        ))),
+    }
+}
 
-    let transcriber = &app_state.transcriber;
-    let sentiment = &app_state.sentiment;
-    let ner = &app_state.ner;
-    let zero_shot = &app_state.zero_shot;
+async fn read_field(mut field: actix_multipart::Field) -> Result<String, UploadError> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|err| UploadError::Invalid(format!("invalid form: {}", err)))?;
+        if value.len() + chunk.len() > FIELD_MAX_BYTES {
+            return Err(UploadError::Invalid("form field is too long".to_string()));
+        }
+        value.extend_from_slice(&chunk);
+    }
+    String::from_utf8(value)
+        .map_err(|_| UploadError::Invalid("form field is not UTF-8".to_string()))
+}
+
+fn is_multipart(ctx: &GuardContext) -> bool {
+    content_type(ctx).is_some_and(|mime| mime.starts_with("multipart/form-data"))
+}
 
-    // Transcribe audio
-    let transcribed_text = transcribe_audio(format!("./tmp/{}", file_path), transcriber).await;
-    // Define emotional tone
-    let emotional_tone = emotional_tone(transcribed_text.clone(), sentiment).await?;
-    // Extract names and locations using NER (stubbed)
-    let (name, location) = name_and_locations(transcribed_text.clone(), ner).await?;
-    // Parse categories based on text (you can extend this to match actual topics)
-    let category = sqlx::query_as::<_, Category>("SELECT * FROM category")
-        .fetch_all(pool.get_ref())
-        .await?;
+fn is_audio(ctx: &GuardContext) -> bool {
+    content_type(ctx).is_some_and(|mime| mime.starts_with("audio/"))
+}
 
-    let categories = categories(transcribed_text.clone(), category, zero_shot).await?;
+fn content_type<'a>(ctx: &'a GuardContext) -> Option<&'a str> {
+    ctx.head()
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+}
 
+pub fn upload_url(path: &Path) -> Result<String, UploadError> {
+    reqwest::Url::from_file_path(path)
+        .map(String::from)
+        .map_err(|()| UploadError::Invalid(format!("invalid path {}", path.display())))
+}
+
+// The call and its job are stored together so a crash can't lose the submission
+async fn queue_call(
+    pool: &PgPool,
+    workers: &Workers,
+    audio_url: &str,
+    language: Option<&str>,
+) -> AppResult<HttpResponse> {
+    let mut tx = pool.begin().await?;
+    let (id, _) = insert_call(&mut tx, workers, audio_url, language).await?;
+    tx.commit().await?;
+    workers.wake();
+
+    Ok(HttpResponse::Ok().json(CallId { id }))
+}
+
+// Store a queued call and the job processing it, returns the ids of both. A requested
+// language is stored right away, without a probability.
+pub async fn insert_call(
+    conn: &mut PgConnection,
+    workers: &Workers,
+    audio_url: &str,
+    language: Option<&str>,
+) -> Result<(Uuid, Uuid), sqlx::Error> {
     let call = sqlx::query_as::<_, CallId>(
         r#"
-    INSERT INTO call (name, location, emotional_tone, text, categories, id)
-    VALUES ($1, $2, $3, $4, $5, $6)
+    INSERT INTO call (id, audio_url, status, language)
+    VALUES ($1, $2, $3, $4)
     RETURNING id
     "#,
     )
-    .bind(name.map(|name| name.join(" ")))
-    .bind(location.map(|loc| loc.join(" ")))
-    .bind(emotional_tone)
-    .bind(transcribed_text)
-    .bind(&categories as &[String])
-    .bind(file_path)
-    .fetch_one(pool.get_ref())
+    .bind(Uuid::new_v4())
+    .bind(audio_url)
+    .bind(CallStatus::Queued.as_str())
+    .bind(language)
+    .fetch_one(&mut *conn)
     .await?;
 
-    Ok(HttpResponse::Ok().json(call))
+    let task = Task::Call {
+        call_id: call.id,
+        audio_url: audio_url.to_string(),
+        language: language.map(str::to_string),
+    };
+    let job_id = workers.enqueue(&mut *conn, &task).await?;
+    let event = CallEvent::Status {
+        call_id: call.id,
+        status: CallStatus::Queued.as_str().to_string(),
+    };
+    publish(&mut *conn, &event).await?;
+    Ok((call.id, job_id))
 }
 
-// Get a specific call by ID
+// Get a specific call by ID. `?include=segments` adds the timed transcript segments,
+// `?include=entities` the named entities mentioned in it.
 #[get("call/{id}")]
-pub async fn get_call(pool: web::Data<PgPool>, id: web::Path<Uuid>) -> impl Responder {
-    let result = sqlx::query_as::<_, Call>(
+pub async fn get_call(
+    pool: web::Data<PgPool>,
+    id: web::Path<Uuid>,
+    options: web::Query<CallOptions>,
+) -> AppResult<impl Responder> {
+    let mut call = fetch_call(pool.get_ref(), *id).await?;
+    if let Some(call) = call.as_mut().filter(|_| options.includes("segments")) {
+        call.segments = Some(fetch_segments(pool.get_ref(), call.id).await?);
+    }
+    if let Some(call) = call.as_mut().filter(|_| options.includes("entities")) {
+        call.entities = Some(fetch_entities(pool.get_ref(), call.id).await?);
+    }
+
+    match call {
+        Some(call) if CallStatus::is_finished(&call.status) => Ok(HttpResponse::Ok().json(call)),
+        // Still in the pipeline, report the current stage
+        Some(call) => Ok(HttpResponse::Accepted().json(call)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// Load a call with the categories it was assigned and the analysis of each speaker
+pub async fn fetch_call(pool: &PgPool, id: Uuid) -> Result<Option<Call>, sqlx::Error> {
+    let call = sqlx::query_as::<_, Call>(
         r#"
-    SELECT id, name, location, emotional_tone, text, categories
+    SELECT id, status, error, name, location, emotional_tone, text, translation,
+        codec, sample_rate, channels, duration_secs, chunks_done, chunks_total,
+        language, language_probability, diarization, extraction
     FROM call
     WHERE id = $1
     "#,
     )
-    .bind(*id)
-    .fetch_one(pool.get_ref())
-    .await;
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
 
-    match result {
-        Ok(call) => HttpResponse::Ok().json(call),
-        Err(_) => HttpResponse::Accepted().finish(),
-    }
+    let Some(mut call) = call else {
+        return Ok(None);
+    };
+    call.categories = sqlx::query_as::<_, CallCategory>(
+        r#"
+    SELECT category.id, category.title, call_category.score, call_category.matched_label,
+        call_category.source, call_category.model_version
+    FROM call_category
+    JOIN category ON category.id = call_category.category_id
+    WHERE call_category.call_id = $1
+    ORDER BY call_category.score DESC NULLS LAST
+    "#,
+    )
+    .bind(call.id)
+    .fetch_all(pool)
+    .await?;
+    call.speakers = sqlx::query_as::<_, CallSpeaker>(
+        r#"
+    SELECT speaker, talk_time_ms, emotional_tone, name, location
+    FROM call_speaker
+    WHERE call_id = $1
+    ORDER BY speaker
+    "#,
+    )
+    .bind(call.id)
+    .fetch_all(pool)
+    .await?;
+    Ok(Some(call))
 }
 
-use actix_web::{test, App};
+pub async fn fetch_segments(pool: &PgPool, call_id: Uuid) -> Result<Vec<CallSegment>, sqlx::Error> {
+    sqlx::query_as::<_, CallSegment>(
+        r#"
+    SELECT id, start_ms, end_ms, speaker, text, avg_probability
+    FROM call_segment
+    WHERE call_id = $1
+    ORDER BY position
+    "#,
+    )
+    .bind(call_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn fetch_entities(pool: &PgPool, call_id: Uuid) -> Result<Vec<CallEntity>, sqlx::Error> {
+    sqlx::query_as::<_, CallEntity>(
+        r#"
+    SELECT call_entity.id, segment_id, entity_type, call_entity.text, normalized, start_char,
+        end_char, translation, score, entity_id, geoname_id, place, country_code, admin_region,
+        latitude, longitude
+    FROM call_entity
+    LEFT JOIN call_segment ON call_segment.id = call_entity.segment_id
+    WHERE call_entity.call_id = $1
+    ORDER BY call_segment.position, start_char
+    "#,
+    )
+    .bind(call_id)
+    .fetch_all(pool)
+    .await
+}
+
+use actix_web::{http::StatusCode, test, App};
 
 // Test GET /call/{id}
 #[actix_web::test]
@@ -105,11 +324,88 @@ async fn test_get_call() {
     )
     .await;
 
-    let call_id = Uuid::new_v4(); // Assume you have a valid UUID for testing
+    let call_id = Uuid::new_v4(); // Unknown call
     let req = test::TestRequest::get()
         .uri(&format!("/call/{}", call_id))
         .to_request();
 
     let resp = test::call_service(&app, req).await;
-    assert!(resp.status().is_success());
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+// Test POST /call with a multipart form lacking the audio field
+#[actix_web::test]
+async fn test_upload_call_without_audio() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Workers::new()))
+            .service(upload_call),
+    )
+    .await;
+
+    let body = "--boundary\r\n\
+        Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+        no audio here\r\n\
+        --boundary--\r\n";
+    let req = test::TestRequest::post()
+        .uri("/call")
+        .insert_header((
+            header::CONTENT_TYPE,
+            "multipart/form-data; boundary=boundary",
+        ))
+        .set_payload(body)
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+// Test POST /call with a URL pointing at a private address
+#[actix_web::test]
+async fn test_create_call_private_address() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Workers::new()))
+            .service(create_call),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/call")
+        .set_json(CreateCallRequest {
+            audio_url: "http://127.0.0.1:8080/tmp/call.wav".to_string(),
+            language: None,
+        })
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+// Test POST /call with a language Whisper doesn't know
+#[actix_web::test]
+async fn test_create_call_unknown_language() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Workers::new()))
+            .service(create_call),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/call")
+        .set_json(CreateCallRequest {
+            audio_url: "https://example.com/call.wav".to_string(),
+            language: Some("klingon".to_string()),
+        })
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
 }
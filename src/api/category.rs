@@ -1,130 +1,170 @@
-use std::sync::{Arc, Mutex};
-
-use super::models::{Category, CreateCategory, UpdateCategory};
-use super::utils::reindex_calls_for_category;
-use crate::ai_config::AppState;
+use super::models::{
+    Category, CategoryChange, CreateCategory, ExtractionField, ReindexOptions, UpdateCategory,
+};
+use super::reindex::enqueue_reindex;
+use super::worker::Workers;
 use crate::db::establish_connection;
-use crate::errors::AppResult;
+use crate::errors::{AppError, AppResult};
 use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
 use sqlx::PgPool;
 
 // Get all categories
 #[get("/category")]
 pub async fn get_categories(pool: web::Data<PgPool>) -> AppResult<impl Responder> {
-    let categories = sqlx::query_as::<_, Category>("SELECT id, title, points FROM category")
-        .fetch_all(pool.get_ref())
-        .await?;
+    let categories =
+        sqlx::query_as::<_, Category>("SELECT id, title, points, extraction_schema FROM category")
+            .fetch_all(pool.get_ref())
+            .await?;
 
     Ok(HttpResponse::Ok().json(categories))
 }
 
-// Create a new category
+// Create a new category. With `?dry_run=true` nothing is saved, the returned reindex job
+// only reports which calls would get the category.
 #[post("/category")]
 pub async fn create_category(
-    app_state: web::Data<Arc<Mutex<AppState>>>,
+    workers: web::Data<Workers>,
     pool: web::Data<PgPool>,
+    options: web::Query<ReindexOptions>,
     new_category: web::Json<CreateCategory>,
 ) -> AppResult<impl Responder> {
+    validate_schema(new_category.extraction_schema.as_deref().map(Vec::as_slice))?;
+    let mut tx = pool.begin().await?;
+
+    if options.dry_run {
+        let new_category = new_category.into_inner();
+        let reindex_job_id =
+            enqueue_reindex(&mut tx, &workers, None, Some(new_category.clone())).await?;
+        tx.commit().await?;
+        workers.wake();
+
+        return Ok(HttpResponse::Ok().json(CategoryChange {
+            id: None,
+            title: new_category.title,
+            points: new_category.points,
+            extraction_schema: new_category.extraction_schema,
+            reindex_job_id,
+            dry_run: true,
+        }));
+    }
+
     let category = match sqlx::query_as::<_, Category>(
         r#"
-    INSERT INTO category (title, points)
-    VALUES ($1, $2)
+    INSERT INTO category (title, points, extraction_schema)
+    VALUES ($1, $2, $3)
     RETURNING * 
     "#,
     )
     .bind(&new_category.title)
     .bind(&new_category.points)
-    .fetch_one(pool.get_ref())
+    .bind(&new_category.extraction_schema)
+    .fetch_one(&mut *tx)
     .await
     {
         Ok(category) => category,
         Err(_) => return Ok(HttpResponse::UnprocessableEntity().finish()),
     };
 
-    let candidate_labels: Vec<String> = {
-        let title_iter = std::iter::once(category.title.clone());
-        let points_iter = category
-            .points
-            .clone()
-            .unwrap_or_else(|| vec![])
-            .into_iter(); // Handle None case
-        title_iter.chain(points_iter) // Combine both title and points
-    }
-    .collect();
-
-    let app_state = match app_state.lock() {
-        Ok(state) => state,
-        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
-    };
-
-    let zero_shot = &app_state.zero_shot;
-    reindex_calls_for_category(&pool, None, &category.title, candidate_labels, zero_shot).await?;
+    // Existing calls are matched against the new category in the background
+    let reindex_job_id = enqueue_reindex(&mut tx, &workers, Some(category.id), None).await?;
+    tx.commit().await?;
+    workers.wake();
 
-    Ok(HttpResponse::Ok().json(category))
+    Ok(HttpResponse::Ok().json(CategoryChange {
+        id: Some(category.id),
+        title: category.title,
+        points: category.points,
+        extraction_schema: category.extraction_schema,
+        reindex_job_id,
+        dry_run: false,
+    }))
 }
 
-// Update an existing category
+// Update an existing category. With `?dry_run=true` the category is left untouched, the
+// returned reindex job only reports which calls would gain or lose it.
 #[put("/category/{category_id}")]
 pub async fn update_category(
-    app_state: web::Data<Arc<Mutex<AppState>>>,
+    workers: web::Data<Workers>,
     pool: web::Data<PgPool>,
     id: web::Path<i32>,
+    options: web::Query<ReindexOptions>,
     updated_category: web::Json<UpdateCategory>,
 ) -> AppResult<impl Responder> {
-    let title =
-        match sqlx::query_as::<_, Category>("SELECT id, title, points FROM category WHERE id = $1")
-            .bind(*id)
-            .fetch_one(pool.get_ref())
-            .await
-        {
-            Ok(category) => category.title,
-            Err(_) => return Ok(HttpResponse::UnprocessableEntity().finish()),
+    validate_schema(
+        updated_category
+            .extraction_schema
+            .as_deref()
+            .map(Vec::as_slice),
+    )?;
+    let current = match sqlx::query_as::<_, Category>(
+        "SELECT id, title, points, extraction_schema FROM category WHERE id = $1",
+    )
+    .bind(*id)
+    .fetch_one(pool.get_ref())
+    .await
+    {
+        Ok(category) => category,
+        Err(_) => return Ok(HttpResponse::UnprocessableEntity().finish()),
+    };
+
+    let mut tx = pool.begin().await?;
+
+    if options.dry_run {
+        let updated_category = updated_category.into_inner();
+        let proposed = CreateCategory {
+            title: updated_category.title.unwrap_or(current.title),
+            points: updated_category.points.or(current.points),
+            extraction_schema: updated_category
+                .extraction_schema
+                .or(current.extraction_schema),
         };
+        let reindex_job_id =
+            enqueue_reindex(&mut tx, &workers, Some(current.id), Some(proposed.clone())).await?;
+        tx.commit().await?;
+        workers.wake();
+
+        return Ok(HttpResponse::Ok().json(CategoryChange {
+            id: Some(current.id),
+            title: proposed.title,
+            points: proposed.points,
+            extraction_schema: proposed.extraction_schema,
+            reindex_job_id,
+            dry_run: true,
+        }));
+    }
+
     let category = match sqlx::query_as::<_, Category>(
         r#"
     UPDATE category
-    SET title = COALESCE($1, title), points = COALESCE($2, points)
-    WHERE id = $3
+    SET title = COALESCE($1, title), points = COALESCE($2, points),
+        extraction_schema = COALESCE($3, extraction_schema)
+    WHERE id = $4
     RETURNING *
     "#,
     )
     .bind(&updated_category.title)
     .bind(&updated_category.points)
+    .bind(&updated_category.extraction_schema)
     .bind(*id)
-    .fetch_one(pool.get_ref())
+    .fetch_one(&mut *tx)
     .await
     {
         Ok(category) => category,
         Err(_) => return Ok(HttpResponse::UnprocessableEntity().finish()),
     };
 
-    let candidate_labels: Vec<String> = {
-        let title_iter = std::iter::once(category.title.clone());
-        let points_iter = category
-            .points
-            .clone()
-            .unwrap_or_else(|| vec![])
-            .into_iter(); // Handle None case
-        title_iter.chain(points_iter) // Combine both title and points
-    }
-    .collect();
-
-    let app_state = match app_state.lock() {
-        Ok(state) => state,
-        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
-    };
+    let reindex_job_id = enqueue_reindex(&mut tx, &workers, Some(category.id), None).await?;
+    tx.commit().await?;
+    workers.wake();
 
-    let zero_shot = &app_state.zero_shot;
-    reindex_calls_for_category(
-        &pool,
-        Some(&title),
-        &category.title,
-        candidate_labels,
-        zero_shot,
-    )
-    .await?;
-
-    Ok(HttpResponse::Ok().json(category))
+    Ok(HttpResponse::Ok().json(CategoryChange {
+        id: Some(category.id),
+        title: category.title,
+        points: category.points,
+        extraction_schema: category.extraction_schema,
+        reindex_job_id,
+        dry_run: false,
+    }))
 }
 
 // Delete a category
@@ -133,20 +173,11 @@ pub async fn delete_category(
     pool: web::Data<PgPool>,
     id: web::Path<i32>,
 ) -> AppResult<impl Responder> {
-    let result = sqlx::query(
-        r#"
-    WITH deleted_category AS (
-        DELETE FROM category
-        WHERE id = $1
-        RETURNING title
-    )
-    UPDATE call
-    SET categories = array_remove(categories, (SELECT title::text FROM deleted_category))
-    "#,
-    )
-    .bind(*id)
-    .execute(pool.get_ref())
-    .await;
+    // Assignments to calls are removed by the call_category foreign key
+    let result = sqlx::query("DELETE FROM category WHERE id = $1")
+        .bind(*id)
+        .execute(pool.get_ref())
+        .await;
 
     match result {
         Ok(_) => Ok(HttpResponse::Ok().finish()),
@@ -154,7 +185,28 @@ pub async fn delete_category(
     }
 }
 
-use actix_web::{test, App};
+// Fields need a question and a name unique within the schema, it keys their answer
+fn validate_schema(schema: Option<&[ExtractionField]>) -> AppResult<()> {
+    let fields = schema.unwrap_or_default();
+    for (index, field) in fields.iter().enumerate() {
+        if field.name.trim().is_empty() || field.question.trim().is_empty() {
+            return Err(AppError::Invalid(format!(
+                "extraction field {} needs a name and a question",
+                index
+            )));
+        }
+        if fields[..index].iter().any(|other| other.name == field.name) {
+            return Err(AppError::Invalid(format!(
+                "extraction field {:?} is defined twice",
+                field.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+use actix_web::{http::StatusCode, test, App};
+use serde_json::json;
 
 #[actix_web::test]
 async fn test_get_categories() {
@@ -193,3 +245,27 @@ async fn test_delete_category() {
 
     assert!(resp.status().is_success());
 }
+
+// Test POST /category with an extraction schema naming a field twice
+#[actix_web::test]
+async fn test_create_category_duplicate_field() {
+    let pool = establish_connection().await;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Workers::new()))
+            .service(create_category),
+    )
+    .await;
+
+    let field = json!({ "name": "visa_type", "question": "What type of visa is it?" });
+    let req = test::TestRequest::post()
+        .uri("/category")
+        .set_json(json!({ "title": "Visas", "extraction_schema": [field, field] }))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
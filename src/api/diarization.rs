@@ -0,0 +1,455 @@
+use std::f32::consts::PI;
+
+use symphonia::core::dsp::complex::Complex;
+use symphonia::core::dsp::fft::Fft;
+
+use super::audio::{ENERGY_FRAME_MS, SAMPLE_RATE};
+use super::utils::Segment;
+use crate::config::env_or;
+
+// 25 ms analysis frames every 10 ms, zero-padded to the FFT size
+const FRAME_LEN: usize = 400;
+const FRAME_HOP: usize = 160;
+const FFT_SIZE: usize = 512;
+const MEL_BANDS: usize = 26;
+const CEPSTRA: usize = 12;
+// Frames quieter than the loudest frame of their segment by more than this are silence
+const VOICED_RANGE_DB: f32 = 30.0;
+const K_MEANS_ITERATIONS: usize = 20;
+
+// How the speakers of a call were told apart
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiarizationMethod {
+    // Each party was recorded on its own channel
+    Channels,
+    // Segments were clustered by their voice features
+    Clustering,
+    Single,
+}
+
+impl DiarizationMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Channels => "channels",
+            Self::Clustering => "clustering",
+            Self::Single => "single",
+        }
+    }
+}
+
+// Label every transcript segment with a speaker, numbered from 1 in order of first appearance.
+// Dual-channel recordings are split by channel, anything else is clustered into at most two
+// speakers from MFCC statistics of each segment.
+pub fn diarize(
+    samples: &[f32],
+    channel_energy: &[Vec<f32>],
+    segments: &mut [Segment],
+) -> DiarizationMethod {
+    if segments.is_empty() {
+        return DiarizationMethod::Single;
+    }
+
+    let (method, labels) = match split_channels(channel_energy, segments) {
+        Some(labels) => (DiarizationMethod::Channels, labels),
+        None => cluster(samples, segments),
+    };
+
+    // Number speakers by first appearance, the operator usually speaks first
+    let mut order: Vec<usize> = Vec::new();
+    for (segment, label) in segments.iter_mut().zip(labels) {
+        let speaker = match order.iter().position(|known| *known == label) {
+            Some(index) => index,
+            None => {
+                order.push(label);
+                order.len() - 1
+            }
+        };
+        segment.speaker = Some(speaker as i32 + 1);
+    }
+    method
+}
+
+// Speaker per segment from the louder channel, when the channels really carry different
+// parties rather than the same mix twice
+fn split_channels(channel_energy: &[Vec<f32>], segments: &[Segment]) -> Option<Vec<usize>> {
+    if channel_energy.len() < 2 {
+        return None;
+    }
+
+    let min_separation_db = env_or("DIARIZATION_CHANNEL_SEPARATION_DB", 6.0f32);
+    let mut labels = Vec::with_capacity(segments.len());
+    let mut separation = 0.0;
+    for segment in segments {
+        let first = (segment.start_ms.max(0) as usize) / ENERGY_FRAME_MS;
+        let last = (segment.end_ms.max(0) as usize).div_ceil(ENERGY_FRAME_MS);
+        let energies: Vec<f32> = channel_energy
+            .iter()
+            .map(|frames| {
+                let last = last.min(frames.len());
+                frames
+                    .get(first..last)
+                    .map_or(0.0, |frames| frames.iter().sum())
+            })
+            .collect();
+
+        let (loudest, _) = energies
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        let mut sorted = energies.clone();
+        sorted.sort_by(|a, b| b.total_cmp(a));
+        separation += decibels(sorted[0]) - decibels(sorted[1]);
+        labels.push(loudest);
+    }
+
+    (separation / segments.len() as f32 >= min_separation_db).then_some(labels)
+}
+
+fn cluster(samples: &[f32], segments: &[Segment]) -> (DiarizationMethod, Vec<usize>) {
+    let single = (DiarizationMethod::Single, vec![0; segments.len()]);
+    let features = SegmentFeatures::new();
+    let embeddings: Vec<Option<(Vec<f32>, usize)>> = segments
+        .iter()
+        .map(|segment| {
+            let start = ms_to_sample(segment.start_ms).min(samples.len());
+            let end = ms_to_sample(segment.end_ms).clamp(start, samples.len());
+            features.embedding(&samples[start..end])
+        })
+        .collect();
+
+    let mut points: Vec<(usize, Vec<f32>, f32)> = embeddings
+        .iter()
+        .enumerate()
+        .filter_map(|(index, embedding)| {
+            let (vector, frames) = embedding.as_ref()?;
+            Some((index, vector.clone(), *frames as f32))
+        })
+        .collect();
+    if points.len() < 2 {
+        return single;
+    }
+    standardize(&mut points);
+
+    let vectors: Vec<&[f32]> = points
+        .iter()
+        .map(|(_, vector, _)| vector.as_slice())
+        .collect();
+    let weights: Vec<f32> = points.iter().map(|(_, _, weight)| *weight).collect();
+    let (assignment, centroids) = k_means(&vectors, &weights);
+
+    // Two clusters that are closer to each other than their members are to them are one voice
+    let spread = vectors
+        .iter()
+        .zip(&assignment)
+        .zip(&weights)
+        .map(|((vector, cluster), weight)| distance(vector, &centroids[*cluster]) * weight)
+        .sum::<f32>()
+        / weights.iter().sum::<f32>();
+    let min_separation = env_or("DIARIZATION_MIN_SEPARATION", 1.5f32);
+    if distance(&centroids[0], &centroids[1]) < min_separation * spread {
+        return single;
+    }
+
+    // Segments without voiced frames follow the previous speaker
+    let mut labels = vec![None; segments.len()];
+    for ((index, _, _), cluster) in points.iter().zip(assignment) {
+        labels[*index] = Some(cluster);
+    }
+    let mut previous = labels.iter().flatten().next().copied().unwrap_or_default();
+    let labels = labels
+        .into_iter()
+        .map(|label| {
+            previous = label.unwrap_or(previous);
+            previous
+        })
+        .collect();
+    (DiarizationMethod::Clustering, labels)
+}
+
+// Computes MFCC statistics of a stretch of 16 kHz audio
+struct SegmentFeatures {
+    fft: Fft,
+    window: Vec<f32>,
+    filters: Vec<Vec<(usize, f32)>>,
+}
+
+impl SegmentFeatures {
+    fn new() -> Self {
+        let window = (0..FRAME_LEN)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / (FRAME_LEN - 1) as f32).cos())
+            .collect();
+        Self {
+            fft: Fft::new(FFT_SIZE),
+            window,
+            filters: mel_filters(),
+        }
+    }
+
+    // Mean and standard deviation of the voiced frames' cepstra, with the number of voiced
+    // frames. None when the stretch holds no voiced frame.
+    fn embedding(&self, samples: &[f32]) -> Option<(Vec<f32>, usize)> {
+        let frames: Vec<(f32, Vec<f32>)> = samples
+            .windows(FRAME_LEN)
+            .step_by(FRAME_HOP)
+            .map(|frame| self.cepstrum(frame))
+            .collect();
+        let loudest = frames
+            .iter()
+            .map(|(energy, _)| *energy)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let voiced: Vec<&Vec<f32>> = frames
+            .iter()
+            .filter(|(energy, _)| *energy > loudest - VOICED_RANGE_DB && *energy > -80.0)
+            .map(|(_, cepstrum)| cepstrum)
+            .collect();
+        if voiced.is_empty() {
+            return None;
+        }
+
+        let count = voiced.len() as f32;
+        let mean: Vec<f32> = (0..CEPSTRA)
+            .map(|c| voiced.iter().map(|cepstrum| cepstrum[c]).sum::<f32>() / count)
+            .collect();
+        let deviation = (0..CEPSTRA).map(|c| {
+            let variance = voiced
+                .iter()
+                .map(|cepstrum| (cepstrum[c] - mean[c]).powi(2))
+                .sum::<f32>()
+                / count;
+            variance.sqrt()
+        });
+        let embedding = mean.iter().copied().chain(deviation).collect();
+        Some((embedding, voiced.len()))
+    }
+
+    // Frame energy in dB and its cepstral coefficients, without c0
+    fn cepstrum(&self, frame: &[f32]) -> (f32, Vec<f32>) {
+        let mut spectrum = vec![Complex::default(); FFT_SIZE];
+        for ((bin, sample), window) in spectrum.iter_mut().zip(frame).zip(&self.window) {
+            bin.re = sample * window;
+        }
+        self.fft.fft_inplace(&mut spectrum);
+        let power: Vec<f32> = spectrum[..=FFT_SIZE / 2]
+            .iter()
+            .map(|bin| bin.re * bin.re + bin.im * bin.im)
+            .collect();
+
+        let energy = decibels(power.iter().sum::<f32>() / FFT_SIZE as f32);
+        let bands: Vec<f32> = self
+            .filters
+            .iter()
+            .map(|filter| {
+                let band: f32 = filter
+                    .iter()
+                    .map(|(bin, weight)| power[*bin] * weight)
+                    .sum();
+                (band + 1e-10).ln()
+            })
+            .collect();
+        // DCT-II of the log mel energies
+        let cepstrum = (1..=CEPSTRA)
+            .map(|c| {
+                bands
+                    .iter()
+                    .enumerate()
+                    .map(|(m, band)| {
+                        band * (PI * c as f32 * (m as f32 + 0.5) / MEL_BANDS as f32).cos()
+                    })
+                    .sum()
+            })
+            .collect();
+        (energy, cepstrum)
+    }
+}
+
+// Triangular filters spaced on the mel scale over the telephone band
+fn mel_filters() -> Vec<Vec<(usize, f32)>> {
+    let mel = |hz: f32| 2595.0 * (1.0 + hz / 700.0).log10();
+    let hz = |mel: f32| 700.0 * (10f32.powf(mel / 2595.0) - 1.0);
+    let (low, high) = (mel(100.0), mel(7600.0));
+    let bin = |frequency: f32| frequency * FFT_SIZE as f32 / SAMPLE_RATE as f32;
+    let edges: Vec<f32> = (0..MEL_BANDS + 2)
+        .map(|i| bin(hz(low + (high - low) * i as f32 / (MEL_BANDS + 1) as f32)))
+        .collect();
+
+    edges
+        .windows(3)
+        .map(|edge| {
+            let (left, center, right) = (edge[0], edge[1], edge[2]);
+            (left.ceil() as usize..=right.floor() as usize)
+                .filter_map(|bin| {
+                    let position = bin as f32;
+                    let weight = if position <= center {
+                        (position - left) / (center - left)
+                    } else {
+                        (right - position) / (right - center)
+                    };
+                    (weight > 0.0).then_some((bin, weight))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Scale every dimension to zero mean and unit variance so no coefficient dominates distances
+fn standardize(points: &mut [(usize, Vec<f32>, f32)]) {
+    let dimensions = points[0].1.len();
+    let count = points.len() as f32;
+    for d in 0..dimensions {
+        let mean = points.iter().map(|(_, vector, _)| vector[d]).sum::<f32>() / count;
+        let deviation = (points
+            .iter()
+            .map(|(_, vector, _)| (vector[d] - mean).powi(2))
+            .sum::<f32>()
+            / count)
+            .sqrt()
+            .max(1e-6);
+        for (_, vector, _) in points.iter_mut() {
+            vector[d] = (vector[d] - mean) / deviation;
+        }
+    }
+}
+
+// Weighted 2-means, seeded with the heaviest point and the point farthest from it
+fn k_means(vectors: &[&[f32]], weights: &[f32]) -> (Vec<usize>, [Vec<f32>; 2]) {
+    let heaviest = (0..vectors.len())
+        .max_by(|a, b| weights[*a].total_cmp(&weights[*b]))
+        .unwrap_or_default();
+    let farthest = (0..vectors.len())
+        .max_by(|a, b| {
+            distance(vectors[*a], vectors[heaviest])
+                .total_cmp(&distance(vectors[*b], vectors[heaviest]))
+        })
+        .unwrap_or_default();
+    let mut centroids = [vectors[heaviest].to_vec(), vectors[farthest].to_vec()];
+
+    let mut assignment = vec![0; vectors.len()];
+    for _ in 0..K_MEANS_ITERATIONS {
+        let next: Vec<usize> = vectors
+            .iter()
+            .map(|vector| {
+                usize::from(distance(vector, &centroids[1]) < distance(vector, &centroids[0]))
+            })
+            .collect();
+        let converged = next == assignment;
+        assignment = next;
+
+        for (cluster, centroid) in centroids.iter_mut().enumerate() {
+            let members = || {
+                vectors
+                    .iter()
+                    .zip(weights)
+                    .zip(&assignment)
+                    .filter(move |(_, assigned)| **assigned == cluster)
+                    .map(|(member, _)| member)
+            };
+            let total: f32 = members().map(|(_, weight)| *weight).sum();
+            if total <= 0.0 {
+                continue;
+            }
+            for (d, value) in centroid.iter_mut().enumerate() {
+                *value = members()
+                    .map(|(vector, weight)| vector[d] * weight)
+                    .sum::<f32>()
+                    / total;
+            }
+        }
+        if converged {
+            break;
+        }
+    }
+    (assignment, centroids)
+}
+
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+fn decibels(power: f32) -> f32 {
+    10.0 * (power + 1e-12).log10()
+}
+
+fn ms_to_sample(ms: i32) -> usize {
+    ms.max(0) as usize * SAMPLE_RATE as usize / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(start_ms: i32, end_ms: i32) -> Segment {
+        Segment {
+            start_ms,
+            end_ms,
+            text: String::new(),
+            avg_probability: None,
+            speaker: None,
+        }
+    }
+
+    fn speakers(segments: &[Segment]) -> Vec<Option<i32>> {
+        segments.iter().map(|segment| segment.speaker).collect()
+    }
+
+    // Energy frames of one second per turn, loud on the channel of whoever speaks
+    fn channel(turns: &[bool]) -> Vec<f32> {
+        let frames = 1000 / ENERGY_FRAME_MS;
+        turns
+            .iter()
+            .flat_map(|speaks| vec![if *speaks { 0.5 } else { 0.001 }; frames])
+            .collect()
+    }
+
+    fn tone(frequency: f32, ms: usize) -> impl Iterator<Item = f32> {
+        (0..ms * SAMPLE_RATE as usize / 1000)
+            .map(move |n| 0.5 * (2.0 * PI * frequency * n as f32 / SAMPLE_RATE as f32).sin())
+    }
+
+    #[test]
+    fn test_diarize_separate_channels() {
+        let energy = vec![
+            channel(&[true, false, true]),
+            channel(&[false, true, false]),
+        ];
+        let mut segments = vec![segment(0, 1000), segment(1000, 2000), segment(2000, 3000)];
+
+        let method = diarize(&[], &energy, &mut segments);
+        assert_eq!(method, DiarizationMethod::Channels);
+        assert_eq!(speakers(&segments), [Some(1), Some(2), Some(1)]);
+    }
+
+    #[test]
+    fn test_diarize_same_mix_on_both_channels() {
+        let mix = channel(&[true, true, true]);
+        let energy = vec![mix.clone(), mix];
+        let mut segments = vec![segment(0, 1000), segment(1000, 2000), segment(2000, 3000)];
+
+        // Nothing to cluster either without samples
+        let method = diarize(&[], &energy, &mut segments);
+        assert_eq!(method, DiarizationMethod::Single);
+        assert_eq!(speakers(&segments), [Some(1), Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn test_diarize_two_tones() {
+        let samples: Vec<f32> = tone(300.0, 1000)
+            .chain(tone(1800.0, 1000))
+            .chain(tone(300.0, 1000))
+            .chain(tone(1800.0, 1000))
+            .collect();
+        let mut segments = vec![
+            segment(0, 1000),
+            segment(1000, 2000),
+            segment(2000, 3000),
+            segment(3000, 4000),
+        ];
+
+        let method = diarize(&samples, &[], &mut segments);
+        assert_eq!(method, DiarizationMethod::Clustering);
+        assert_eq!(speakers(&segments), [Some(1), Some(2), Some(1), Some(2)]);
+    }
+}
@@ -0,0 +1,385 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::{Client, Url};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::config::env_or;
+use crate::errors::DownloadError;
+
+// Bytes needed to tell the supported formats apart
+const MAGIC_LEN: usize = 12;
+
+#[derive(Clone)]
+struct DownloadConfig {
+    max_bytes: u64,
+    max_duration: Duration,
+    timeout: Duration,
+    connect_timeout: Duration,
+    max_redirects: usize,
+    retries: u32,
+    backoff: Duration,
+    allowed_hosts: Vec<String>,
+    denied_hosts: Vec<String>,
+    allow_private: bool,
+}
+
+impl DownloadConfig {
+    fn from_env() -> Self {
+        Self {
+            max_bytes: env_or("DOWNLOAD_MAX_BYTES", 100 * 1024 * 1024),
+            max_duration: Duration::from_secs(env_or("DOWNLOAD_MAX_DURATION_SECS", 4 * 60 * 60)),
+            timeout: Duration::from_secs(env_or("DOWNLOAD_TIMEOUT_SECS", 300)),
+            connect_timeout: Duration::from_secs(env_or("DOWNLOAD_CONNECT_TIMEOUT_SECS", 10)),
+            max_redirects: env_or("DOWNLOAD_MAX_REDIRECTS", 5),
+            retries: env_or("DOWNLOAD_RETRIES", 3),
+            backoff: Duration::from_millis(env_or("DOWNLOAD_BACKOFF_MS", 500)),
+            allowed_hosts: host_list("DOWNLOAD_ALLOWED_HOSTS"),
+            denied_hosts: host_list("DOWNLOAD_DENIED_HOSTS"),
+            allow_private: env_or("DOWNLOAD_ALLOW_PRIVATE", false),
+        }
+    }
+
+    // Checks that don't need the network: scheme, host lists and literal IP addresses
+    fn check_url(&self, url: &Url) -> Result<(), DownloadError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(DownloadError::InvalidUrl(
+                "only http and https URLs are supported".to_string(),
+            ));
+        }
+        let host = match url.host() {
+            Some(url::Host::Domain(domain)) => domain.to_ascii_lowercase(),
+            Some(url::Host::Ipv4(ip)) => self.check_ip(IpAddr::V4(ip))?.to_string(),
+            Some(url::Host::Ipv6(ip)) => self.check_ip(IpAddr::V6(ip))?.to_string(),
+            None => return Err(DownloadError::InvalidUrl("URL has no host".to_string())),
+        };
+
+        if self
+            .denied_hosts
+            .iter()
+            .any(|entry| host_matches(&host, entry))
+        {
+            return Err(DownloadError::Blocked(format!("host {} is denied", host)));
+        }
+        if !self.allowed_hosts.is_empty()
+            && !self
+                .allowed_hosts
+                .iter()
+                .any(|entry| host_matches(&host, entry))
+        {
+            return Err(DownloadError::Blocked(format!(
+                "host {} is not allowed",
+                host
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<IpAddr, DownloadError> {
+        if self.allow_private || is_public(ip) {
+            Ok(ip)
+        } else {
+            Err(DownloadError::Blocked(format!(
+                "{} is a private address",
+                ip
+            )))
+        }
+    }
+
+    fn client(&self) -> Result<Client, DownloadError> {
+        let config = self.clone();
+        // Every hop of a redirect goes through the same checks as the submitted URL
+        let policy = Policy::custom(move |attempt: Attempt| {
+            if attempt.previous().len() > config.max_redirects {
+                return attempt.error(DownloadError::TooManyRedirects(config.max_redirects));
+            }
+            match config.check_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(err) => attempt.error(err),
+            }
+        });
+
+        Client::builder()
+            .redirect(policy)
+            .connect_timeout(self.connect_timeout)
+            .dns_resolver(Arc::new(PublicResolver {
+                allow_private: self.allow_private,
+            }))
+            .build()
+            .map_err(DownloadError::from)
+    }
+}
+
+// Resolves like the system resolver but drops private addresses, so a public host name
+// can't be pointed at an internal service
+struct PublicResolver {
+    allow_private: bool,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow_private = self.allow_private;
+        Box::pin(async move {
+            let addrs = resolve_public(name.as_str(), allow_private).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+async fn resolve_public(host: &str, allow_private: bool) -> Result<Vec<SocketAddr>, DownloadError> {
+    let addrs = tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(|_| DownloadError::InvalidUrl(format!("could not resolve host {}", host)))?
+        .filter(|addr| allow_private || is_public(addr.ip()))
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(DownloadError::Blocked(format!(
+            "host {} resolves to a private address",
+            host
+        )));
+    }
+    Ok(addrs)
+}
+
+// Validate an audio URL when the call is submitted, so the client gets the reason right away
+// instead of a failed call later on
+pub async fn validate_audio_url(audio_url: &str) -> Result<(), DownloadError> {
+    let config = DownloadConfig::from_env();
+    let url = Url::parse(audio_url).map_err(|err| DownloadError::InvalidUrl(err.to_string()))?;
+    config.check_url(&url)?;
+    if let Some(url::Host::Domain(domain)) = url.host() {
+        resolve_public(domain, config.allow_private).await?;
+    }
+    Ok(())
+}
+
+// Validate a URL the server calls on its own, e.g. a webhook. Private addresses are refused
+// like for audio, the download host lists don't apply.
+pub async fn validate_public_url(url: &str, allow_private: bool) -> Result<Url, DownloadError> {
+    let config = public_config(allow_private);
+    let url = Url::parse(url).map_err(|err| DownloadError::InvalidUrl(err.to_string()))?;
+    config.check_url(&url)?;
+    if let Some(url::Host::Domain(domain)) = url.host() {
+        resolve_public(domain, allow_private).await?;
+    }
+    Ok(url)
+}
+
+// Client for the URLs checked by `validate_public_url`. Redirects are not followed, their
+// target could be anything.
+pub fn public_client(allow_private: bool, timeout: Duration) -> Result<Client, DownloadError> {
+    let config = public_config(allow_private);
+    Client::builder()
+        .redirect(Policy::none())
+        .connect_timeout(config.connect_timeout)
+        .timeout(timeout)
+        .dns_resolver(Arc::new(PublicResolver { allow_private }))
+        .build()
+        .map_err(DownloadError::from)
+}
+
+fn public_config(allow_private: bool) -> DownloadConfig {
+    DownloadConfig {
+        allowed_hosts: Vec::new(),
+        denied_hosts: Vec::new(),
+        allow_private,
+        ..DownloadConfig::from_env()
+    }
+}
+
+// Longest audio accepted. The length is only known once the file is decoded, so the limit is
+// enforced there, for uploads too.
+pub fn max_duration() -> Duration {
+    DownloadConfig::from_env().max_duration
+}
+
+// Download audio to the tmp folder, which is not served. The caller deletes the file once it
+// is decoded. Network errors, timeouts and 5xx responses are retried with exponential
+// backoff, anything else fails right away.
+pub async fn download_audio_file(audio_url: &str) -> Result<PathBuf, DownloadError> {
+    let config = DownloadConfig::from_env();
+    let url = Url::parse(audio_url).map_err(|err| DownloadError::InvalidUrl(err.to_string()))?;
+    config.check_url(&url)?;
+    let client = config.client()?;
+    tokio::fs::create_dir_all("./tmp").await?;
+    let path = PathBuf::from(format!("./tmp/{}", Uuid::new_v4()));
+
+    let mut attempt = 0;
+    loop {
+        let result = tokio::time::timeout(config.timeout, fetch(&client, &config, &url, &path))
+            .await
+            .unwrap_or_else(|_| Err(DownloadError::TimedOut(config.timeout.as_secs())));
+        let err = match result {
+            Ok(()) => return Ok(path),
+            Err(err) => err,
+        };
+
+        let _ = tokio::fs::remove_file(&path).await;
+        if !err.is_retryable() || attempt >= config.retries {
+            return Err(err);
+        }
+        let backoff = config.backoff.saturating_mul(2u32.pow(attempt.min(16)));
+        attempt += 1;
+        log::warn!(
+            "download of {} failed, retry {}/{} in {:?}: {}",
+            url,
+            attempt,
+            config.retries,
+            backoff,
+            err
+        );
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+// Stream the response to disk, checking status, content type, size and the format of the
+// first bytes on the way
+async fn fetch(
+    client: &Client,
+    config: &DownloadConfig,
+    url: &Url,
+    path: &Path,
+) -> Result<(), DownloadError> {
+    let mut response = client.get(url.clone()).send().await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(DownloadError::Status(status.as_u16()));
+    }
+    if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+        let content_type = content_type.to_str().unwrap_or_default();
+        if !is_audio_content_type(content_type) {
+            return Err(DownloadError::ContentType(content_type.to_string()));
+        }
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length > config.max_bytes)
+    {
+        return Err(DownloadError::TooLarge(config.max_bytes));
+    }
+
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut head = Vec::with_capacity(MAGIC_LEN);
+    let mut size = 0;
+    while let Some(chunk) = response.chunk().await? {
+        size += chunk.len() as u64;
+        if size > config.max_bytes {
+            return Err(DownloadError::TooLarge(config.max_bytes));
+        }
+        if head.len() < MAGIC_LEN {
+            let missing = (MAGIC_LEN - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..missing]);
+            if head.len() == MAGIC_LEN {
+                audio_format(&head).ok_or(DownloadError::UnknownFormat)?;
+            }
+        }
+        file.write_all(&chunk).await?;
+    }
+    if head.len() < MAGIC_LEN {
+        audio_format(&head).ok_or(DownloadError::UnknownFormat)?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+// Formats the pipeline can decode, detected from the first bytes of the file
+pub fn audio_format(head: &[u8]) -> Option<&'static str> {
+    match head {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("wav"),
+        [b'I', b'D', b'3', ..] => Some("mp3"),
+        [0xFF, frame, ..] if frame & 0xE0 == 0xE0 => Some("mp3"),
+        [b'O', b'g', b'g', b'S', ..] => Some("ogg"),
+        [b'f', b'L', b'a', b'C', ..] => Some("flac"),
+        // MP4 container, the box size comes before the type
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("m4a"),
+        _ => None,
+    }
+}
+
+// Servers often label audio as a generic binary, the magic bytes decide in that case
+fn is_audio_content_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("audio/")
+        || matches!(
+            essence.as_str(),
+            "application/octet-stream" | "binary/octet-stream" | "application/ogg"
+        )
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space, 100.64.0.0/10
+                || (a == 100 && b & 0xC0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || first & 0xFE00 == 0xFC00
+                    // Link local, fe80::/10
+                    || first & 0xFFC0 == 0xFE80)
+            }
+        },
+    }
+}
+
+// Comma separated, an entry matches the host itself and its subdomains
+fn host_list(key: &str) -> Vec<String> {
+    env_or(key, String::new())
+        .split(',')
+        .map(|host| host.trim().trim_start_matches("*.").to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+}
+
+fn host_matches(host: &str, entry: &str) -> bool {
+    host == entry
+        || host
+            .strip_suffix(entry)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::audio_format;
+
+    #[test]
+    fn test_audio_format_m4a() {
+        let head = [
+            0, 0, 0, 0x20, b'f', b't', b'y', b'p', b'M', b'4', b'A', b' ',
+        ];
+        assert_eq!(audio_format(&head), Some("m4a"));
+    }
+
+    #[test]
+    fn test_audio_format_unknown() {
+        assert_eq!(audio_format(b"<!DOCTYPE ht"), None);
+    }
+}
@@ -0,0 +1,168 @@
+use rust_bert::pipelines::token_classification::{Token, TokenClassificationModel};
+use rust_tokenizers::Mask;
+
+use super::models::EntityType;
+
+// Named entity found in a text
+#[derive(Clone, Debug)]
+pub struct EntitySpan {
+    pub entity_type: EntityType,
+    pub text: String,
+    pub normalized: String,
+    // Character offsets into the text, end exclusive
+    pub start_char: usize,
+    pub end_char: usize,
+    // Mean score of the words in the span
+    pub score: f64,
+}
+
+// A word of the input with the label of its first piece
+struct Word {
+    label: String,
+    score: f64,
+    start_char: usize,
+    end_char: usize,
+}
+
+// Run NER over all texts in one batch and merge the tagged pieces into entity spans
+pub fn extract_entities<S: AsRef<str>>(
+    texts: &[S],
+    ner: &TokenClassificationModel,
+) -> Vec<Vec<EntitySpan>> {
+    ner.predict(texts, false, false)
+        .into_iter()
+        .zip(texts)
+        .map(|(tokens, text)| merge_entities(text.as_ref(), &words(&tokens)))
+        .collect()
+}
+
+// Join WordPiece (`##`) and SentencePiece fragments back into words. The models are trained on
+// the label of the first piece, later pieces only carry noise.
+fn words(tokens: &[Token]) -> Vec<Word> {
+    let mut words: Vec<Word> = Vec::new();
+    for token in tokens {
+        let Some(offset) = token.offset else {
+            continue;
+        };
+        match words.last_mut() {
+            Some(word) if token.mask == Mask::Continuation => {
+                word.end_char = offset.end as usize;
+            }
+            _ => words.push(Word {
+                label: token.label.clone(),
+                score: token.score,
+                start_char: offset.begin as usize,
+                end_char: offset.end as usize,
+            }),
+        }
+    }
+    words
+}
+
+// Group consecutive words into spans. `B-` always starts an entity, `I-` continues one of the
+// same type and starts a new one otherwise, which also covers IOB1 models tagging only `I-`.
+fn merge_entities(text: &str, words: &[Word]) -> Vec<EntitySpan> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut spans = Vec::new();
+    // Entity being built: its type, first and last word
+    let mut current: Option<(EntityType, usize, usize)> = None;
+
+    for (index, word) in words.iter().enumerate() {
+        let (begins, entity_type) = match word.label.split_once('-') {
+            Some(("B", entity_type)) => (true, ner_type(entity_type)),
+            Some(("I", entity_type)) => (false, ner_type(entity_type)),
+            // Some models tag the type alone
+            _ => (false, ner_type(&word.label)),
+        };
+        match (current, entity_type) {
+            (Some((current_type, _, _)), Some(entity_type))
+                if !begins && current_type == entity_type =>
+            {
+                current = current.map(|(entity_type, first, _)| (entity_type, first, index));
+            }
+            _ => {
+                if let Some(entity) = current.take() {
+                    spans.extend(span(&chars, words, entity));
+                }
+                current = entity_type.map(|entity_type| (entity_type, index, index));
+            }
+        }
+    }
+    if let Some(entity) = current {
+        spans.extend(span(&chars, words, entity));
+    }
+    spans
+}
+
+// Types of the CoNLL label set. Models tagging more, like OntoNotes dates, would mix their
+// text with the canonical values `api::identifiers` stores.
+fn ner_type(label: &str) -> Option<EntityType> {
+    EntityType::parse(label).filter(|entity_type| {
+        matches!(
+            entity_type,
+            EntityType::Person | EntityType::Location | EntityType::Organization | EntityType::Misc
+        )
+    })
+}
+
+fn span(
+    chars: &[char],
+    words: &[Word],
+    (entity_type, first, last): (EntityType, usize, usize),
+) -> Option<EntitySpan> {
+    let start_char = words[first].start_char.min(chars.len());
+    let end_char = words[last].end_char.clamp(start_char, chars.len());
+    let text = chars[start_char..end_char].iter().collect::<String>();
+    let normalized = normalize(&text);
+    // A lone punctuation mark tagged as part of an entity
+    if normalized.is_empty() {
+        return None;
+    }
+    let words = &words[first..=last];
+    Some(EntitySpan {
+        entity_type,
+        text,
+        normalized,
+        start_char,
+        end_char,
+        score: words.iter().map(|word| word.score).sum::<f64>() / words.len() as f64,
+    })
+}
+
+// Lowercase with single spaces, without surrounding punctuation or a trailing possessive, so
+// "Smith's" and "smith" are the same entity
+pub fn normalize(text: &str) -> String {
+    let text = text.trim_matches(|c: char| !c.is_alphanumeric());
+    let text = ["'s", "’s"]
+        .iter()
+        .find_map(|possessive| text.strip_suffix(possessive))
+        .unwrap_or(text);
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// Distinct entities of one type in order of appearance, joined into a summary that fits the
+// VARCHAR(255) `name` and `location` columns
+pub fn summarize<'a>(
+    spans: impl IntoIterator<Item = &'a EntitySpan>,
+    entity_type: EntityType,
+) -> Option<String> {
+    const MAX_LEN: usize = 255;
+    let mut seen = Vec::new();
+    let mut summary = String::new();
+    for span in spans {
+        if span.entity_type != entity_type || seen.contains(&span.normalized) {
+            continue;
+        }
+        let separator = if summary.is_empty() { "" } else { ", " };
+        if summary.chars().count() + separator.len() + span.text.chars().count() > MAX_LEN {
+            break;
+        }
+        summary.push_str(separator);
+        summary.push_str(&span.text);
+        seen.push(span.normalized.clone());
+    }
+    (!summary.is_empty()).then_some(summary)
+}
@@ -0,0 +1,281 @@
+use std::collections::HashSet;
+
+use actix_web::{get, post, web, HttpResponse, Responder};
+use sqlx::{PgConnection, PgPool};
+
+use super::models::{Entity, EntityCall, EntityType, MergeEntities, SplitEntity};
+use super::registry::{entity_key, lock};
+use crate::db::establish_connection;
+use crate::errors::{AppError, AppResult};
+
+const SELECT_ENTITY: &str = r#"
+    SELECT e.id, e.entity_type, e.name, e.created_at,
+        (SELECT COUNT(*) FROM call_entity m WHERE m.entity_id = e.id) AS mentions,
+        (SELECT COUNT(DISTINCT m.call_id) FROM call_entity m WHERE m.entity_id = e.id) AS calls,
+        ARRAY(SELECT a.key FROM entity_alias a WHERE a.entity_id = e.id ORDER BY a.key) AS aliases
+    FROM entity e
+    WHERE e.id = $1
+    "#;
+
+async fn fetch_entity(conn: &mut PgConnection, id: i32) -> Result<Option<Entity>, sqlx::Error> {
+    sqlx::query_as::<_, Entity>(SELECT_ENTITY)
+        .bind(id)
+        .fetch_optional(conn)
+        .await
+}
+
+#[get("/entity/{id}")]
+pub async fn get_entity(pool: web::Data<PgPool>, id: web::Path<i32>) -> AppResult<impl Responder> {
+    let mut conn = pool.acquire().await?;
+    match fetch_entity(&mut conn, *id).await? {
+        Some(entity) => Ok(HttpResponse::Ok().json(entity)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// Every call mentioning an entity, newest first
+#[get("/entity/{id}/calls")]
+pub async fn get_entity_calls(
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> AppResult<impl Responder> {
+    let exists = sqlx::query_scalar::<_, i32>("SELECT id FROM entity WHERE id = $1")
+        .bind(*id)
+        .fetch_optional(pool.get_ref())
+        .await?;
+    if exists.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let calls = sqlx::query_as::<_, EntityCall>(
+        r#"
+    SELECT c.id, c.status, c.created_at, COUNT(*) AS mentions,
+        ARRAY_AGG(DISTINCT m.text) AS texts
+    FROM call_entity m
+    JOIN call c ON c.id = m.call_id
+    WHERE m.entity_id = $1
+    GROUP BY c.id
+    ORDER BY c.created_at DESC
+    "#,
+    )
+    .bind(*id)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(calls))
+}
+
+// Fold duplicates into the entity in the path. Their mentions and aliases move over and they
+// are deleted.
+#[post("/entity/{id}/merge")]
+pub async fn merge_entities(
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+    merge: web::Json<MergeEntities>,
+) -> AppResult<impl Responder> {
+    let id = *id;
+    let sources = merge
+        .entity_ids
+        .iter()
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    if sources.is_empty() {
+        return Err(AppError::Invalid(
+            "entity_ids must not be empty".to_string(),
+        ));
+    }
+    if sources.contains(&id) {
+        return Err(AppError::Invalid(format!(
+            "entity {} can't be merged into itself",
+            id
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+    lock(&mut tx).await?;
+    let Some(target) = fetch_entity(&mut tx, id).await? else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let found =
+        sqlx::query_as::<_, (i32, String)>("SELECT id, entity_type FROM entity WHERE id = ANY($1)")
+            .bind(&sources)
+            .fetch_all(&mut *tx)
+            .await?;
+    if let Some(missing) = sources
+        .iter()
+        .find(|source| !found.iter().any(|(id, _)| id == *source))
+    {
+        return Err(AppError::Invalid(format!("unknown entity {}", missing)));
+    }
+    if let Some((other, entity_type)) = found
+        .iter()
+        .find(|(_, entity_type)| *entity_type != target.entity_type)
+    {
+        return Err(AppError::Invalid(format!(
+            "entity {} is {}, not {}",
+            other, entity_type, target.entity_type
+        )));
+    }
+
+    sqlx::query("UPDATE call_entity SET entity_id = $1 WHERE entity_id = ANY($2)")
+        .bind(id)
+        .bind(&sources)
+        .execute(&mut *tx)
+        .await?;
+    // Keys are unique per type, so the moved aliases can't collide with the target's
+    sqlx::query("UPDATE entity_alias SET entity_id = $1 WHERE entity_id = ANY($2)")
+        .bind(id)
+        .bind(&sources)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM entity WHERE id = ANY($1)")
+        .bind(&sources)
+        .execute(&mut *tx)
+        .await?;
+    let merged = fetch_entity(&mut tx, id).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(merged))
+}
+
+// Move mentions wrongly linked to an entity to a new one. The aliases go along unless a
+// mention left behind still uses them, later mentions by those names keep linking to the
+// original entity.
+#[post("/entity/{id}/split")]
+pub async fn split_entity(
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+    split: web::Json<SplitEntity>,
+) -> AppResult<impl Responder> {
+    let id = *id;
+    let split = split.into_inner();
+    if split.mention_ids.is_empty() {
+        return Err(AppError::Invalid(
+            "mention_ids must not be empty".to_string(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+    lock(&mut tx).await?;
+    let Some(entity) = fetch_entity(&mut tx, id).await? else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let Some(entity_type) = EntityType::parse(&entity.entity_type) else {
+        return Err(anyhow::anyhow!("unknown entity type {}", entity.entity_type).into());
+    };
+    let mentions = sqlx::query_as::<_, (i32, String, String)>(
+        "SELECT id, text, normalized FROM call_entity WHERE entity_id = $1 ORDER BY id",
+    )
+    .bind(id)
+    .fetch_all(&mut *tx)
+    .await?;
+    if let Some(missing) = split
+        .mention_ids
+        .iter()
+        .find(|mention| !mentions.iter().any(|(id, _, _)| id == *mention))
+    {
+        return Err(AppError::Invalid(format!(
+            "mention {} isn't linked to entity {}",
+            missing, id
+        )));
+    }
+    let (moved, kept): (Vec<_>, Vec<_>) = mentions
+        .iter()
+        .partition(|(id, _, _)| split.mention_ids.contains(id));
+    if kept.is_empty() {
+        return Err(AppError::Invalid(format!(
+            "splitting every mention would leave entity {} empty",
+            id
+        )));
+    }
+
+    let name = split
+        .name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| moved[0].1.trim().to_string());
+    let new_id = sqlx::query_scalar::<_, i32>(
+        "INSERT INTO entity (entity_type, name) VALUES ($1, $2) RETURNING id",
+    )
+    .bind(entity_type.as_str())
+    .bind(name)
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE call_entity SET entity_id = $1 WHERE id = ANY($2)")
+        .bind(new_id)
+        .bind(moved.iter().map(|(id, _, _)| *id).collect::<Vec<_>>())
+        .execute(&mut *tx)
+        .await?;
+
+    let kept_keys = kept
+        .iter()
+        .map(|(_, _, normalized)| entity_key(entity_type, normalized))
+        .collect::<HashSet<_>>();
+    let moved_keys = moved
+        .iter()
+        .map(|(_, _, normalized)| entity_key(entity_type, normalized))
+        .filter(|key| !kept_keys.contains(key))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    sqlx::query(
+        r#"
+    UPDATE entity_alias SET entity_id = $1
+    WHERE entity_type = $2 AND key = ANY($3) AND entity_id = $4
+    "#,
+    )
+    .bind(new_id)
+    .bind(entity_type.as_str())
+    .bind(&moved_keys)
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+    let created = fetch_entity(&mut tx, new_id).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(created))
+}
+
+use actix_web::{http::StatusCode, test, App};
+use serde_json::json;
+
+// Test GET /entity/{id}/calls with an entity that doesn't exist
+#[actix_web::test]
+async fn test_get_entity_calls_not_found() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .service(get_entity_calls),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/entity/-1/calls")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+// Test POST /entity/{id}/merge with the entity itself among the duplicates
+#[actix_web::test]
+async fn test_merge_entity_into_itself() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .service(merge_entities),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/entity/1/merge")
+        .set_json(json!({ "entity_ids": [1] }))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
@@ -0,0 +1,327 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::{PgPool, Postgres};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use super::call::fetch_call;
+use super::models::{Call, CallStatus};
+use super::utils::Segment;
+use crate::config::env_or;
+use crate::db::establish_connection;
+use crate::errors::AppResult;
+
+// Postgres channel the pipeline publishes on, every instance listens so the client gets the
+// events whichever worker runs the call
+const CHANNEL: &str = "call_events";
+
+// NOTIFY payloads have to stay below 8000 bytes
+const MAX_PAYLOAD: usize = 7999;
+
+// Events a subscriber may fall behind by before it skips some
+const CAPACITY: usize = 1024;
+
+// Progress of a call through the pipeline
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum CallEvent {
+    // The call entered a stage, see `CallStatus`
+    Status {
+        call_id: Uuid,
+        status: String,
+    },
+    // Chunks transcribed (or translated) by the current stage
+    Progress {
+        call_id: Uuid,
+        chunks_done: i32,
+        chunks_total: i32,
+    },
+    Language {
+        call_id: Uuid,
+        language: String,
+        probability: Option<f64>,
+    },
+    // Transcript of one chunk, as soon as it is ready
+    Segments {
+        call_id: Uuid,
+        translation: bool,
+        segments: Vec<EventSegment>,
+    },
+    // The call is stored, `call` holds the results
+    Done {
+        call_id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        call: Option<Box<Call>>,
+    },
+    // `status` is `queued` when the call is retried, `failed` when it is given up
+    Failed {
+        call_id: Uuid,
+        status: String,
+        error: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EventSegment {
+    pub start_ms: i32,
+    pub end_ms: i32,
+    pub text: String,
+}
+
+impl From<&Segment> for EventSegment {
+    fn from(segment: &Segment) -> Self {
+        Self {
+            start_ms: segment.start_ms,
+            end_ms: segment.end_ms,
+            text: segment.text.clone(),
+        }
+    }
+}
+
+impl CallEvent {
+    fn call_id(&self) -> Uuid {
+        match self {
+            Self::Status { call_id, .. }
+            | Self::Progress { call_id, .. }
+            | Self::Language { call_id, .. }
+            | Self::Segments { call_id, .. }
+            | Self::Done { call_id, .. }
+            | Self::Failed { call_id, .. } => *call_id,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Status { .. } => "status",
+            Self::Progress { .. } => "progress",
+            Self::Language { .. } => "language",
+            Self::Segments { .. } => "segments",
+            Self::Done { .. } => "done",
+            Self::Failed { .. } => "failed",
+        }
+    }
+
+    // Nothing follows for the call
+    fn is_final(&self) -> bool {
+        match self {
+            Self::Done { .. } => true,
+            Self::Failed { status, .. } => status == CallStatus::Failed.as_str(),
+            _ => false,
+        }
+    }
+
+    // The event in the Server-Sent Events format
+    fn to_sse(&self) -> Bytes {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        Bytes::from(format!("event: {}\ndata: {}\n\n", self.name(), data))
+    }
+}
+
+// Publish an event to every instance. Inside a transaction it is only sent on commit.
+pub async fn publish<'e, E>(executor: E, event: &CallEvent) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    let payload = serde_json::to_string(event).unwrap_or_default();
+    if payload.len() > MAX_PAYLOAD {
+        log::warn!(
+            "{} event of call {} is too large to publish",
+            event.name(),
+            event.call_id()
+        );
+        return Ok(());
+    }
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(payload)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+// Fans the events published by the pipeline out to the SSE clients of this instance
+#[derive(Clone)]
+pub struct EventHub {
+    sender: broadcast::Sender<Arc<CallEvent>>,
+}
+
+impl EventHub {
+    // Hub that doesn't listen, for handlers that never get any events
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+
+    pub fn start(pool: PgPool) -> Self {
+        let hub = Self::new();
+        let sender = hub.sender.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                if let Err(err) = listen(&pool, &sender).await {
+                    log::error!("call event listener failed: {:?}", err);
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+        hub
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<CallEvent>> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn listen(pool: &PgPool, sender: &broadcast::Sender<Arc<CallEvent>>) -> anyhow::Result<()> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    loop {
+        let notification = listener.recv().await?;
+        let mut event = match serde_json::from_str::<CallEvent>(notification.payload()) {
+            Ok(event) => event,
+            Err(err) => {
+                log::warn!("invalid call event {}: {}", notification.payload(), err);
+                continue;
+            }
+        };
+        // The results don't fit into a notification, they are loaded once per instance
+        if let CallEvent::Done { call_id, call } = &mut event {
+            *call = fetch_call(pool, *call_id).await?.map(Box::new);
+        }
+        // Fails only when nobody is subscribed
+        let _ = sender.send(Arc::new(event));
+    }
+}
+
+// Server-Sent Events of a single call: its current state first, then every change until the
+// call is done or has failed for good
+#[get("/call/{id}/events")]
+pub async fn call_events(
+    pool: web::Data<PgPool>,
+    hub: web::Data<EventHub>,
+    id: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    // Subscribed before the call is read so nothing is missed in between
+    let receiver = hub.subscribe();
+    let Some(call) = fetch_call(pool.get_ref(), *id).await? else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let current = if call.status == CallStatus::Done.as_str() {
+        CallEvent::Done {
+            call_id: call.id,
+            call: Some(Box::new(call)),
+        }
+    } else if call.status == CallStatus::Failed.as_str() {
+        CallEvent::Failed {
+            call_id: call.id,
+            status: call.status,
+            error: call.error.unwrap_or_default(),
+        }
+    } else {
+        CallEvent::Status {
+            call_id: call.id,
+            status: call.status,
+        }
+    };
+    Ok(sse_response(receiver, Some(*id), Some(current)))
+}
+
+// Server-Sent Events of every call
+#[get("/events")]
+pub async fn events(hub: web::Data<EventHub>) -> HttpResponse {
+    sse_response(hub.subscribe(), None, None)
+}
+
+struct Subscription {
+    receiver: broadcast::Receiver<Arc<CallEvent>>,
+    call_id: Option<Uuid>,
+    pending: Option<CallEvent>,
+    finished: bool,
+}
+
+fn sse_response(
+    receiver: broadcast::Receiver<Arc<CallEvent>>,
+    call_id: Option<Uuid>,
+    current: Option<CallEvent>,
+) -> HttpResponse {
+    let keep_alive = Duration::from_secs(env_or("EVENTS_KEEP_ALIVE_SECS", 15));
+    let subscription = Subscription {
+        receiver,
+        call_id,
+        pending: current,
+        finished: false,
+    };
+    let body = futures_util::stream::unfold(subscription, move |mut subscription| async move {
+        if subscription.finished {
+            return None;
+        }
+        if let Some(event) = subscription.pending.take() {
+            subscription.finished = subscription.call_id.is_some() && event.is_final();
+            return Some((Ok::<_, actix_web::Error>(event.to_sse()), subscription));
+        }
+        loop {
+            let received = tokio::select! {
+                received = subscription.receiver.recv() => received,
+                // A comment keeps proxies from closing an idle connection
+                _ = tokio::time::sleep(keep_alive) => {
+                    return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), subscription));
+                }
+            };
+            match received {
+                Ok(event) => {
+                    if subscription
+                        .call_id
+                        .is_some_and(|call_id| call_id != event.call_id())
+                    {
+                        continue;
+                    }
+                    subscription.finished = subscription.call_id.is_some() && event.is_final();
+                    return Some((Ok(event.to_sse()), subscription));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("event subscriber skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+use actix_web::{http::StatusCode, test, App};
+
+// Test GET /call/{id}/events
+#[actix_web::test]
+async fn test_call_events() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(EventHub::new()))
+            .service(call_events),
+    )
+    .await;
+
+    let call_id = Uuid::new_v4(); // Unknown call
+    let req = test::TestRequest::get()
+        .uri(&format!("/call/{}/events", call_id))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+
+use super::models::EntityType;
+use super::registry::entity_key;
+use crate::config::env_or;
+
+const DEFAULT_PLACES_PATH: &str = "data/gazetteer/places.tsv";
+const DEFAULT_ADMIN1_PATH: &str = "data/gazetteer/admin1.tsv";
+
+// Mentions of the capital without naming it
+const CAPITAL: &[&str] = &["capital", "capital city", "столиця", "столица"];
+
+// Place of the gazetteer a location mention resolved to
+#[derive(Clone, Debug)]
+pub struct Place {
+    pub geoname_id: i64,
+    pub name: String,
+    // ISO 3166-1 alpha-2
+    pub country_code: String,
+    // First-level administrative division, its name when the admin1 file has it
+    pub admin_region: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    feature_code: String,
+    population: i64,
+}
+
+impl Place {
+    fn is_country(&self) -> bool {
+        self.feature_code.starts_with("PCL")
+    }
+}
+
+// Offline gazetteer in the GeoNames dump format, matched on the same keys as the entity
+// registry so transliterations and alternate spellings resolve alike
+#[derive(Default)]
+pub struct Gazetteer {
+    places: Vec<Place>,
+    // Key of every name and alternate name to the places carrying it
+    names: HashMap<String, Vec<usize>>,
+    capitals: HashMap<String, usize>,
+    // Country preferred for ambiguous names when nothing else in the call points to one
+    default_country: Option<String>,
+}
+
+impl Gazetteer {
+    // Load the files named by GAZETTEER_PATH and GAZETTEER_ADMIN1_PATH. Without them locations
+    // are stored unresolved, which must not keep the server from starting.
+    pub fn from_env() -> Self {
+        let path = env_or("GAZETTEER_PATH", DEFAULT_PLACES_PATH.to_string());
+        let admin1_path = env_or("GAZETTEER_ADMIN1_PATH", DEFAULT_ADMIN1_PATH.to_string());
+        match Self::load(&path, &admin1_path) {
+            Ok(gazetteer) => {
+                log::info!("loaded {} places from {}", gazetteer.places.len(), path);
+                gazetteer
+            }
+            Err(err) => {
+                log::warn!("locations won't be geocoded: {:?}", err);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn load(path: &str, admin1_path: &str) -> Result<Self> {
+        let places = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        // Region names are optional, the codes are used without them
+        let admin1 = fs::read_to_string(admin1_path).unwrap_or_default();
+        let mut gazetteer = Self::parse(&places, &admin1)?;
+        let country = env_or("GAZETTEER_DEFAULT_COUNTRY", "UA".to_string())
+            .trim()
+            .to_ascii_uppercase();
+        gazetteer.default_country = (!country.is_empty()).then_some(country);
+        Ok(gazetteer)
+    }
+
+    // Parse `places` as the GeoNames geoname table and `admin1` as admin1CodesASCII.txt
+    fn parse(places: &str, admin1: &str) -> Result<Self> {
+        let admin1 = admin1
+            .lines()
+            .filter_map(|line| {
+                let mut columns = line.split('\t');
+                Some((columns.next()?, columns.next()?))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut gazetteer = Self::default();
+        for (number, line) in places.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let columns = line.split('\t').collect::<Vec<_>>();
+            if columns.len() < 15 {
+                return Err(anyhow!("line {}: expected at least 15 columns", number + 1));
+            }
+            let parse_error = |column| anyhow!("line {}: malformed {}", number + 1, column);
+            let country_code = columns[8].to_string();
+            let admin_region = match columns[10] {
+                "" | "00" => None,
+                code => Some(
+                    admin1
+                        .get(format!("{}.{}", country_code, code).as_str())
+                        .map_or(code, |name| *name)
+                        .to_string(),
+                ),
+            };
+            let place = Place {
+                geoname_id: columns[0].parse().map_err(|_| parse_error("geonameid"))?,
+                name: columns[1].to_string(),
+                country_code,
+                admin_region,
+                latitude: columns[4].parse().map_err(|_| parse_error("latitude"))?,
+                longitude: columns[5].parse().map_err(|_| parse_error("longitude"))?,
+                feature_code: columns[7].to_string(),
+                population: columns[14].parse().unwrap_or(0),
+            };
+
+            let index = gazetteer.places.len();
+            let names = [columns[1], columns[2]]
+                .into_iter()
+                .chain(columns[3].split(','));
+            for name in names {
+                let key = place_key(name);
+                if key.is_empty() {
+                    continue;
+                }
+                let places = gazetteer.names.entry(key).or_default();
+                if !places.contains(&index) {
+                    places.push(index);
+                }
+            }
+            if place.feature_code == "PPLC" {
+                gazetteer.capitals.insert(place.country_code.clone(), index);
+            }
+            gazetteer.places.push(place);
+        }
+        Ok(gazetteer)
+    }
+
+    // Resolve the location mentions of a call. Ambiguous names go to the country most of the
+    // unambiguous mentions are in, so a "Paris" among American towns stays in America.
+    pub fn resolve_all<'a>(&'a self, mentions: &[&str]) -> Vec<Option<&'a Place>> {
+        let keys = mentions
+            .iter()
+            .map(|text| place_key(text))
+            .collect::<Vec<_>>();
+        let mut countries = HashMap::<&str, usize>::new();
+        for key in &keys {
+            if let [index] = self.names.get(key).map_or(&[][..], Vec::as_slice) {
+                *countries
+                    .entry(self.places[*index].country_code.as_str())
+                    .or_default() += 1;
+            }
+        }
+        let country = countries
+            .into_iter()
+            .max_by_key(|(country, count)| (*count, *country))
+            .map(|(country, _)| country)
+            .or(self.default_country.as_deref());
+
+        keys.iter().map(|key| self.resolve(key, country)).collect()
+    }
+
+    fn resolve(&self, key: &str, country: Option<&str>) -> Option<&Place> {
+        if CAPITAL.iter().any(|capital| place_key(capital) == key) {
+            return country
+                .and_then(|country| self.capitals.get(country))
+                .map(|index| &self.places[*index]);
+        }
+        // Countries win over towns named after them, then the country of the call, then size
+        self.names
+            .get(key)?
+            .iter()
+            .map(|index| &self.places[*index])
+            .max_by_key(|place| {
+                (
+                    place.is_country(),
+                    Some(place.country_code.as_str()) == country,
+                    place.population,
+                )
+            })
+    }
+}
+
+fn place_key(name: &str) -> String {
+    entity_key(EntityType::Location, &super::entities::normalize(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A line of the geoname table, columns a test doesn't look at are left empty
+    fn row(
+        id: i64,
+        name: &str,
+        alternate_names: &str,
+        feature_code: &str,
+        country_code: &str,
+        admin1: &str,
+        population: i64,
+    ) -> String {
+        format!(
+            "{id}\t{name}\t{name}\t{alternate_names}\t1.5\t2.5\tP\t{feature_code}\t{country_code}\t\t{admin1}\t\t\t\t{population}\t\t0\tEurope/Kyiv\t2024-01-01"
+        )
+    }
+
+    fn gazetteer() -> Gazetteer {
+        let places = [
+            "# comments and empty lines are skipped".to_string(),
+            String::new(),
+            row(690791, "Ukraine", "Україна", "PCLI", "UA", "00", 44_000_000),
+            row(
+                703448,
+                "Kyiv",
+                "Kiev,Київ,Киев",
+                "PPLC",
+                "UA",
+                "12",
+                2_797_553,
+            ),
+            row(
+                698740,
+                "Odesa",
+                "Odessa,Одеса",
+                "PPLA",
+                "UA",
+                "17",
+                1_015_826,
+            ),
+            row(2988507, "Paris", "Париж", "PPLC", "FR", "11", 2_138_551),
+            row(4717560, "Paris", "", "PPLA2", "US", "TX", 24_782),
+            row(4699066, "Houston", "", "PPLA2", "US", "TX", 2_296_224),
+            row(4671654, "Austin", "", "PPLA", "US", "TX", 931_830),
+            row(4140963, "Washington", "", "PPLC", "US", "DC", 689_545),
+        ]
+        .join("\n");
+        let admin1 = "UA.12\tKyiv City\tKyiv City\t703447\nUS.TX\tTexas\tTexas\t4736286\n";
+        Gazetteer::parse(&places, admin1).unwrap()
+    }
+
+    fn resolved(gazetteer: &Gazetteer, mentions: &[&str]) -> Vec<Option<i64>> {
+        gazetteer
+            .resolve_all(mentions)
+            .into_iter()
+            .map(|place| place.map(|place| place.geoname_id))
+            .collect()
+    }
+
+    #[test]
+    fn test_alternate_names() {
+        let gazetteer = gazetteer();
+        assert_eq!(
+            resolved(&gazetteer, &["Kyiv", "Kiev", "Київ", "Киев"]),
+            vec![Some(703448); 4]
+        );
+        let kyiv = gazetteer.resolve_all(&["Kyiv"])[0].unwrap();
+        assert_eq!(kyiv.name, "Kyiv");
+        assert_eq!(kyiv.country_code, "UA");
+        assert_eq!(kyiv.admin_region.as_deref(), Some("Kyiv City"));
+        // A region missing from the admin1 file keeps its code
+        let odesa = gazetteer.resolve_all(&["Odessa"])[0].unwrap();
+        assert_eq!(odesa.admin_region.as_deref(), Some("17"));
+        assert_eq!(resolved(&gazetteer, &["Atlantis"]), vec![None]);
+    }
+
+    #[test]
+    fn test_capital() {
+        let mut gazetteer = gazetteer();
+        // The capital of the country the call mentions
+        assert_eq!(
+            resolved(&gazetteer, &["Houston", "the capital"]),
+            vec![Some(4699066), Some(4140963)]
+        );
+        assert_eq!(
+            resolved(&gazetteer, &["Odesa", "столиця"]),
+            vec![Some(698740), Some(703448)]
+        );
+        // Nothing points to a country
+        assert_eq!(resolved(&gazetteer, &["capital"]), vec![None]);
+        gazetteer.default_country = Some("UA".to_string());
+        assert_eq!(resolved(&gazetteer, &["capital"]), vec![Some(703448)]);
+    }
+
+    #[test]
+    fn test_ambiguous_names() {
+        let mut gazetteer = gazetteer();
+        // Most of the call is in Texas
+        assert_eq!(
+            resolved(&gazetteer, &["Houston", "Austin", "Paris"]),
+            vec![Some(4699066), Some(4671654), Some(4717560)]
+        );
+        // Without other mentions the larger place wins
+        assert_eq!(resolved(&gazetteer, &["Paris"]), vec![Some(2988507)]);
+        gazetteer.default_country = Some("US".to_string());
+        assert_eq!(resolved(&gazetteer, &["Paris"]), vec![Some(4717560)]);
+        // The mentions outweigh the default country
+        assert_eq!(
+            resolved(&gazetteer, &["Kyiv", "Odesa", "Paris"]),
+            vec![Some(703448), Some(698740), Some(2988507)]
+        );
+        // A country wins over places named after it
+        assert_eq!(resolved(&gazetteer, &["Ukraine"]), vec![Some(690791)]);
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        let valid = row(703448, "Kyiv", "", "PPLC", "UA", "12", 1);
+        let short = "703448\tKyiv\tKyiv\t\t50.45\t30.52";
+        let error = Gazetteer::parse(&format!("{}\n{}", valid, short), "")
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "line 2: expected at least 15 columns");
+
+        let latitude = valid.replace("\t1.5\t", "\tnorth\t");
+        let error = Gazetteer::parse(&latitude, "").err().unwrap();
+        assert_eq!(error.to_string(), "line 1: malformed latitude");
+        let id = valid.replace("703448", "kyiv");
+        let error = Gazetteer::parse(&id, "").err().unwrap();
+        assert_eq!(error.to_string(), "line 1: malformed geonameid");
+
+        // The population is optional
+        let gazetteer = Gazetteer::parse(&valid.replace("\t1\t", "\t\t"), "").unwrap();
+        assert_eq!(gazetteer.places[0].population, 0);
+    }
+}
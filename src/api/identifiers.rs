@@ -0,0 +1,746 @@
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use chrono::{Datelike, Days, Months, NaiveDate, Weekday};
+use regex::{Captures, Regex};
+use rust_bert::pipelines::zero_shot_classification::ZeroShotClassificationModel;
+
+use super::entities::EntitySpan;
+use super::models::EntityType;
+use crate::config::env_or;
+
+// Characters before a candidate searched for words naming what it is
+const CONTEXT_CHARS: usize = 48;
+
+const PASSPORT_WORDS: &[&str] = &["passport", "travel document", "mrz", "паспорт"];
+const VISA_WORDS: &[&str] = &["visa", "application", "reference", "віз", "виз", "заявк"];
+const PHONE_WORDS: &[&str] = &[
+    "phone",
+    "mobile",
+    "cell",
+    "call me",
+    "call back",
+    "телефон",
+    "мобільн",
+    "мобильн",
+];
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[(&str, Weekday)] = &[
+    ("monday", Weekday::Mon),
+    ("tuesday", Weekday::Tue),
+    ("wednesday", Weekday::Wed),
+    ("thursday", Weekday::Thu),
+    ("friday", Weekday::Fri),
+    ("saturday", Weekday::Sat),
+    ("sunday", Weekday::Sun),
+];
+const NUMBERS: &[&str] = &[
+    "a", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten", "eleven",
+    "twelve",
+];
+// Days from the call date
+const RELATIVE_DAYS: &[(&str, i64)] = &[
+    ("day before yesterday", -2),
+    ("day after tomorrow", 2),
+    ("today", 0),
+    ("tonight", 0),
+    ("tomorrow", 1),
+    ("yesterday", -1),
+    ("сьогодні", 0),
+    ("сегодня", 0),
+    ("післязавтра", 2),
+    ("послезавтра", 2),
+    ("завтра", 1),
+    ("вчора", -1),
+    ("вчера", -1),
+];
+
+// Document number, phone, email or date found by the rules. `kinds` lists what a number can
+// be when the words around it don't say, the zero-shot model picks one of them.
+pub struct Candidate {
+    pub span: EntitySpan,
+    pub kinds: Vec<EntityType>,
+}
+
+impl Candidate {
+    fn typed(
+        entity_type: EntityType,
+        text: &str,
+        range: (usize, usize),
+        normalized: String,
+    ) -> Self {
+        Self {
+            span: span(entity_type, text, range, normalized, 1.0),
+            kinds: vec![entity_type],
+        }
+    }
+
+    pub fn is_ambiguous(&self) -> bool {
+        self.kinds.len() > 1
+    }
+}
+
+struct Rules {
+    mrz: Regex,
+    email: Regex,
+    spoken_email: Regex,
+    phone: Regex,
+    document: Regex,
+    reference: Regex,
+    iso_date: Regex,
+    numeric_date: Regex,
+    day_month: Regex,
+    month_day: Regex,
+    relative_day: Regex,
+    weekday: Regex,
+    offset: Regex,
+    next_period: Regex,
+}
+
+fn rules() -> &'static Rules {
+    static RULES: OnceLock<Rules> = OnceLock::new();
+    RULES.get_or_init(|| {
+        let month = r"(jan(?:uary)?|feb(?:ruary)?|mar(?:ch)?|apr(?:il)?|may|june?|july?|aug(?:ust)?|sep(?:t(?:ember)?)?|oct(?:ober)?|nov(?:ember)?|dec(?:ember)?)";
+        let weekday = WEEKDAYS
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join("|");
+        let number = format!(r"(\d{{1,3}}|{})", NUMBERS.join("|"));
+        let relative_days = RELATIVE_DAYS
+            .iter()
+            .map(|(words, _)| words.replace(' ', r"\s+"))
+            .collect::<Vec<_>>()
+            .join("|");
+        let regex = |pattern: &str| Regex::new(pattern).expect("invalid identifier rule");
+        Rules {
+            // Second line of a TD3 machine readable zone, the passport number and its check
+            // digit come first
+            mrz: regex(r"\b([A-Z0-9<]{9})(\d)[A-Z<]{3}\d{7}[MFX<]\d{7}[A-Z0-9<]{14}[\d<]\d\b"),
+            email: regex(r"(?i)\b[a-z0-9][a-z0-9._%+-]*@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b"),
+            // Dictated as "john dot smith at gmail dot com"
+            spoken_email: regex(
+                r"(?i)\b([a-z0-9]+(?:\s+(?:dot|underscore|dash)\s+[a-z0-9]+)*)\s+at\s+([a-z0-9-]+(?:\s+dot\s+[a-z0-9-]+)*\s+dot\s+(?:com|org|net|gov|edu|int|eu|ua|uk|de|fr|pl|info))\b",
+            ),
+            phone: regex(r"(?:\+|\b)\d(?:[\s().-]{0,2}\d){7,14}\b"),
+            // Series letters are capitalized, so words before a number aren't taken for them
+            document: regex(r"\b[A-ZА-ЯІЇЄҐ]{1,3}[\s-]?\d{6,9}\b|\b\d{6,9}\b"),
+            // Application ids mix letters, digits and separators freely, only taken after a
+            // word naming them
+            reference: regex(r"\b[A-Z0-9][A-Z0-9/-]{5,}[A-Z0-9]\b"),
+            iso_date: regex(r"\b(\d{4})-(\d{1,2})-(\d{1,2})\b"),
+            numeric_date: regex(r"\b(\d{1,2})[./](\d{1,2})[./](\d{4}|\d{2})\b"),
+            day_month: regex(&format!(
+                r"(?i)\b(\d{{1,2}})(?:st|nd|rd|th)?(?:\s+of)?\s+{}\.?(?:,?\s+(\d{{4}}))?\b",
+                month
+            )),
+            month_day: regex(&format!(
+                r"(?i)\b{}\.?\s+(\d{{1,2}})(?:st|nd|rd|th)?(?:,?\s+(\d{{4}}))?\b",
+                month
+            )),
+            relative_day: regex(&format!(r"(?i)\b({})\b", relative_days)),
+            weekday: regex(&format!(
+                r"(?i)\b(?:(next|this|coming|last|on)\s+)({})\b",
+                weekday
+            )),
+            offset: regex(&format!(
+                r"(?i)\b(?:in\s+{number}\s+(day|week|month)s?|{number}\s+(day|week|month)s?\s+ago)\b"
+            )),
+            next_period: regex(r"(?i)\b(next|last)\s+(week|month)\b"),
+        }
+    })
+}
+
+// Find the identifiers and dates in a text. Relative dates are resolved against `today`, the
+// day of the call.
+pub fn find_identifiers(text: &str, today: NaiveDate) -> Vec<Candidate> {
+    let rules = rules();
+    let mut candidates = Vec::new();
+
+    for captures in rules.mrz.captures_iter(text) {
+        let number = &captures[1];
+        if check_digit(number) == captures[2].parse::<u32>().ok() {
+            let normalized = number.trim_end_matches('<').to_string();
+            candidates.push(Candidate::typed(
+                EntityType::Passport,
+                text,
+                range(&captures, 0),
+                normalized,
+            ));
+        }
+    }
+    for found in rules.email.find_iter(text) {
+        candidates.push(Candidate::typed(
+            EntityType::Email,
+            text,
+            (found.start(), found.end()),
+            found.as_str().to_lowercase(),
+        ));
+    }
+    for captures in rules.spoken_email.captures_iter(text) {
+        let normalized = format!("{}@{}", spoken(&captures[1]), spoken(&captures[2]));
+        candidates.push(Candidate::typed(
+            EntityType::Email,
+            text,
+            range(&captures, 0),
+            normalized,
+        ));
+    }
+    dates(rules, text, today, &mut candidates);
+    for found in rules.phone.find_iter(text) {
+        if let Some(candidate) = phone(text, (found.start(), found.end())) {
+            candidates.push(candidate);
+        }
+    }
+    for found in rules.document.find_iter(text) {
+        candidates.extend(document(text, (found.start(), found.end())));
+    }
+    for found in rules.reference.find_iter(text) {
+        let digits = found.as_str().chars().filter(char::is_ascii_digit).count();
+        if digits >= 4 {
+            candidates
+                .extend(document(text, (found.start(), found.end())).filter(|c| !c.is_ambiguous()));
+        }
+    }
+
+    // Rules earlier above win overlaps, an MRZ or a date holds digits the number rules would
+    // also match
+    let mut kept: Vec<Candidate> = Vec::new();
+    for candidate in candidates {
+        let overlaps = kept.iter().any(|other| {
+            candidate.span.start_char < other.span.end_char
+                && other.span.start_char < candidate.span.end_char
+        });
+        if !overlaps {
+            kept.push(candidate);
+        }
+    }
+    kept.sort_by_key(|candidate| candidate.span.start_char);
+    kept
+}
+
+// Type the ambiguous candidates by asking the zero-shot model what the number in their
+// context is. None for the candidates it isn't sure about.
+pub fn classify(
+    contexts: &[String],
+    candidates: Vec<Candidate>,
+    zero_shot: &ZeroShotClassificationModel,
+) -> Result<Vec<Option<EntitySpan>>> {
+    const LABELS: &[(&str, EntityType)] = &[
+        ("passport number", EntityType::Passport),
+        ("visa application number", EntityType::VisaApplication),
+        ("phone number", EntityType::Phone),
+    ];
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+    let threshold = env_or("IDENTIFIER_MODEL_THRESHOLD", 0.5);
+    let inputs = contexts.iter().map(String::as_str).collect::<Vec<_>>();
+    let labels = LABELS.iter().map(|(label, _)| *label).collect::<Vec<_>>();
+    let predictions = zero_shot.predict_multilabel(
+        &inputs,
+        &labels,
+        Some(Box::new(|label: &str| {
+            format!("The number mentioned is a {}.", label)
+        })),
+        128,
+    )?;
+
+    Ok(candidates
+        .into_iter()
+        .zip(predictions)
+        .map(|(candidate, prediction)| {
+            let (entity_type, score) = prediction
+                .iter()
+                .filter_map(|label| {
+                    let (_, entity_type) = LABELS.iter().find(|(text, _)| *text == label.text)?;
+                    Some((*entity_type, label.score))
+                })
+                .filter(|(entity_type, score)| {
+                    candidate.kinds.contains(entity_type) && *score >= threshold
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1))?;
+            let normalized = match entity_type {
+                EntityType::Phone => e164(&candidate.span.text)?,
+                _ => document_number(&candidate.span.text),
+            };
+            Some(EntitySpan {
+                entity_type,
+                normalized,
+                score,
+                ..candidate.span
+            })
+        })
+        .collect())
+}
+
+// Text around a candidate the model sees
+pub fn context(text: &str, span: &EntitySpan) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let start = span.start_char.saturating_sub(2 * CONTEXT_CHARS);
+    let end = (span.end_char + CONTEXT_CHARS).min(chars.len());
+    chars[start..end].iter().collect()
+}
+
+fn phone(text: &str, range: (usize, usize)) -> Option<Candidate> {
+    let number = &text[range.0..range.1];
+    let international = number.starts_with('+') || number.starts_with("00");
+    let normalized = e164(number)?;
+    if international || mentions(text, range.0, PHONE_WORDS) {
+        return Some(Candidate::typed(EntityType::Phone, text, range, normalized));
+    }
+    // A bare number can be a phone or a document, the words before it decide
+    if mentions(text, range.0, PASSPORT_WORDS) || mentions(text, range.0, VISA_WORDS) {
+        return None;
+    }
+    let digits = number.chars().filter(char::is_ascii_digit).count();
+    let mut kinds = vec![EntityType::Phone];
+    if digits <= 9 {
+        kinds.push(EntityType::Passport);
+    }
+    kinds.push(EntityType::VisaApplication);
+    Some(Candidate {
+        span: span(EntityType::Phone, text, range, normalized, 1.0),
+        kinds,
+    })
+}
+
+fn document(text: &str, range: (usize, usize)) -> Option<Candidate> {
+    let normalized = document_number(&text[range.0..range.1]);
+    let has_letters = normalized.chars().any(char::is_alphabetic);
+    let kinds = if mentions(text, range.0, PASSPORT_WORDS) {
+        vec![EntityType::Passport]
+    } else if mentions(text, range.0, VISA_WORDS) {
+        vec![EntityType::VisaApplication]
+    } else if has_letters {
+        vec![EntityType::Passport, EntityType::VisaApplication]
+    } else {
+        // Digits alone without a word naming them are left to the phone rule
+        return None;
+    };
+    Some(Candidate {
+        span: span(kinds[0], text, range, normalized, 1.0),
+        kinds,
+    })
+}
+
+fn dates(rules: &Rules, text: &str, today: NaiveDate, candidates: &mut Vec<Candidate>) {
+    let mut push = |captures: &Captures, date: Option<NaiveDate>| {
+        if let Some(date) = date {
+            candidates.push(Candidate::typed(
+                EntityType::Date,
+                text,
+                range(captures, 0),
+                date.format("%Y-%m-%d").to_string(),
+            ));
+        }
+    };
+
+    for captures in rules.iso_date.captures_iter(text) {
+        let date = ymd(
+            number(&captures[1]),
+            number(&captures[2]),
+            number(&captures[3]),
+        );
+        push(&captures, date);
+    }
+    for captures in rules.numeric_date.captures_iter(text) {
+        let (first, second) = (number(&captures[1]), number(&captures[2]));
+        let year = match number(&captures[3]) {
+            year if year < 100 => 2000 + year,
+            year => year,
+        };
+        // Day first unless that can't be, as in 03/25/2025
+        let date = if second > 12 && first <= 12 {
+            ymd(year, first, second)
+        } else {
+            ymd(year, second, first)
+        };
+        push(&captures, date);
+    }
+    for captures in rules.day_month.captures_iter(text) {
+        let date = month_date(
+            today,
+            month(&captures[2]),
+            number(&captures[1]),
+            captures.get(3),
+        );
+        push(&captures, date);
+    }
+    for captures in rules.month_day.captures_iter(text) {
+        let date = month_date(
+            today,
+            month(&captures[1]),
+            number(&captures[2]),
+            captures.get(3),
+        );
+        push(&captures, date);
+    }
+    for captures in rules.relative_day.captures_iter(text) {
+        let words = captures[1]
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        let days = RELATIVE_DAYS
+            .iter()
+            .find(|(relative, _)| *relative == words)
+            .map(|(_, days)| *days);
+        push(&captures, days.and_then(|days| add_days(today, days)));
+    }
+    for captures in rules.weekday.captures_iter(text) {
+        let weekday = WEEKDAYS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&captures[2]))
+            .map(|(_, weekday)| *weekday);
+        let date = weekday.and_then(|weekday| {
+            let ahead =
+                (weekday.num_days_from_monday() + 7 - today.weekday().num_days_from_monday()) % 7;
+            match captures[1].to_lowercase().as_str() {
+                // The last one before the call
+                "last" => add_days(today, -(if ahead == 0 { 7 } else { 7 - ahead as i64 })),
+                // "next Tuesday" is the first Tuesday after the call, "this Tuesday" may be
+                // the day of the call itself
+                "next" => add_days(today, if ahead == 0 { 7 } else { ahead as i64 }),
+                _ => add_days(today, ahead as i64),
+            }
+        });
+        push(&captures, date);
+    }
+    for captures in rules.offset.captures_iter(text) {
+        let (amount, unit, sign) = match (captures.get(1), captures.get(3)) {
+            (Some(amount), _) => (amount.as_str(), &captures[2], 1),
+            (None, Some(amount)) => (amount.as_str(), &captures[4], -1),
+            _ => continue,
+        };
+        let amount = NUMBERS
+            .iter()
+            .position(|word| word.eq_ignore_ascii_case(amount))
+            .map(|position| position.max(1) as i64)
+            .or_else(|| amount.parse().ok());
+        let date = amount.and_then(|amount| match unit.to_lowercase().as_str() {
+            "day" => add_days(today, sign * amount),
+            "week" => add_days(today, sign * amount * 7),
+            _ => add_months(today, sign * amount),
+        });
+        push(&captures, date);
+    }
+    for captures in rules.next_period.captures_iter(text) {
+        let sign = if captures[1].eq_ignore_ascii_case("next") {
+            1
+        } else {
+            -1
+        };
+        // The first day of the week or month
+        let date = if captures[2].eq_ignore_ascii_case("week") {
+            add_days(
+                today,
+                sign * 7 - today.weekday().num_days_from_monday() as i64,
+            )
+        } else {
+            add_months(today.with_day(1).unwrap_or(today), sign)
+        };
+        push(&captures, date);
+    }
+}
+
+// A day and month without a year is the closest such date to the call
+fn month_date(
+    today: NaiveDate,
+    month: Option<u32>,
+    day: i32,
+    year: Option<regex::Match>,
+) -> Option<NaiveDate> {
+    let month = month? as i32;
+    if let Some(year) = year {
+        return ymd(number(year.as_str()), month, day);
+    }
+    (today.year() - 1..=today.year() + 1)
+        .filter_map(|year| ymd(year, month, day))
+        .min_by_key(|date| (*date - today).num_days().abs())
+}
+
+fn month(name: &str) -> Option<u32> {
+    let name = name.to_lowercase();
+    MONTHS
+        .iter()
+        .position(|month| name.starts_with(month))
+        .map(|position| position as u32 + 1)
+}
+
+fn ymd(year: i32, month: i32, day: i32) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(year, u32::try_from(month).ok()?, u32::try_from(day).ok()?)
+}
+
+fn number(digits: &str) -> i32 {
+    digits.parse().unwrap_or(0)
+}
+
+fn add_days(date: NaiveDate, days: i64) -> Option<NaiveDate> {
+    if days >= 0 {
+        date.checked_add_days(Days::new(days as u64))
+    } else {
+        date.checked_sub_days(Days::new(days.unsigned_abs()))
+    }
+}
+
+fn add_months(date: NaiveDate, months: i64) -> Option<NaiveDate> {
+    let magnitude = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+    if months >= 0 {
+        date.checked_add_months(magnitude)
+    } else {
+        date.checked_sub_months(magnitude)
+    }
+}
+
+// Phone number in E.164 form. Numbers dialed without a country code get PHONE_COUNTRY_CODE,
+// a leading trunk 0 is dropped.
+pub fn e164(number: &str) -> Option<String> {
+    let digits = number
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    let digits = if number.trim_start().starts_with('+') {
+        digits
+    } else if let Some(international) = digits.strip_prefix("00") {
+        international.to_string()
+    } else if let Some(national) = digits.strip_prefix('0') {
+        format!(
+            "{}{}",
+            env_or("PHONE_COUNTRY_CODE", "380".to_string()),
+            national
+        )
+    } else if digits.len() >= 11 {
+        digits
+    } else {
+        return None;
+    };
+    let valid = (8..=15).contains(&digits.len()) && !digits.starts_with('0');
+    valid.then(|| format!("+{}", digits))
+}
+
+// Uppercase without the spaces and dashes the number was dictated with
+fn document_number(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+// ICAO 9303 check digit, weights 7, 3, 1 over digits, letters from 10 and fillers as 0
+fn check_digit(field: &str) -> Option<u32> {
+    let mut sum = 0;
+    for (c, weight) in field.chars().zip([7, 3, 1].into_iter().cycle()) {
+        let value = match c {
+            '0'..='9' => c.to_digit(10)?,
+            'A'..='Z' => c as u32 - 'A' as u32 + 10,
+            '<' => 0,
+            _ => return None,
+        };
+        sum += value * weight;
+    }
+    Some(sum % 10)
+}
+
+// Whether a word naming the kind of number comes shortly before it
+fn mentions(text: &str, start: usize, words: &[&str]) -> bool {
+    let before = text[..start]
+        .chars()
+        .rev()
+        .take(CONTEXT_CHARS)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect::<String>()
+        .to_lowercase();
+    words.iter().any(|word| before.contains(word))
+}
+
+fn spoken(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| match word.to_lowercase().as_str() {
+            "dot" => ".".to_string(),
+            "underscore" => "_".to_string(),
+            "dash" => "-".to_string(),
+            word => word.to_string(),
+        })
+        .collect()
+}
+
+fn range(captures: &Captures, group: usize) -> (usize, usize) {
+    captures
+        .get(group)
+        .map_or((0, 0), |found| (found.start(), found.end()))
+}
+
+// Span of a byte range of the text, with character offsets like the NER spans
+fn span(
+    entity_type: EntityType,
+    text: &str,
+    (start, end): (usize, usize),
+    normalized: String,
+    score: f64,
+) -> EntitySpan {
+    let start_char = text[..start].chars().count();
+    EntitySpan {
+        entity_type,
+        text: text[start..end].to_string(),
+        normalized,
+        start_char,
+        end_char: start_char + text[start..end].chars().count(),
+        score,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A Tuesday
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, 20).unwrap()
+    }
+
+    fn found(text: &str) -> Vec<(EntityType, String)> {
+        find_identifiers(text, today())
+            .into_iter()
+            .map(|candidate| (candidate.span.entity_type, candidate.span.normalized))
+            .collect()
+    }
+
+    fn date(text: &str) -> String {
+        match found(text).as_slice() {
+            [(EntityType::Date, date)] => date.clone(),
+            other => panic!("expected one date in {:?}, found {:?}", text, other),
+        }
+    }
+
+    #[test]
+    fn test_check_digit() {
+        // The ICAO 9303 specimen passport
+        assert_eq!(check_digit("L898902C3"), Some(6));
+        assert_eq!(check_digit("740812"), Some(2));
+        assert_eq!(check_digit("AB<<<"), Some((10 * 7 + 11 * 3) % 10));
+        assert_eq!(check_digit("l898902c3"), None);
+    }
+
+    #[test]
+    fn test_mrz() {
+        let valid = "L898902C36UTO7408122F1204159ZE184226B<<<<<10";
+        assert_eq!(
+            found(valid),
+            vec![(EntityType::Passport, "L898902C3".to_string())]
+        );
+        // The passport number doesn't match its check digit
+        let invalid = "L898902C35UTO7408122F1204159ZE184226B<<<<<10";
+        assert_eq!(found(invalid), vec![]);
+    }
+
+    #[test]
+    fn test_e164() {
+        // A trunk 0 is replaced with the country code
+        assert_eq!(e164("067 123 45 67").as_deref(), Some("+380671234567"));
+        assert_eq!(e164("0044 20 7946 0958").as_deref(), Some("+442079460958"));
+        assert_eq!(e164("+44 20 7946 0958").as_deref(), Some("+442079460958"));
+        assert_eq!(e164("380671234567").as_deref(), Some("+380671234567"));
+        // Too short to have a country code, or too long for E.164
+        assert_eq!(e164("1234567"), None);
+        assert_eq!(e164("+1 234 567"), None);
+        assert_eq!(e164("+1234567890123456"), None);
+        assert_eq!(e164("000 1234 5678"), None);
+    }
+
+    #[test]
+    fn test_numeric_dates() {
+        assert_eq!(date("on 05/03/2025"), "2025-03-05");
+        assert_eq!(date("on 05.03.25"), "2025-03-05");
+        // Month first when the day can't be the second number
+        assert_eq!(date("on 03/25/2025"), "2025-03-25");
+        assert_eq!(date("on 2025-03-05"), "2025-03-05");
+    }
+
+    #[test]
+    fn test_month_dates() {
+        assert_eq!(date("on the 5th of March 2025"), "2025-03-05");
+        assert_eq!(date("on March 5, 2025"), "2025-03-05");
+        // Without a year, the closest to the call
+        assert_eq!(date("on 2 January"), "2027-01-02");
+        assert_eq!(date("on Sept 30"), "2026-09-30");
+    }
+
+    #[test]
+    fn test_relative_dates() {
+        assert_eq!(date("tomorrow"), "2026-10-21");
+        assert_eq!(date("the day before yesterday"), "2026-10-18");
+        assert_eq!(date("післязавтра"), "2026-10-22");
+        // The call is on a Tuesday
+        assert_eq!(date("next Tuesday"), "2026-10-27");
+        assert_eq!(date("this Tuesday"), "2026-10-20");
+        assert_eq!(date("last Tuesday"), "2026-10-13");
+        assert_eq!(date("on Friday"), "2026-10-23");
+        assert_eq!(date("last Friday"), "2026-10-16");
+        assert_eq!(date("in a week"), "2026-10-27");
+        assert_eq!(date("three days ago"), "2026-10-17");
+        assert_eq!(date("in 2 months"), "2026-12-20");
+        // Periods start on a Monday or the first of the month
+        assert_eq!(date("last week"), "2026-10-12");
+        assert_eq!(date("next week"), "2026-10-26");
+        assert_eq!(date("next month"), "2026-11-01");
+    }
+
+    #[test]
+    fn test_overlaps() {
+        // The digits of a date aren't also taken for a phone number
+        assert_eq!(date("2025-03-05"), "2025-03-05");
+        assert_eq!(
+            found("call me on 067 123 45 67 tomorrow"),
+            vec![
+                (EntityType::Phone, "+380671234567".to_string()),
+                (EntityType::Date, "2026-10-21".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_phone_or_document_by_context() {
+        assert_eq!(
+            found("my passport number is 123456789"),
+            vec![(EntityType::Passport, "123456789".to_string())]
+        );
+        assert_eq!(
+            found("my passport is FE 123456"),
+            vec![(EntityType::Passport, "FE123456".to_string())]
+        );
+        assert_eq!(
+            found("the visa application is 0671234567"),
+            vec![(EntityType::VisaApplication, "0671234567".to_string())]
+        );
+        assert_eq!(
+            found("my mobile is 0671234567"),
+            vec![(EntityType::Phone, "+380671234567".to_string())]
+        );
+        // Nothing says what a bare number is, the model decides
+        let candidates = find_identifiers("it is 0671234567", today());
+        assert_eq!(candidates.len(), 1);
+        assert!(candidates[0].is_ambiguous());
+        assert_eq!(
+            candidates[0].kinds,
+            vec![EntityType::Phone, EntityType::VisaApplication]
+        );
+    }
+
+    #[test]
+    fn test_emails() {
+        assert_eq!(
+            found("write to Olena.K@Example.com"),
+            vec![(EntityType::Email, "olena.k@example.com".to_string())]
+        );
+        assert_eq!(
+            found("it's olena dot k at gmail dot com"),
+            vec![(EntityType::Email, "olena.k@gmail.com".to_string())]
+        );
+    }
+}
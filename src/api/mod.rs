@@ -1,7 +1,23 @@
-mod call;
+mod analytics;
+mod audio;
+pub mod call;
 mod category;
-mod models;
+mod diarization;
+pub mod download;
+pub mod entities;
+mod entity;
+pub mod events;
+pub mod gazetteer;
+pub mod identifiers;
+pub mod models;
+pub mod registry;
+pub mod reindex;
+mod stream;
+mod transcript;
 mod utils;
+mod vad;
+pub mod webhooks;
+pub mod worker;
 
 use actix_web::web::{self, service};
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -11,7 +27,29 @@ pub fn config(cfg: &mut web::ServiceConfig) {
             .service(category::create_category)
             .service(category::update_category)
             .service(category::delete_category) //.service(call::get_call)
+            // Uploads are matched on their content type before the JSON variant
+            .service(call::upload_call)
+            .service(call::upload_call_raw)
             .service(call::create_call)
-            .service(call::get_call),
+            .service(call::get_call)
+            .service(transcript::get_transcript)
+            .service(events::call_events)
+            .service(events::events)
+            .service(stream::stream)
+            .service(reindex::get_reindex)
+            .service(reindex::cancel_reindex)
+            .service(webhooks::get_webhooks)
+            .service(webhooks::create_webhook)
+            .service(webhooks::get_webhook)
+            .service(webhooks::update_webhook)
+            .service(webhooks::delete_webhook)
+            .service(webhooks::get_deliveries)
+            .service(webhooks::redeliver)
+            .service(webhooks::ping_webhook)
+            .service(entity::get_entity)
+            .service(entity::get_entity_calls)
+            .service(entity::merge_entities)
+            .service(entity::split_entity)
+            .service(analytics::get_locations),
     );
 }
@@ -1,5 +1,7 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
+use sqlx::types::Json;
 use uuid::Uuid;
 
 // Model for category data
@@ -8,18 +10,57 @@ pub struct Category {
     pub id: i32,
     pub title: String,
     pub points: Option<Vec<String>>,
+    pub extraction_schema: Option<Json<Vec<ExtractionField>>>,
 }
 
-#[derive(Deserialize, Serialize)]
+// Field of a category's extraction schema, answered by the question-answering model over
+// the transcripts of its calls
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ExtractionField {
+    // Key of the answer in the call's `extraction` document
+    pub name: String,
+    pub question: String,
+}
+
+impl Category {
+    // Labels fed to zero-shot classification: the title followed by its points
+    pub fn candidate_labels(&self) -> Vec<String> {
+        std::iter::once(self.title.clone())
+            .chain(self.points.clone().unwrap_or_default())
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct CreateCategory {
     pub title: String,
     pub points: Option<Vec<String>>,
+    pub extraction_schema: Option<Json<Vec<ExtractionField>>>,
 }
 
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
 pub struct UpdateCategory {
     pub title: Option<String>,
     pub points: Option<Vec<String>>,
+    pub extraction_schema: Option<Json<Vec<ExtractionField>>>,
+}
+
+#[derive(Deserialize)]
+pub struct ReindexOptions {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// Category returned by create and update, with the reindex job matching calls against it
+#[derive(Serialize)]
+pub struct CategoryChange {
+    // Not set when a category creation is only previewed
+    pub id: Option<i32>,
+    pub title: String,
+    pub points: Option<Vec<String>>,
+    pub extraction_schema: Option<Json<Vec<ExtractionField>>>,
+    pub reindex_job_id: Uuid,
+    pub dry_run: bool,
 }
 
 #[derive(Serialize, FromRow)]
@@ -31,16 +72,472 @@ pub struct CallId {
 pub struct CallReindex {
     pub id: Uuid,
     pub text: String,
-    pub categories: Option<Vec<String>>,
+    pub translation: Option<String>,
+    pub language: Option<String>,
+    // Whether the call is currently assigned the category being reindexed
+    pub has_category: bool,
+    // Whether the call's extraction holds answers to the category's schema
+    pub has_extraction: bool,
+}
+
+// What assigned a category to a call, stored in `call_category.source`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CategorySource {
+    Pipeline,
+    Reindex,
+    // Carried over from the old `call.categories` column by the call_category migration
+    Migration,
+}
+
+impl CategorySource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pipeline => "pipeline",
+            Self::Reindex => "reindex",
+            Self::Migration => "migration",
+        }
+    }
+}
+
+// Category matched by zero-shot classification, with the label that scored highest
+pub struct CategoryMatch {
+    pub category_id: i32,
+    pub score: f64,
+    pub matched_label: String,
+}
+
+// Category assigned to a call, as returned by the call API
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct CallCategory {
+    pub id: i32,
+    pub title: String,
+    // Zero-shot score of the matched label, missing for assignments migrated from TEXT[]
+    pub score: Option<f64>,
+    // Title or point of the category that triggered the match
+    pub matched_label: Option<String>,
+    pub source: String,
+    pub model_version: Option<String>,
+}
+
+// Processing stage of a call, stored in the `status` column
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CallStatus {
+    // Audio is still arriving over /api/stream
+    Streaming,
+    Queued,
+    Downloading,
+    Decoding,
+    Transcribing,
+    Diarizing,
+    Translating,
+    Analyzing,
+    Done,
+    Failed,
+}
+
+impl CallStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Streaming => "streaming",
+            Self::Queued => "queued",
+            Self::Downloading => "downloading",
+            Self::Decoding => "decoding",
+            Self::Transcribing => "transcribing",
+            Self::Diarizing => "diarizing",
+            Self::Translating => "translating",
+            Self::Analyzing => "analyzing",
+            Self::Done => "done",
+            Self::Failed => "failed",
+        }
+    }
+
+    // Whether the pipeline has stopped working on the call
+    pub fn is_finished(status: &str) -> bool {
+        status == Self::Done.as_str() || status == Self::Failed.as_str()
+    }
 }
 
 // Model for call data
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
 pub struct Call {
     pub id: Uuid,
+    pub status: String,
+    pub error: Option<String>,
     pub name: Option<String>,
     pub location: Option<String>,
     pub emotional_tone: Option<String>,
+    pub text: Option<String>,
+    // English translation, only for calls in other languages
+    pub translation: Option<String>,
+    // Original audio, known once it was decoded
+    pub codec: Option<String>,
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+    pub duration_secs: Option<f64>,
+    // Chunks of speech transcribed (or translated) so far by the current stage
+    pub chunks_done: Option<i32>,
+    pub chunks_total: Option<i32>,
+    // Whisper language code, the probability is missing when the language was requested
+    pub language: Option<String>,
+    pub language_probability: Option<f64>,
+    // How the speakers were told apart, see `api::diarization`
+    pub diarization: Option<String>,
+    // Answers to the extraction schemas of the call's categories, by field name
+    pub extraction: Option<Json<serde_json::Value>>,
+    #[sqlx(skip)]
+    pub categories: Vec<CallCategory>,
+    #[sqlx(skip)]
+    pub speakers: Vec<CallSpeaker>,
+    // Only returned with `?include=segments`
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<CallSegment>>,
+    // Only returned with `?include=entities`
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entities: Option<Vec<CallEntity>>,
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct CallSegment {
+    pub id: i32,
+    pub start_ms: i32,
+    pub end_ms: i32,
+    pub speaker: Option<i32>,
+    pub text: String,
+    pub avg_probability: Option<f64>,
+}
+
+// Kind of a named entity. The first four are the suffixes of the NER model labels, the rest
+// are found by `api::identifiers`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EntityType {
+    Person,
+    Location,
+    Organization,
+    Misc,
+    Passport,
+    VisaApplication,
+    Phone,
+    Email,
+    Date,
+}
+
+impl EntityType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Person => "PER",
+            Self::Location => "LOC",
+            Self::Organization => "ORG",
+            Self::Misc => "MISC",
+            Self::Passport => "PASSPORT",
+            Self::VisaApplication => "VISA",
+            Self::Phone => "PHONE",
+            Self::Email => "EMAIL",
+            Self::Date => "DATE",
+        }
+    }
+
+    pub fn parse(entity_type: &str) -> Option<Self> {
+        match entity_type {
+            "PER" => Some(Self::Person),
+            "LOC" => Some(Self::Location),
+            "ORG" => Some(Self::Organization),
+            "MISC" => Some(Self::Misc),
+            "PASSPORT" => Some(Self::Passport),
+            "VISA" => Some(Self::VisaApplication),
+            "PHONE" => Some(Self::Phone),
+            "EMAIL" => Some(Self::Email),
+            "DATE" => Some(Self::Date),
+            _ => None,
+        }
+    }
+}
+
+// Mention of a named entity in a call
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct CallEntity {
+    pub id: i32,
+    pub segment_id: Option<i32>,
+    pub entity_type: String,
+    // As it appears in the transcript
     pub text: String,
-    pub categories: Option<Vec<String>>,
+    // Lowercase, without surrounding punctuation or a possessive. Identifiers are in canonical
+    // form: E.164 phones, uppercase document numbers and ISO 8601 dates.
+    pub normalized: String,
+    // Character offsets into the segment text, end exclusive
+    pub start_char: i32,
+    pub end_char: i32,
+    // The offsets index the English translation of the segment
+    pub translation: bool,
+    pub score: f64,
+    // Registry entity the mention is linked to, only for PER, ORG and LOC
+    pub entity_id: Option<i32>,
+    // Gazetteer place a location resolved to
+    pub geoname_id: Option<i64>,
+    pub place: Option<String>,
+    pub country_code: Option<String>,
+    pub admin_region: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+// Person, organization or location of the registry, with the number of its mentions
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Entity {
+    pub id: i32,
+    pub entity_type: String,
+    pub name: String,
+    pub mentions: i64,
+    pub calls: i64,
+    // Matching keys of the names it was mentioned by
+    pub aliases: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Call mentioning a registry entity
+#[derive(Serialize, sqlx::FromRow)]
+pub struct EntityCall {
+    pub id: Uuid,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub mentions: i64,
+    // Distinct texts the entity was mentioned by in the call
+    pub texts: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MergeEntities {
+    // Entities folded into the one in the path, they are deleted afterwards
+    pub entity_ids: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct SplitEntity {
+    // Mentions moved to the new entity
+    pub mention_ids: Vec<i32>,
+    // Name of the new entity, the text of the first mention by default
+    pub name: Option<String>,
+}
+
+// Sentiment and entities of what one speaker said
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct CallSpeaker {
+    pub speaker: i32,
+    pub talk_time_ms: i32,
+    pub emotional_tone: Option<String>,
+    pub name: Option<String>,
+    pub location: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LocationsOptions {
+    // Only calls created in [from, to)
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // ISO 3166-1 alpha-2 code of the country to keep
+    pub country: Option<String>,
+}
+
+// Gazetteer place with the number of times calls mentioned it
+#[derive(sqlx::FromRow)]
+pub struct LocationCount {
+    pub geoname_id: i64,
+    pub place: String,
+    pub country_code: String,
+    pub admin_region: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub mentions: i64,
+    pub calls: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CallOptions {
+    // Comma separated optional parts of the call: `segments`, `entities`
+    #[serde(default)]
+    pub include: String,
+}
+
+#[derive(Deserialize)]
+pub struct TranscriptOptions {
+    // Label cues with their speaker when it is known
+    #[serde(default = "default_true")]
+    pub speakers: bool,
+    // Wrap names and locations in WebVTT cue classes
+    #[serde(default)]
+    pub highlight: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl CallOptions {
+    pub fn includes(&self, part: &str) -> bool {
+        self.include
+            .split(',')
+            .any(|include| include.trim() == part)
+    }
+}
+
+// Processing stage of a category reindex, stored in the `reindex` table
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReindexStatus {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+impl ReindexStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Cancelled => "cancelled",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub fn is_finished(status: &str) -> bool {
+        status == Self::Done.as_str()
+            || status == Self::Cancelled.as_str()
+            || status == Self::Failed.as_str()
+    }
+}
+
+// Progress of a reindex job
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Reindex {
+    pub job_id: Uuid,
+    pub category_id: Option<i32>,
+    pub dry_run: bool,
+    pub status: String,
+    pub error: Option<String>,
+    pub total: i32,
+    pub processed: i32,
+    pub changed: i32,
+    // Calls that gain or lose the category (or would, in a dry run)
+    pub gained: Vec<Uuid>,
+    pub lost: Vec<Uuid>,
+    pub eta_seconds: Option<f64>,
+}
+
+// Event a webhook can subscribe to, sent as the `type` of the payload
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookEvent {
+    // A call went through the pipeline, `data` is the call as returned by GET /api/call/{id}
+    CallDone,
+    // A call failed for good
+    CallFailed,
+    // A category reindex assigned a category to a call or removed it
+    CallCategoriesChanged,
+    // Sent by POST /api/webhooks/{id}/ping, whatever the filter
+    Ping,
+}
+
+impl WebhookEvent {
+    pub const ALL: [Self; 4] = [
+        Self::CallDone,
+        Self::CallFailed,
+        Self::CallCategoriesChanged,
+        Self::Ping,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CallDone => "call.done",
+            Self::CallFailed => "call.failed",
+            Self::CallCategoriesChanged => "call.categories_changed",
+            Self::Ping => "ping",
+        }
+    }
+
+    pub fn parse(event_type: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|event| event.as_str() == event_type)
+    }
+}
+
+// Model for webhook data
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    // Only returned when the webhook is created
+    #[serde(skip)]
+    pub secret: String,
+    // Event types sent to the webhook, empty for all of them
+    pub event_types: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+// Webhook returned by create, the only time the signing secret is shown
+#[derive(Serialize)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateWebhook {
+    pub url: String,
+    // Generated when missing
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub event_types: Vec<String>,
+    pub active: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateWebhook {
+    pub url: Option<String>,
+    pub secret: Option<String>,
+    pub event_types: Option<Vec<String>>,
+    pub active: Option<bool>,
+}
+
+// Lifecycle of a row in the `webhook_delivery` table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    // Not sent yet or waiting for a retry
+    Pending,
+    Delivered,
+    // Gave up after the last attempt
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+// One event sent to a webhook, with the outcome of the last attempt
+#[derive(Serialize, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: i32,
+    // Shared by the redeliveries of an event, receivers can use it to drop duplicates
+    pub event_id: Uuid,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
 }
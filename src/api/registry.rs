@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use super::models::EntityType;
+use crate::config::env_or;
+
+// Key of the advisory lock guarding the registry. Merges and splits take it alone, storing a
+// call shares it and locks the keys it links instead.
+const REGISTRY_LOCK: i64 = 0x656e_7469_7479;
+// First half of the two-part advisory locks on keys, the second half is the hashed prefix
+const KEY_LOCK: i32 = 0x6b65_7973;
+
+// Titles dropped from the front of person names
+const TITLES: &[&str] = &[
+    "mr",
+    "mrs",
+    "ms",
+    "miss",
+    "mister",
+    "dr",
+    "prof",
+    "sir",
+    "pan",
+    "pani",
+    "пан",
+    "пані",
+    "господин",
+    "госпожа",
+    "гражданин",
+];
+
+// Minimum similarity of keys for a mention to join an existing entity
+fn match_threshold() -> f64 {
+    env_or("ENTITY_MATCH_THRESHOLD", 0.85)
+}
+
+// Only these types are linked, MISC mentions are too vague to be the same thing across calls
+fn is_linked(entity_type: EntityType) -> bool {
+    matches!(
+        entity_type,
+        EntityType::Person | EntityType::Organization | EntityType::Location
+    )
+}
+
+// Take the registry lock alone until the transaction ends
+pub async fn lock(conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(REGISTRY_LOCK)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// Share the registry lock and lock the keys about to be linked until the transaction ends, so
+// two calls mentioning a new person don't both create it. A key can only match keys of its
+// type starting with the same letter, those are locked together. The locks are taken in the
+// same order by every transaction, so they can't deadlock.
+async fn lock_keys(
+    conn: &mut PgConnection,
+    keys: &[(EntityType, String)],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock_shared($1)")
+        .bind(REGISTRY_LOCK)
+        .execute(&mut *conn)
+        .await?;
+    let prefixes = keys
+        .iter()
+        .filter_map(|(entity_type, key)| {
+            let first = key.chars().next()?;
+            Some(format!("{}:{}", entity_type.as_str(), first))
+        })
+        .collect::<Vec<_>>();
+    let hashes = sqlx::query_scalar::<_, i32>(
+        "SELECT DISTINCT hashtext(prefix) FROM UNNEST($1::text[]) AS prefix ORDER BY 1",
+    )
+    .bind(&prefixes)
+    .fetch_all(&mut *conn)
+    .await?;
+    for hash in hashes {
+        sqlx::query("SELECT pg_advisory_xact_lock($1, $2)")
+            .bind(KEY_LOCK)
+            .bind(hash)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+// Link the PER, ORG and LOC mentions of a call to registry entities, creating the ones never
+// seen before. Runs in the transaction storing the mentions.
+pub async fn link_entities(conn: &mut PgConnection, call_id: Uuid) -> Result<(), sqlx::Error> {
+    let mentions = sqlx::query_as::<_, (i32, String, String, String)>(
+        "SELECT id, entity_type, text, normalized FROM call_entity WHERE call_id = $1 ORDER BY id",
+    )
+    .bind(call_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mentions = mentions
+        .into_iter()
+        .filter_map(|(id, entity_type, text, normalized)| {
+            let entity_type = EntityType::parse(&entity_type).filter(|t| is_linked(*t))?;
+            let key = entity_key(entity_type, &normalized);
+            (!key.is_empty()).then_some((id, entity_type, key, text))
+        })
+        .collect::<Vec<_>>();
+    if mentions.is_empty() {
+        return Ok(());
+    }
+
+    let keys = mentions
+        .iter()
+        .map(|(_, entity_type, key, _)| (*entity_type, key.clone()))
+        .collect::<Vec<_>>();
+    lock_keys(conn, &keys).await?;
+    let threshold = match_threshold();
+    let mut resolved = HashMap::new();
+    for (id, entity_type, key, text) in mentions {
+        let entity_id = match resolved.get(&(entity_type, key.clone())) {
+            Some(entity_id) => *entity_id,
+            None => {
+                let entity_id = resolve(conn, entity_type, &key, &text, threshold).await?;
+                resolved.insert((entity_type, key), entity_id);
+                entity_id
+            }
+        };
+        sqlx::query("UPDATE call_entity SET entity_id = $1 WHERE id = $2")
+            .bind(entity_id)
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+// Entity with the key, else the closest one above the threshold, which learns the key as an
+// alias, else a new entity named after the mention
+async fn resolve(
+    conn: &mut PgConnection,
+    entity_type: EntityType,
+    key: &str,
+    text: &str,
+    threshold: f64,
+) -> Result<i32, sqlx::Error> {
+    let exact = sqlx::query_scalar::<_, i32>(
+        "SELECT entity_id FROM entity_alias WHERE entity_type = $1 AND key = $2",
+    )
+    .bind(entity_type.as_str())
+    .bind(key)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(entity_id) = exact {
+        return Ok(entity_id);
+    }
+
+    // Keys further apart in length can't reach the threshold. Sharing the first letter keeps
+    // the candidates few, misspellings rarely start with the wrong one. Both are served by
+    // `entity_alias_prefix_idx`.
+    let length = key.chars().count();
+    let max_difference = (length as f64 * (1.0 - threshold)).floor() as i32;
+    let candidates = sqlx::query_as::<_, (String, i32)>(
+        r#"
+    SELECT key, entity_id FROM entity_alias
+    WHERE entity_type = $1 AND LEFT(key, 1) = LEFT($2, 1)
+        AND LENGTH(key) BETWEEN LENGTH($2) - $3 AND LENGTH($2) + $3
+    "#,
+    )
+    .bind(entity_type.as_str())
+    .bind(key)
+    .bind(max_difference)
+    .fetch_all(&mut *conn)
+    .await?;
+    let closest = candidates
+        .into_iter()
+        .map(|(alias, entity_id)| (similarity(key, &alias), entity_id))
+        .filter(|(score, _)| *score >= threshold)
+        .max_by(|a, b| a.0.total_cmp(&b.0));
+
+    let entity_id = match closest {
+        Some((_, entity_id)) => entity_id,
+        None => {
+            sqlx::query_scalar::<_, i32>(
+                "INSERT INTO entity (entity_type, name) VALUES ($1, $2) RETURNING id",
+            )
+            .bind(entity_type.as_str())
+            .bind(text.trim())
+            .fetch_one(&mut *conn)
+            .await?
+        }
+    };
+    sqlx::query("INSERT INTO entity_alias (entity_type, key, entity_id) VALUES ($1, $2, $3)")
+        .bind(entity_type.as_str())
+        .bind(key)
+        .bind(entity_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(entity_id)
+}
+
+// Matching key of a normalized mention. Cyrillic is transliterated and spelling variants of
+// the transliterations are folded, so "Коваленко", "Kovalenko" and "Kowalenko" share a key.
+pub fn entity_key(entity_type: EntityType, normalized: &str) -> String {
+    let mut words = normalized.split_whitespace().collect::<Vec<_>>();
+    while words.len() > 1 {
+        let first = words[0].trim_end_matches('.');
+        if first == "the" || (entity_type == EntityType::Person && TITLES.contains(&first)) {
+            words.remove(0);
+        } else {
+            break;
+        }
+    }
+
+    let words = words
+        .into_iter()
+        .map(|word| fold(&latin(word)))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    words.join(" ")
+}
+
+// Transliterate Cyrillic following the Ukrainian national system and drop the diacritics of
+// Latin letters
+fn latin(word: &str) -> String {
+    let mut latin = String::with_capacity(word.len());
+    for c in word.chars().flat_map(char::to_lowercase) {
+        let replacement = match c {
+            'а' => "a",
+            'б' => "b",
+            'в' => "v",
+            'г' => "h",
+            'ґ' => "g",
+            'д' => "d",
+            'е' => "e",
+            'є' => "ye",
+            'ж' => "zh",
+            'з' => "z",
+            'и' => "y",
+            'і' => "i",
+            'ї' => "yi",
+            'й' => "y",
+            'к' => "k",
+            'л' => "l",
+            'м' => "m",
+            'н' => "n",
+            'о' => "o",
+            'п' => "p",
+            'р' => "r",
+            'с' => "s",
+            'т' => "t",
+            'у' => "u",
+            'ф' => "f",
+            'х' => "kh",
+            'ц' => "ts",
+            'ч' => "ch",
+            'ш' => "sh",
+            'щ' => "shch",
+            'ь' | 'ъ' => "",
+            'ю' => "yu",
+            'я' => "ya",
+            'ы' => "y",
+            'э' => "e",
+            'ё' => "yo",
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ą' | 'ă' => "a",
+            'ç' | 'č' | 'ć' => "c",
+            'ď' | 'đ' => "d",
+            'è' | 'é' | 'ê' | 'ë' | 'ě' | 'ę' => "e",
+            'ì' | 'í' | 'î' | 'ï' => "i",
+            'ł' => "l",
+            'ñ' | 'ń' | 'ň' => "n",
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ő' => "o",
+            'ř' => "r",
+            'š' | 'ś' | 'ş' => "s",
+            'ť' | 'ţ' => "t",
+            'ù' | 'ú' | 'û' | 'ü' | 'ů' | 'ű' => "u",
+            'ý' | 'ÿ' => "y",
+            'ž' | 'ź' | 'ż' => "z",
+            'ß' => "ss",
+            c if c.is_alphanumeric() => {
+                latin.push(c);
+                continue;
+            }
+            // Apostrophes and hyphens inside names
+            _ => "",
+        };
+        latin.push_str(replacement);
+    }
+    latin
+}
+
+// Fold the letters transliteration systems disagree on, Russian "g" and Ukrainian "h" for "г"
+// or German "w" for "в", then collapse doubled letters
+fn fold(word: &str) -> String {
+    let mut word = word.to_string();
+    for (from, to) in [
+        ("shch", "sh"),
+        ("sch", "sh"),
+        ("kh", "h"),
+        ("tz", "c"),
+        ("ts", "c"),
+        ("ck", "k"),
+        ("ph", "f"),
+        ("w", "v"),
+        ("x", "ks"),
+        ("q", "k"),
+        ("g", "h"),
+        ("j", "i"),
+        ("y", "i"),
+    ] {
+        word = word.replace(from, to);
+    }
+    let mut folded = String::with_capacity(word.len());
+    for c in word.chars() {
+        if !folded.ends_with(c) {
+            folded.push(c);
+        }
+    }
+    folded
+}
+
+// 1 for equal keys down to 0 for keys with nothing in common, from the edit distance
+fn similarity(a: &str, b: &str) -> f64 {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    1.0 - previous[b.len()] as f64 / longest as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::entities::normalize;
+
+    fn person(name: &str) -> String {
+        entity_key(EntityType::Person, &normalize(name))
+    }
+
+    #[test]
+    fn test_entity_key_transliterations() {
+        assert_eq!(person("Коваленко"), person("Kovalenko"));
+        assert_eq!(person("Kowalenko"), person("Kovalenko"));
+        assert_eq!(person("Шевченко"), person("Shevchenko"));
+    }
+
+    #[test]
+    fn test_entity_key_titles() {
+        assert_eq!(person("Mr. Kovalenko"), person("Kovalenko"));
+        assert_eq!(person("пан Коваленко"), person("Kovalenko"));
+        assert_eq!(person("Dr Olena Kovalenko"), person("Olena Kovalenko"));
+        // A title alone is the whole name
+        assert_eq!(person("Mr."), "mr");
+        // Only people have titles
+        assert_eq!(entity_key(EntityType::Organization, "pan am"), "pan am");
+    }
+
+    #[test]
+    fn test_entity_key_ukrainian_and_russian_g() {
+        // Ukrainian "г" is transliterated "h", Russian "г" is "g"
+        assert_eq!(latin("григорій"), "hryhoriy");
+        assert_eq!(person("Григорій"), person("Grigoriy"));
+        assert_eq!(person("Григорий"), person("Hryhorii"));
+        assert_eq!(person("Ольга"), person("Olga"));
+    }
+
+    #[test]
+    fn test_entity_key_distinct_names() {
+        assert_ne!(person("Kovalenko"), person("Kovalchuk"));
+        assert_ne!(person("Petrenko"), person("Petrov"));
+        assert!(similarity(&person("Kovalenko"), &person("Kovalchuk")) < 0.85);
+        assert!(similarity(&person("Petrenko"), &person("Petrov")) < 0.85);
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity("kovalenko", "kovalenko"), 1.0);
+        assert_eq!(similarity("", ""), 1.0);
+        assert_eq!(similarity("abc", "xyz"), 0.0);
+        // One dropped letter in nine stays above the default threshold, two don't
+        assert!(similarity("kovalenko", "kovalenk") >= 0.85);
+        assert!(similarity("kovalenko", "kvalenk") < 0.85);
+    }
+}
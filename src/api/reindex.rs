@@ -0,0 +1,133 @@
+use super::models::{CreateCategory, Reindex, ReindexStatus};
+use super::worker::{Task, Workers};
+use crate::db::establish_connection;
+use crate::errors::AppResult;
+use actix_web::{delete, get, web, HttpResponse, Responder};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+const SELECT_REINDEX: &str = r#"
+    SELECT job_id, category_id, dry_run, status, error, total, processed, changed, gained, lost,
+        CASE WHEN status = 'running' AND processed > 0
+            THEN EXTRACT(EPOCH FROM NOW() - started_at)::float8 / processed * (total - processed)
+        END AS eta_seconds
+    FROM reindex
+    WHERE job_id = $1
+    "#;
+
+// Queue a reindex of the category together with the row tracking its progress. A proposed
+// category turns the job into a dry run classifying calls against it without saving anything.
+pub async fn enqueue_reindex(
+    conn: &mut PgConnection,
+    workers: &Workers,
+    category_id: Option<i32>,
+    proposed: Option<CreateCategory>,
+) -> Result<Uuid, sqlx::Error> {
+    let dry_run = proposed.is_some();
+    let task = Task::Reindex {
+        category_id,
+        proposed,
+    };
+    let job_id = workers.enqueue(&mut *conn, &task).await?;
+
+    sqlx::query(
+        r#"
+    INSERT INTO reindex (job_id, category_id, dry_run, status)
+    VALUES ($1, $2, $3, $4)
+    "#,
+    )
+    .bind(job_id)
+    .bind(category_id)
+    .bind(dry_run)
+    .bind(ReindexStatus::Queued.as_str())
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(job_id)
+}
+
+// Get the progress of a reindex job
+#[get("/reindex/{job_id}")]
+pub async fn get_reindex(
+    pool: web::Data<PgPool>,
+    job_id: web::Path<Uuid>,
+) -> AppResult<impl Responder> {
+    let reindex = fetch_reindex(pool.get_ref(), *job_id).await?;
+
+    match reindex {
+        Some(reindex) => Ok(HttpResponse::Ok().json(reindex)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+pub async fn fetch_reindex(pool: &PgPool, job_id: Uuid) -> Result<Option<Reindex>, sqlx::Error> {
+    sqlx::query_as::<_, Reindex>(SELECT_REINDEX)
+        .bind(job_id)
+        .fetch_optional(pool)
+        .await
+}
+
+// Cancel a reindex job. A running job stops before the next call it would classify.
+#[delete("/reindex/{job_id}")]
+pub async fn cancel_reindex(
+    pool: web::Data<PgPool>,
+    job_id: web::Path<Uuid>,
+) -> AppResult<impl Responder> {
+    let status = sqlx::query_scalar::<_, String>("SELECT status FROM reindex WHERE job_id = $1")
+        .bind(*job_id)
+        .fetch_optional(pool.get_ref())
+        .await?;
+
+    match status {
+        None => return Ok(HttpResponse::NotFound().finish()),
+        Some(status) if ReindexStatus::is_finished(&status) => {
+            return Ok(HttpResponse::Conflict().finish())
+        }
+        Some(_) => {}
+    }
+
+    // A job that hasn't started is cancelled right away, the worker skips it once claimed
+    sqlx::query(
+        r#"
+    UPDATE reindex
+    SET cancel_requested = TRUE,
+        status = CASE WHEN status = $1 THEN $2 ELSE status END,
+        finished_at = CASE WHEN status = $1 THEN NOW() ELSE finished_at END
+    WHERE job_id = $3
+    "#,
+    )
+    .bind(ReindexStatus::Queued.as_str())
+    .bind(ReindexStatus::Cancelled.as_str())
+    .bind(*job_id)
+    .execute(pool.get_ref())
+    .await?;
+
+    let reindex = sqlx::query_as::<_, Reindex>(SELECT_REINDEX)
+        .bind(*job_id)
+        .fetch_one(pool.get_ref())
+        .await?;
+
+    Ok(HttpResponse::Accepted().json(reindex))
+}
+
+use actix_web::{http::StatusCode, test, App};
+
+// Test GET /reindex/{job_id}
+#[actix_web::test]
+async fn test_get_reindex() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .service(get_reindex),
+    )
+    .await;
+
+    let job_id = Uuid::new_v4(); // Unknown job
+    let req = test::TestRequest::get()
+        .uri(&format!("/reindex/{}", job_id))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,700 @@
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Message, MessageStream, Session};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use super::audio::SAMPLE_RATE;
+use super::call::{requested_language, upload_url};
+use super::events::{publish, CallEvent};
+use super::models::{CallStatus, WebhookEvent};
+use super::utils::Segment;
+use super::utils::{detect_language, emotional_tone, name_and_locations, transcribe_audio};
+use super::vad::{speech_chunks, VadConfig};
+use super::webhooks::dispatch;
+use super::worker::{Task, Workers};
+use crate::ai_config::AppState;
+use crate::config::env_or;
+use crate::db::establish_connection;
+use crate::errors::AppError;
+
+#[derive(Deserialize)]
+struct StreamOptions {
+    // Whisper language code, detected from the first chunk of speech when missing
+    #[serde(default)]
+    language: Option<String>,
+    #[serde(default)]
+    format: SampleFormat,
+}
+
+// Encoding of the binary frames, always mono at SAMPLE_RATE
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum SampleFormat {
+    #[default]
+    S16le,
+    F32le,
+}
+
+impl SampleFormat {
+    fn width(self) -> usize {
+        match self {
+            Self::S16le => 2,
+            Self::F32le => 4,
+        }
+    }
+
+    fn sample(self, bytes: &[u8]) -> f32 {
+        match self {
+            Self::S16le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            Self::F32le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+}
+
+// Sent by the client once all audio is out, the remaining events follow before the server
+// closes the socket
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    End,
+}
+
+// Text messages sent to the client
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    // First message, the call the stream is stored as
+    Call {
+        id: Uuid,
+    },
+    Language {
+        language: String,
+        probability: f64,
+    },
+    Segment {
+        start_ms: i32,
+        end_ms: i32,
+        text: String,
+    },
+    // Tone of the last STREAM_SENTIMENT_WINDOW_SECS of speech
+    Sentiment {
+        emotional_tone: Option<String>,
+        start_ms: i32,
+        end_ms: i32,
+    },
+    // A name or location not mentioned before
+    Entity {
+        label: &'static str,
+        text: String,
+        start_ms: i32,
+    },
+    // All audio is transcribed, the call goes on to diarization and analysis
+    Done {
+        id: Uuid,
+    },
+    Error {
+        error: String,
+    },
+}
+
+// How often a live call marks its row as still streaming
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Streams open on this instance
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+// Place of an open stream, given back when the live call is dropped
+struct StreamSlot;
+
+impl StreamSlot {
+    fn take(max: usize) -> Option<Self> {
+        OPEN_STREAMS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| Self)
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Transcribe a live call. The client sends PCM frames as binary messages and gets the
+// transcript segments, the rolling tone and new names and locations back as JSON text
+// messages. The call is stored in `call` and queued for the usual pipeline once the stream
+// ends.
+#[get("/stream")]
+pub async fn stream(
+    req: HttpRequest,
+    body: web::Payload,
+    pool: web::Data<PgPool>,
+    workers: web::Data<Workers>,
+    options: web::Query<StreamOptions>,
+) -> actix_web::Result<HttpResponse> {
+    let language = requested_language(options.language.as_deref())?;
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    let app_state = req
+        .app_data::<web::Data<AppState>>()
+        .ok_or_else(|| actix_web::error::ErrorServiceUnavailable("models are not loaded"))?;
+    // Each stream keeps a transcriber busy, extra ones are asked to come back later
+    let Some(slot) = StreamSlot::take(env_or("STREAM_MAX_CONCURRENT", 16usize)) else {
+        let reason = CloseReason {
+            code: CloseCode::Again,
+            description: Some("too many open streams".to_string()),
+        };
+        actix_web::rt::spawn(session.close(Some(reason)));
+        return Ok(response);
+    };
+
+    let call_id = Uuid::new_v4();
+    let path = audio_path(call_id).await?;
+    // The audio goes to disk as it arrives, the sizes in the header are filled in at the end
+    let mut file = File::create(&path).await?;
+    file.write_all(&wav_header(0)).await?;
+    let audio_url = upload_url(&path).map_err(AppError::from)?;
+    sqlx::query(
+        r#"
+    INSERT INTO call (id, audio_url, status, language)
+    VALUES ($1, $2, $3, $4)
+    "#,
+    )
+    .bind(call_id)
+    .bind(audio_url)
+    .bind(CallStatus::Streaming.as_str())
+    .bind(&language)
+    .execute(pool.get_ref())
+    .await
+    .map_err(AppError::from)?;
+    let event = CallEvent::Status {
+        call_id,
+        status: CallStatus::Streaming.as_str().to_string(),
+    };
+    publish(pool.get_ref(), &event)
+        .await
+        .map_err(AppError::from)?;
+
+    let live = LiveCall {
+        pool: pool.get_ref().clone(),
+        app_state: app_state.get_ref().clone(),
+        workers: workers.get_ref().clone(),
+        call_id,
+        path,
+        format: options.format,
+        vad: VadConfig::from_env(),
+        pause: SAMPLE_RATE as usize * env_or("STREAM_PAUSE_MS", 700usize) / 1000,
+        max_samples: SAMPLE_RATE as usize * env_or("STREAM_MAX_SECS", 3600usize),
+        sentiment_window_ms: env_or("STREAM_SENTIMENT_WINDOW_SECS", 30i32) * 1000,
+        language,
+        session,
+        connected: true,
+        partial: Vec::new(),
+        file,
+        received: 0,
+        samples: Vec::new(),
+        transcribed: 0,
+        segments: Vec::new(),
+        names: Vec::new(),
+        locations: Vec::new(),
+        _slot: slot,
+    };
+    actix_web::rt::spawn(run(live, messages));
+
+    Ok(response)
+}
+
+// Streamed audio is kept next to the uploads, so the call can be analyzed again later
+async fn audio_path(call_id: Uuid) -> std::io::Result<PathBuf> {
+    let dir = env_or("UPLOAD_DIR", PathBuf::from("./uploads"));
+    tokio::fs::create_dir_all(&dir).await?;
+    Ok(tokio::fs::canonicalize(&dir)
+        .await?
+        .join(format!("{}.wav", call_id)))
+}
+
+// State of one streamed call
+struct LiveCall {
+    pool: PgPool,
+    app_state: AppState,
+    workers: Workers,
+    call_id: Uuid,
+    path: PathBuf,
+    format: SampleFormat,
+    vad: VadConfig,
+    // Silence in samples after which the speech before it is transcribed
+    pause: usize,
+    max_samples: usize,
+    sentiment_window_ms: i32,
+    language: Option<String>,
+    session: Session,
+    // Cleared once the client is gone, the call is still stored
+    connected: bool,
+    // Bytes of a sample split between two frames
+    partial: Vec<u8>,
+    // WAV file the audio is appended to
+    file: File,
+    received: usize,
+    // Samples not transcribed yet, the ones before are only in the file
+    samples: Vec<f32>,
+    // Samples transcribed or skipped as silence, `samples` starts after them
+    transcribed: usize,
+    segments: Vec<Segment>,
+    names: Vec<String>,
+    locations: Vec<String>,
+    _slot: StreamSlot,
+}
+
+async fn run(live: LiveCall, messages: MessageStream) {
+    let call_id = live.call_id;
+    let pool = live.pool.clone();
+    // Calls left `streaming` by an instance that went away are failed by `fail_abandoned`
+    let heartbeat = actix_web::rt::spawn(async move {
+        loop {
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            let touched = sqlx::query("UPDATE call SET updated_at = NOW() WHERE id = $1")
+                .bind(call_id)
+                .execute(&pool)
+                .await;
+            if let Err(err) = touched {
+                log::warn!("failed to extend stream of call {}: {:?}", call_id, err);
+            }
+        }
+    });
+    receive(live, messages).await;
+    heartbeat.abort();
+}
+
+async fn receive(mut live: LiveCall, mut messages: MessageStream) {
+    let call_id = live.call_id;
+    live.send(StreamEvent::Call { id: call_id }).await;
+    // Frames arriving during a transcription wait in the socket, Whisper keeps up with real
+    // time on any replica
+    let mut close = None;
+    let received = async {
+        while let Some(message) = messages.recv().await {
+            match message? {
+                Message::Binary(bytes) => {
+                    live.push(&bytes).await?;
+                    if live.received > live.max_samples {
+                        let seconds = live.max_samples / SAMPLE_RATE as usize;
+                        live.send(StreamEvent::Error {
+                            error: format!("stream is longer than {} seconds", seconds),
+                        })
+                        .await;
+                        close = Some(CloseReason::from(CloseCode::Size));
+                        break;
+                    }
+                    live.transcribe(false).await?;
+                }
+                Message::Text(text) => match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(ClientMessage::End) => break,
+                    Err(err) => {
+                        live.send(StreamEvent::Error {
+                            error: format!("invalid message: {}", err),
+                        })
+                        .await
+                    }
+                },
+                Message::Ping(bytes) if live.session.pong(&bytes).await.is_err() => {
+                    live.connected = false;
+                }
+                Message::Close(reason) => {
+                    // The client is gone, the call is finished without it
+                    let _ = live.session.clone().close(reason).await;
+                    live.connected = false;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        live.transcribe(true).await
+    };
+
+    if let Err(err) = received.await {
+        log::error!("stream of call {} failed: {:?}", call_id, err);
+        live.send(StreamEvent::Error {
+            error: err.to_string(),
+        })
+        .await;
+        if live.connected {
+            let _ = live.session.close(Some(CloseCode::Error.into())).await;
+        }
+        record_failure(&live.pool, call_id, &err.to_string()).await;
+        return;
+    }
+
+    live.send(StreamEvent::Done { id: call_id }).await;
+    if live.connected {
+        let _ = live.session.clone().close(close).await;
+    }
+    let pool = live.pool.clone();
+    if let Err(err) = live.finish().await {
+        log::error!("failed to finish streamed call {}: {:?}", call_id, err);
+        record_failure(&pool, call_id, &err.to_string()).await;
+    }
+}
+
+impl LiveCall {
+    // Append a binary frame to the audio file and the samples waiting for transcription
+    async fn push(&mut self, bytes: &[u8]) -> Result<()> {
+        let format = self.format;
+        self.partial.extend_from_slice(bytes);
+        let whole = self.partial.len() / format.width() * format.width();
+        let samples = self.partial[..whole]
+            .chunks_exact(format.width())
+            .map(|bytes| format.sample(bytes))
+            .collect::<Vec<_>>();
+        self.partial.drain(..whole);
+        self.file.write_all(&pcm16(&samples)).await?;
+        self.received += samples.len();
+        self.samples.extend(samples);
+        Ok(())
+    }
+
+    // Transcribe the speech followed by a pause, or everything left when `flush` is set
+    async fn transcribe(&mut self, flush: bool) -> Result<()> {
+        let pending = &self.samples;
+        let mut chunks = speech_chunks(pending, &self.vad);
+        let end = match chunks.last() {
+            // Only the last chunk can still grow
+            Some(last) if !flush && pending.len() - last.end < self.pause => {
+                let start = last.start;
+                chunks.pop();
+                start
+            }
+            Some(_) => pending.len(),
+            // Nothing but silence, which is dropped once a whole chunk of it piled up
+            None if flush
+                || pending.len() * 1000 / SAMPLE_RATE as usize > self.vad.max_chunk_ms =>
+            {
+                pending.len()
+            }
+            None => 0,
+        };
+
+        for chunk in chunks {
+            self.transcribe_chunk(chunk).await?;
+        }
+        self.samples.drain(..end);
+        self.transcribed += end;
+        Ok(())
+    }
+
+    // Transcribe a range of the pending samples
+    async fn transcribe_chunk(&mut self, chunk: Range<usize>) -> Result<()> {
+        let samples = Arc::new(self.samples[chunk.clone()].to_vec());
+        let language = match self.language.clone() {
+            Some(language) => language,
+            None => {
+                let detection_samples = samples.clone();
+                let (language, probability) = self
+                    .app_state
+                    .transcriber
+                    .run(move |whisper| detect_language(&detection_samples, whisper))
+                    .await??;
+                sqlx::query(
+                    r#"
+    UPDATE call SET language = $1, language_probability = $2, updated_at = NOW()
+    WHERE id = $3
+    "#,
+                )
+                .bind(&language)
+                .bind(probability)
+                .bind(self.call_id)
+                .execute(&self.pool)
+                .await?;
+                let event = CallEvent::Language {
+                    call_id: self.call_id,
+                    language: language.clone(),
+                    probability: Some(probability),
+                };
+                publish(&self.pool, &event).await?;
+                self.send(StreamEvent::Language {
+                    language: language.clone(),
+                    probability,
+                })
+                .await;
+                self.language = Some(language.clone());
+                language
+            }
+        };
+
+        let chunk_language = language.clone();
+        let transcript = self
+            .app_state
+            .transcriber
+            .run(move |whisper| transcribe_audio(&samples, &chunk_language, false, whisper))
+            .await??;
+        let offset_ms = ((self.transcribed + chunk.start) * 1000 / SAMPLE_RATE as usize) as i32;
+        let mut segments = transcript.segments;
+        for segment in &mut segments {
+            segment.start_ms += offset_ms;
+            segment.end_ms += offset_ms;
+        }
+        let event = CallEvent::Segments {
+            call_id: self.call_id,
+            translation: false,
+            segments: segments.iter().map(Into::into).collect(),
+        };
+        publish(&self.pool, &event).await?;
+        for segment in segments {
+            self.send(StreamEvent::Segment {
+                start_ms: segment.start_ms,
+                end_ms: segment.end_ms,
+                text: segment.text.clone(),
+            })
+            .await;
+            self.segments.push(segment);
+        }
+
+        // Languages without models are only transcribed while streaming, their translation
+        // is analyzed once the stream ends
+        let Some(models) = self.app_state.models(Some(&language)).cloned() else {
+            return Ok(());
+        };
+        let Some(last) = self.segments.last() else {
+            return Ok(());
+        };
+        let window_end = last.end_ms;
+        let window: Vec<&Segment> = self
+            .segments
+            .iter()
+            .filter(|segment| segment.end_ms > window_end - self.sentiment_window_ms)
+            .collect();
+        let window_start = window
+            .first()
+            .map_or(window_end, |segment| segment.start_ms);
+        let window_text = window
+            .iter()
+            .map(|segment| segment.text.trim())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let sentiment = models
+            .sentiment
+            .run(move |sentiment| emotional_tone(window_text, sentiment));
+        let chunk_text = transcript.text;
+        let ner = models
+            .ner
+            .run(move |ner| name_and_locations(chunk_text, ner));
+        let (sentiment, names_and_locations) = tokio::try_join!(sentiment, ner)?;
+
+        self.send(StreamEvent::Sentiment {
+            emotional_tone: sentiment?,
+            start_ms: window_start,
+            end_ms: window_end,
+        })
+        .await;
+        let (names, locations) = names_and_locations?;
+        for name in names.unwrap_or_default() {
+            if !self.names.contains(&name) {
+                self.send(StreamEvent::Entity {
+                    label: "PER",
+                    text: name.clone(),
+                    start_ms: offset_ms,
+                })
+                .await;
+                self.names.push(name);
+            }
+        }
+        for location in locations.unwrap_or_default() {
+            if !self.locations.contains(&location) {
+                self.send(StreamEvent::Entity {
+                    label: "LOC",
+                    text: location.clone(),
+                    start_ms: offset_ms,
+                })
+                .await;
+                self.locations.push(location);
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, event: StreamEvent) {
+        if !self.connected {
+            return;
+        }
+        let Ok(message) = serde_json::to_string(&event) else {
+            return;
+        };
+        if self.session.text(message).await.is_err() {
+            self.connected = false;
+        }
+    }
+
+    // Store the audio and queue the call for the rest of the pipeline. It runs as a job like
+    // any submitted call, so a restart doesn't lose it.
+    async fn finish(mut self) -> Result<()> {
+        if self.received == 0 {
+            let _ = tokio::fs::remove_file(&self.path).await;
+            return Err(anyhow!("stream contained no audio"));
+        }
+        let header = wav_header(self.received as u32 * 2);
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.file.write_all(&header).await?;
+        self.file.sync_all().await?;
+        let audio_url = upload_url(&self.path)?;
+
+        let mut tx = self.pool.begin().await?;
+        let queued = sqlx::query(
+            "UPDATE call SET status = $1, updated_at = NOW() WHERE id = $2 AND status = $3",
+        )
+        .bind(CallStatus::Queued.as_str())
+        .bind(self.call_id)
+        .bind(CallStatus::Streaming.as_str())
+        .execute(&mut *tx)
+        .await?;
+        if queued.rows_affected() == 0 {
+            log::warn!("call {} was failed before its stream ended", self.call_id);
+            return Ok(());
+        }
+        // The detected language is passed on so it isn't detected again
+        let task = Task::Call {
+            call_id: self.call_id,
+            audio_url,
+            language: self.language,
+        };
+        self.workers.enqueue(&mut *tx, &task).await?;
+        let event = CallEvent::Status {
+            call_id: self.call_id,
+            status: CallStatus::Queued.as_str().to_string(),
+        };
+        publish(&mut *tx, &event).await?;
+        tx.commit().await?;
+        self.workers.wake();
+        Ok(())
+    }
+}
+
+// Fail the calls still `streaming` whose heartbeat stopped, the instance receiving them is gone
+pub async fn fail_abandoned(pool: &PgPool) -> Result<()> {
+    let timeout = env_or("STREAM_ABANDONED_SECS", 60.0f64);
+    let abandoned = sqlx::query_scalar::<_, Uuid>(
+        r#"
+    UPDATE call SET status = $1, error = $2, updated_at = NOW()
+    WHERE status = $3 AND updated_at < NOW() - make_interval(secs => $4)
+    RETURNING id
+    "#,
+    )
+    .bind(CallStatus::Failed.as_str())
+    .bind("stream ended without reaching the server")
+    .bind(CallStatus::Streaming.as_str())
+    .bind(timeout)
+    .fetch_all(pool)
+    .await?;
+    for call_id in abandoned {
+        log::error!("stream of call {} was abandoned", call_id);
+        notify_failure(pool, call_id, "stream ended without reaching the server").await?;
+    }
+    Ok(())
+}
+
+async fn record_failure(pool: &PgPool, call_id: Uuid, reason: &str) {
+    let result = async {
+        sqlx::query("UPDATE call SET status = $1, error = $2, updated_at = NOW() WHERE id = $3")
+            .bind(CallStatus::Failed.as_str())
+            .bind(reason)
+            .bind(call_id)
+            .execute(pool)
+            .await?;
+        notify_failure(pool, call_id, reason).await
+    };
+    if let Err(err) = result.await {
+        log::error!("failed to store error of call {}: {:?}", call_id, err);
+    }
+}
+
+async fn notify_failure(pool: &PgPool, call_id: Uuid, reason: &str) -> Result<()> {
+    let event = CallEvent::Failed {
+        call_id,
+        status: CallStatus::Failed.as_str().to_string(),
+        error: reason.to_string(),
+    };
+    publish(pool, &event).await?;
+    let data = serde_json::json!({ "call_id": call_id, "error": reason });
+    dispatch(pool, WebhookEvent::CallFailed, data).await?;
+    Ok(())
+}
+
+// Header of a 16-bit PCM WAV at SAMPLE_RATE with `data_len` bytes of samples
+fn wav_header(data_len: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(44);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, mono
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    out.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    out
+}
+
+fn pcm16(samples: &[f32]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+        .collect()
+}
+
+use actix_web::{http::StatusCode, test, App};
+
+// Test GET /stream without a WebSocket upgrade
+#[actix_web::test]
+async fn test_stream_without_upgrade() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Workers::new()))
+            .service(stream),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/stream").to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pcm16, wav_header, StreamSlot};
+
+    #[test]
+    fn test_stream_slots() {
+        let first = StreamSlot::take(1);
+        assert!(first.is_some());
+        assert!(StreamSlot::take(1).is_none());
+        drop(first);
+        assert!(StreamSlot::take(1).is_some());
+    }
+
+    #[test]
+    fn test_wav() {
+        let header = wav_header(4);
+        assert_eq!(header.len(), 44);
+        assert_eq!(&header[4..8], &40u32.to_le_bytes());
+        assert_eq!(&header[40..44], &4u32.to_le_bytes());
+        assert_eq!(pcm16(&[1.0, -2.0]), [0xff, 0x7f, 0x01, 0x80]);
+    }
+}
@@ -0,0 +1,420 @@
+use super::call::{fetch_call, fetch_segments};
+use super::models::{CallStatus, EntityType, TranscriptOptions};
+use crate::db::establish_connection;
+use crate::errors::AppResult;
+use actix_web::{get, web, HttpResponse, Responder};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt::Write;
+use uuid::Uuid;
+
+// A timed piece of transcript as it is rendered
+struct Cue {
+    start_ms: i32,
+    end_ms: i32,
+    text: String,
+    speaker: Option<String>,
+    highlights: Vec<Highlight>,
+}
+
+// Cue class and color of each entity type, in the order of the STYLE block
+const CLASSES: &[(EntityType, &str, &str)] = &[
+    (EntityType::Person, "person", "yellow"),
+    (EntityType::Location, "location", "cyan"),
+    (EntityType::Organization, "organization", "lime"),
+    (EntityType::Misc, "misc", "silver"),
+    (EntityType::Passport, "passport", "orange"),
+    (EntityType::VisaApplication, "visa", "orange"),
+    (EntityType::Phone, "phone", "magenta"),
+    (EntityType::Email, "email", "magenta"),
+    (EntityType::Date, "date", "white"),
+];
+
+// Entity mention highlighted in a VTT cue, by character offsets into its text, with the cue
+// class it gets
+struct Highlight {
+    start_char: usize,
+    end_char: usize,
+    class: &'static str,
+}
+
+// Render the stored transcript as SubRip, WebVTT or plain text
+#[get("/call/{id}/transcript.{format}")]
+pub async fn get_transcript(
+    pool: web::Data<PgPool>,
+    path: web::Path<(Uuid, String)>,
+    options: web::Query<TranscriptOptions>,
+) -> AppResult<impl Responder> {
+    let (id, format) = path.into_inner();
+    let content_type = match format.as_str() {
+        "srt" => "application/x-subrip; charset=utf-8",
+        "vtt" => "text/vtt; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let Some(call) = fetch_call(pool.get_ref(), id).await? else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    // The transcript is replaced until the call is done
+    if call.status != CallStatus::Done.as_str() {
+        return Ok(HttpResponse::Conflict().json(call.status));
+    }
+
+    let mut highlights = match options.highlight && format == "vtt" {
+        true => fetch_highlights(pool.get_ref(), id).await?,
+        false => HashMap::new(),
+    };
+    let mut cues: Vec<Cue> = fetch_segments(pool.get_ref(), id)
+        .await?
+        .into_iter()
+        .map(|segment| Cue {
+            start_ms: segment.start_ms,
+            end_ms: segment.end_ms,
+            text: segment.text,
+            speaker: segment
+                .speaker
+                .map(|speaker| format!("Speaker {}", speaker)),
+            highlights: highlights.remove(&segment.id).unwrap_or_default(),
+        })
+        .collect();
+    // Calls transcribed before segments were stored only have the full text
+    if cues.is_empty() {
+        if let Some(text) = call.text.as_deref().filter(|text| !text.trim().is_empty()) {
+            cues.push(Cue {
+                start_ms: 0,
+                end_ms: (call.duration_secs.unwrap_or_default() * 1000.0) as i32,
+                text: text.trim().to_string(),
+                speaker: None,
+                highlights: Vec::new(),
+            });
+        }
+    }
+    if !options.speakers {
+        cues.iter_mut().for_each(|cue| cue.speaker = None);
+    }
+
+    let body = match format.as_str() {
+        "srt" => render_srt(&cues),
+        "vtt" => render_vtt(&cues),
+        _ => render_txt(&cues),
+    };
+    Ok(HttpResponse::Ok().content_type(content_type).body(body))
+}
+
+fn render_srt(cues: &[Cue]) -> String {
+    let mut out = String::new();
+    for (index, cue) in cues.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "{}\n{} --> {}",
+            index + 1,
+            timestamp(cue.start_ms, ','),
+            timestamp(cue.end_ms, ',')
+        );
+        match &cue.speaker {
+            Some(speaker) => {
+                let _ = writeln!(out, "{}: {}\n", speaker, cue.text);
+            }
+            None => {
+                let _ = writeln!(out, "{}\n", cue.text);
+            }
+        }
+    }
+    out
+}
+
+fn render_vtt(cues: &[Cue]) -> String {
+    let mut out = String::from("WEBVTT\n\n");
+    // Styles for the classes the cues use
+    let styles = CLASSES
+        .iter()
+        .filter(|(_, class, _)| {
+            cues.iter()
+                .flat_map(|cue| &cue.highlights)
+                .any(|highlight| highlight.class == *class)
+        })
+        .map(|(_, class, color)| format!("::cue(.{}) {{ color: {}; }}\n", class, color))
+        .collect::<String>();
+    if !styles.is_empty() {
+        let _ = writeln!(out, "STYLE\n{}", styles);
+    }
+    for cue in cues {
+        let _ = writeln!(
+            out,
+            "{} --> {}",
+            timestamp(cue.start_ms, '.'),
+            timestamp(cue.end_ms, '.')
+        );
+        let text = highlight(&cue.text, &cue.highlights);
+        match &cue.speaker {
+            Some(speaker) => {
+                let _ = writeln!(out, "<v {}>{}\n", escape_vtt(speaker), text);
+            }
+            None => {
+                let _ = writeln!(out, "{}\n", text);
+            }
+        }
+    }
+    out
+}
+
+fn render_txt(cues: &[Cue]) -> String {
+    let mut out = String::new();
+    for cue in cues {
+        match &cue.speaker {
+            Some(speaker) => {
+                let _ = writeln!(out, "{}: {}", speaker, cue.text);
+            }
+            None => {
+                let _ = writeln!(out, "{}", cue.text);
+            }
+        }
+    }
+    out
+}
+
+// `HH:MM:SS,mmm` for SubRip, `HH:MM:SS.mmm` for WebVTT
+fn timestamp(ms: i32, separator: char) -> String {
+    let ms = ms.max(0);
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        separator,
+        ms % 1000
+    )
+}
+
+// Entities found in the segments, by segment id. Mentions found in the
+// English translation of a segment don't index its text.
+async fn fetch_highlights(
+    pool: &PgPool,
+    call_id: Uuid,
+) -> Result<HashMap<i32, Vec<Highlight>>, sqlx::Error> {
+    let entities = sqlx::query_as::<_, (i32, String, i32, i32)>(
+        r#"
+    SELECT segment_id, entity_type, start_char, end_char
+    FROM call_entity
+    WHERE call_id = $1 AND segment_id IS NOT NULL AND NOT translation
+    "#,
+    )
+    .bind(call_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut highlights: HashMap<i32, Vec<Highlight>> = HashMap::new();
+    for (segment_id, entity_type, start_char, end_char) in entities {
+        let Some((_, class, _)) = CLASSES
+            .iter()
+            .find(|(kind, _, _)| Some(*kind) == EntityType::parse(&entity_type))
+        else {
+            continue;
+        };
+        highlights.entry(segment_id).or_default().push(Highlight {
+            start_char: start_char.max(0) as usize,
+            end_char: end_char.max(0) as usize,
+            class,
+        });
+    }
+    Ok(highlights)
+}
+
+// Escape the cue text and wrap the highlighted character ranges in `<c.class>` spans. Of
+// overlapping ranges the one starting first, or the longer, is kept.
+fn highlight(text: &str, highlights: &[Highlight]) -> String {
+    // Byte offset of every character, and of the end of the text
+    let offsets = text
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain([text.len()])
+        .collect::<Vec<_>>();
+    let mut spans = highlights
+        .iter()
+        .filter(|highlight| highlight.start_char < highlight.end_char)
+        .filter_map(|highlight| {
+            let start = *offsets.get(highlight.start_char)?;
+            let end = *offsets.get(highlight.end_char)?;
+            Some((start, end, highlight.class))
+        })
+        .collect::<Vec<_>>();
+    spans.sort_by_key(|(start, end, _)| (*start, std::cmp::Reverse(*end)));
+
+    let mut out = String::with_capacity(text.len());
+    let mut position = 0;
+    for (start, end, class) in spans {
+        if start < position {
+            continue;
+        }
+        out.push_str(&escape_vtt(&text[position..start]));
+        let _ = write!(out, "<c.{}>{}</c>", class, escape_vtt(&text[start..end]));
+        position = end;
+    }
+    out.push_str(&escape_vtt(&text[position..]));
+    out
+}
+
+fn escape_vtt(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+use actix_web::{http::StatusCode, test, App};
+
+// Test GET /call/{id}/transcript.{format}
+#[actix_web::test]
+async fn test_get_transcript() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .service(get_transcript),
+    )
+    .await;
+
+    let call_id = Uuid::new_v4(); // Unknown call
+    let req = test::TestRequest::get()
+        .uri(&format!("/call/{}/transcript.vtt", call_id))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_srt, render_txt, render_vtt, timestamp, Cue, Highlight};
+
+    fn cues() -> Vec<Cue> {
+        vec![
+            Cue {
+                start_ms: 0,
+                end_ms: 1500,
+                text: "Taras <flew> to Kyiv & met Kyivans".to_string(),
+                speaker: Some("Speaker 1".to_string()),
+                highlights: Vec::new(),
+            },
+            Cue {
+                start_ms: 3_723_004,
+                end_ms: 3_725_000,
+                text: "Bye.".to_string(),
+                speaker: None,
+                highlights: Vec::new(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(3_723_004, ','), "01:02:03,004");
+        assert_eq!(timestamp(59_999, '.'), "00:00:59.999");
+        assert_eq!(timestamp(-20, ','), "00:00:00,000");
+    }
+
+    #[test]
+    fn test_render_srt() {
+        assert_eq!(
+            render_srt(&cues()),
+            "1\n00:00:00,000 --> 00:00:01,500\nSpeaker 1: Taras <flew> to Kyiv & met Kyivans\n\n\
+             2\n01:02:03,004 --> 01:02:05,000\nBye.\n\n"
+        );
+    }
+
+    #[test]
+    fn test_render_vtt() {
+        assert_eq!(
+            render_vtt(&cues()),
+            "WEBVTT\n\n\
+             00:00:00.000 --> 00:00:01.500\n<v Speaker 1>Taras &lt;flew&gt; to Kyiv &amp; met Kyivans\n\n\
+             01:02:03.004 --> 01:02:05.000\nBye.\n\n"
+        );
+    }
+
+    #[test]
+    fn test_render_vtt_highlights() {
+        let mut cues = cues();
+        cues.truncate(1);
+        // "Kyiv" inside "Kyivans" is not a mention
+        cues[0].highlights = vec![
+            Highlight {
+                start_char: 16,
+                end_char: 20,
+                class: "location",
+            },
+            Highlight {
+                start_char: 0,
+                end_char: 5,
+                class: "person",
+            },
+        ];
+        assert_eq!(
+            render_vtt(&cues),
+            "WEBVTT\n\n\
+             STYLE\n::cue(.person) { color: yellow; }\n::cue(.location) { color: cyan; }\n\n\
+             00:00:00.000 --> 00:00:01.500\n\
+             <v Speaker 1><c.person>Taras</c> &lt;flew&gt; to <c.location>Kyiv</c> &amp; met Kyivans\n\n"
+        );
+    }
+
+    #[test]
+    fn test_render_vtt_multi_word_mentions() {
+        let highlights = [(0, 5, "person"), (30, 38, "location"), (45, 58, "phone")];
+        let cue = Cue {
+            start_ms: 0,
+            end_ms: 4000,
+            text: "Olena needs a new passport in New York, call 067 123 45 67".to_string(),
+            speaker: None,
+            highlights: highlights
+                .iter()
+                .map(|&(start_char, end_char, class)| Highlight {
+                    start_char,
+                    end_char,
+                    class,
+                })
+                .collect(),
+        };
+        // "new" and "passport" are left alone, only the mentions are wrapped
+        assert_eq!(
+            render_vtt(&[cue]),
+            "WEBVTT\n\n\
+             STYLE\n::cue(.person) { color: yellow; }\n::cue(.location) { color: cyan; }\n\
+             ::cue(.phone) { color: magenta; }\n\n\
+             00:00:00.000 --> 00:00:04.000\n\
+             <c.person>Olena</c> needs a new passport in <c.location>New York</c>, \
+             call <c.phone>067 123 45 67</c>\n\n"
+        );
+    }
+
+    #[test]
+    fn test_highlight_overlaps_and_offsets() {
+        let highlight = |start_char, end_char, class| Highlight {
+            start_char,
+            end_char,
+            class,
+        };
+        // Character offsets, not bytes. Of overlapping mentions the longer one is kept, ranges
+        // past the text are dropped.
+        assert_eq!(
+            super::highlight(
+                "Пан Коваленко з Києва",
+                &[
+                    highlight(4, 13, "person"),
+                    highlight(0, 13, "person"),
+                    highlight(16, 21, "location"),
+                    highlight(20, 40, "date"),
+                ]
+            ),
+            "<c.person>Пан Коваленко</c> з <c.location>Києва</c>"
+        );
+    }
+
+    #[test]
+    fn test_render_txt() {
+        assert_eq!(
+            render_txt(&cues()),
+            "Speaker 1: Taras <flew> to Kyiv & met Kyivans\nBye.\n"
+        );
+    }
+}
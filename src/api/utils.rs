@@ -1,39 +1,185 @@
-use anyhow::Result;
-use rust_bert::pipelines::ner::NERModel;
-use rust_bert::pipelines::sentiment::{SentimentModel, SentimentPolarity};
+use actix_web::web::Bytes;
+use anyhow::{anyhow, Result};
+use futures_util::{Stream, StreamExt};
+use rust_bert::pipelines::question_answering::{QaInput, QuestionAnsweringModel};
+use rust_bert::pipelines::sentiment::SentimentPolarity;
+use rust_bert::pipelines::token_classification::TokenClassificationModel;
 use rust_bert::pipelines::zero_shot_classification::ZeroShotClassificationModel;
-use simple_transcribe_rs::transcriber::Transcriber;
+use serde_json::json;
+use sqlx::types::Json;
 use sqlx::PgPool;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::Write;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext};
 
-use super::models::{CallReindex, Category};
+use super::entities::extract_entities;
+use super::models::{
+    CallReindex, Category, CategoryMatch, CategorySource, EntityType, ExtractionField,
+    ReindexStatus, WebhookEvent,
+};
+use super::webhooks::dispatch;
+use crate::ai_config::{AnalysisModels, AppState, SentimentClassifier};
+use crate::config::env_or;
+use crate::errors::UploadError;
 
-// Get audio file and write to tmp folder
-pub async fn download_audio_file(audio_url: &str) -> Result<Uuid> {
-    let response = reqwest::get(audio_url).await?;
-    let bytes = response.bytes().await?;
-    let path = Uuid::new_v4();
-    let mut file = File::create(format!("./tmp/{}", path))?;
-    file.write_all(&bytes)?;
-    Ok(path)
+// Minimal zero-shot score for a call to be assigned a category
+const CATEGORY_THRESHOLD: f64 = 0.89;
+
+// Stream an uploaded audio body to the upload folder, giving up once it exceeds `max_bytes`.
+// Returns the absolute path of the stored file.
+pub async fn store_upload<S, E>(body: S, max_bytes: u64) -> Result<PathBuf, UploadError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Display,
+{
+    let dir = env_or("UPLOAD_DIR", PathBuf::from("./uploads"));
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = tokio::fs::canonicalize(&dir)
+        .await?
+        .join(Uuid::new_v4().to_string());
+
+    let result = write_upload(body, &path, max_bytes).await;
+    if result.is_err() {
+        // Don't leave partial uploads behind
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+    result.map(|_| path)
+}
+
+async fn write_upload<S, E>(body: S, path: &Path, max_bytes: u64) -> Result<(), UploadError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Display,
+{
+    let mut body = std::pin::pin!(body);
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|err| UploadError::Invalid(format!("invalid body: {}", err)))?;
+        size += chunk.len() as u64;
+        if size > max_bytes {
+            return Err(UploadError::TooLarge(max_bytes));
+        }
+        file.write_all(&chunk).await?;
+    }
+    if size == 0 {
+        return Err(UploadError::Invalid("audio is empty".to_string()));
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+pub struct Transcript {
+    pub text: String,
+    pub segments: Vec<Segment>,
+}
+
+pub struct Segment {
+    pub start_ms: i32,
+    pub end_ms: i32,
+    pub text: String,
+    // Mean probability of the segment's text tokens, None when it has none
+    pub avg_probability: Option<f64>,
+    // Set by `diarization::diarize`
+    pub speaker: Option<i32>,
 }
 
-// Helper function to transcribe audio using simple_transcribe
-pub async fn transcribe_audio(path: String, trans: &Transcriber) -> String {
-    let result = trans.transcribe(&path, None).unwrap();
-    let text = result.get_text();
-    text.to_string()
+// Transcribe 16 kHz mono samples produced by `audio::decode_audio` spoken in `language`, or
+// translate them to English with Whisper's translate task
+pub fn transcribe_audio(
+    samples: &[f32],
+    language: &str,
+    translate: bool,
+    whisper: &WhisperContext,
+) -> Result<Transcript> {
+    let task = if translate {
+        "translation"
+    } else {
+        "transcription"
+    };
+    let mut state = whisper.create_state()?;
+    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    params.set_language(Some(language));
+    params.set_translate(translate);
+    state
+        .full(params, samples)
+        .map_err(|err| anyhow!("{} failed: {}", task, err))?;
+
+    let mut transcript = Transcript {
+        text: String::new(),
+        segments: Vec::new(),
+    };
+    for segment in 0..state.full_n_segments()? {
+        let text = state.full_get_segment_text(segment)?;
+
+        // Timestamps, control and language tokens come after the text tokens in the vocabulary
+        let mut probabilities = Vec::new();
+        for token in 0..state.full_n_tokens(segment)? {
+            if state.full_get_token_id(segment, token)? < whisper.token_eot() {
+                probabilities.push(state.full_get_token_prob(segment, token)? as f64);
+            }
+        }
+        let avg_probability = (!probabilities.is_empty())
+            .then(|| probabilities.iter().sum::<f64>() / probabilities.len() as f64);
+
+        transcript.text.push_str(&text);
+        transcript.segments.push(Segment {
+            // Whisper counts in units of 10 ms
+            start_ms: state.full_get_segment_t0(segment)? as i32 * 10,
+            end_ms: state.full_get_segment_t1(segment)? as i32 * 10,
+            text: text.trim().to_string(),
+            avg_probability,
+            speaker: None,
+        });
+    }
+    Ok(transcript)
+}
+
+// Most likely spoken language of the first 30 seconds of `samples`, with its probability
+pub fn detect_language(samples: &[f32], whisper: &WhisperContext) -> Result<(String, f64)> {
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get().min(4));
+    let mut state = whisper.create_state()?;
+    state.pcm_to_mel(samples, threads)?;
+    let probabilities = state.lang_detect(0, threads)?;
+    let (id, probability) = probabilities
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .ok_or_else(|| anyhow!("language detection returned no language"))?;
+    let language =
+        whisper_rs::get_lang_str(id as i32).ok_or_else(|| anyhow!("unknown language id {}", id))?;
+    Ok((language.to_string(), *probability as f64))
 }
 
-pub async fn emotional_tone(
+pub fn emotional_tone(
     text: String,
-    sentiment_classifier: &SentimentModel,
+    sentiment_classifier: &SentimentClassifier,
 ) -> Result<Option<String>> {
+    let sentiment_classifier = match sentiment_classifier {
+        SentimentClassifier::Binary(model) => model,
+        SentimentClassifier::Labeled(model) => {
+            let label = model
+                .predict([text.as_str()])
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("sentiment model returned no prediction"))?;
+            let emotional_tone = match label.text.to_ascii_lowercase().as_str() {
+                "positive" => "Positive",
+                "negative" if label.score > 0.9 => "Angry",
+                "negative" => "Negative",
+                _ => "Neutral",
+            };
+            return Ok(Some(emotional_tone.to_string()));
+        }
+    };
     let output = sentiment_classifier.predict(&[text.as_str()]); // Pass the vector of &str
-    let sentiment = output.into_iter().next().unwrap();
+    let sentiment = output
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("sentiment model returned no prediction"))?;
     let emotional_tone = match sentiment.polarity {
         SentimentPolarity::Positive => {
             if sentiment.score > 0.999 {
@@ -56,41 +202,37 @@ pub async fn emotional_tone(
     Ok(Some(emotional_tone.to_string()))
 }
 
-pub async fn name_and_locations(
+// Distinct names and locations mentioned in the text, in order of appearance
+pub fn name_and_locations(
     text: String,
-    ner_model: &NERModel,
+    ner_model: &TokenClassificationModel,
 ) -> Result<(Option<Vec<String>>, Option<Vec<String>>)> {
-    let output = ner_model.predict(&[text]);
-    // Initialize vectors to hold name and location entities.
-    let mut names: Vec<String> = Vec::new();
-    let mut locations: Vec<String> = Vec::new();
-
-    // Iterate over the output and categorize entities based on their label (e.g., 'PER' for persons, 'LOC' for locations).
-    for entity in output.iter().flatten() {
-        match entity.label.as_str() {
-            "I-PER" => names.push(entity.clone().word), // "PER" typically represents persons in NER.
-            "I-LOC" => locations.push(entity.clone().word), // "LOC" represents locations.
-            _ => {}                                     // Ignore other entity types.
-        }
-    }
-
-    // Return None if no names or locations were found.
-    let names_opt = if names.is_empty() { None } else { Some(names) };
-    let locations_opt = if locations.is_empty() {
-        None
-    } else {
-        Some(locations)
+    let spans = extract_entities(&[text], ner_model)
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let distinct = |entity_type: EntityType| {
+        let mut seen: Vec<&str> = Vec::new();
+        let texts = spans
+            .iter()
+            .filter(|span| span.entity_type == entity_type)
+            .filter(|span| {
+                let new = !seen.contains(&span.normalized.as_str());
+                seen.push(&span.normalized);
+                new
+            })
+            .map(|span| span.text.clone())
+            .collect::<Vec<_>>();
+        (!texts.is_empty()).then_some(texts)
     };
-
-    // Return the names and locations.
-    Ok((names_opt, locations_opt))
+    Ok((distinct(EntityType::Person), distinct(EntityType::Location)))
 }
 
-pub async fn categories(
+pub fn categories(
     text: String,
     categories: Vec<Category>,
     zero_shot: &ZeroShotClassificationModel,
-) -> Result<Vec<String>> {
+) -> Result<Vec<CategoryMatch>> {
     let candidate_labels: Vec<String> = categories
         .iter()
         .flat_map(|c| {
@@ -109,128 +251,318 @@ pub async fn categories(
         None,
         128,
     )?;
-    let mut unique_categories = HashSet::new();
+    let mut matches: HashMap<i32, CategoryMatch> = HashMap::new();
 
     // Iterate over the Vec<Vec<Label>> and filter by score
-    for label in &output[0] {
-        if label.score > 0.89 {
+    for label in output.iter().flatten() {
+        if label.score > CATEGORY_THRESHOLD {
             // Check if the label matches any category title or points
-            if let Some(category_title) = categories.iter().find_map(|category| {
-                if category.title == label.text
+            for category in categories.iter().filter(|category| {
+                category.title == label.text
                     || category
                         .points
                         .as_ref()
                         .map_or(false, |points| points.contains(&label.text))
-                {
-                    Some(category.title.clone())
-                } else {
-                    None
-                }
             }) {
-                // Insert the category title into the HashSet (duplicates are automatically handled)
-                unique_categories.insert(category_title);
+                // Keep the label that scored highest for each category
+                let matched = matches.entry(category.id).or_insert(CategoryMatch {
+                    category_id: category.id,
+                    score: label.score,
+                    matched_label: label.text.clone(),
+                });
+                if label.score > matched.score {
+                    matched.score = label.score;
+                    matched.matched_label = label.text.clone();
+                }
             }
         }
     }
-    // Convert the HashSet back into a Vec for the return value
-    Ok(unique_categories.into_iter().collect())
+    Ok(matches.into_values().collect())
 }
 
-pub async fn reindex_calls_for_category(
+// Answer the extraction fields of the call's categories over its transcript. The document
+// maps each field name to the best answer with its confidence. Answers below
+// EXTRACTION_MIN_SCORE keep their confidence but no value. A field defined by several
+// categories keeps the most confident answer.
+pub fn extract_fields(
+    text: &str,
+    fields: Vec<(i32, ExtractionField)>,
+    qa: &QuestionAnsweringModel,
+) -> Option<serde_json::Value> {
+    if fields.is_empty() || text.trim().is_empty() {
+        return None;
+    }
+    let inputs = fields
+        .iter()
+        .map(|(_, field)| QaInput {
+            question: field.question.clone(),
+            context: text.to_string(),
+        })
+        .collect::<Vec<_>>();
+    let answers = qa.predict(&inputs, 1, 8);
+    let min_score = env_or("EXTRACTION_MIN_SCORE", 0.1);
+
+    let mut document = serde_json::Map::new();
+    for ((category_id, field), answers) in fields.into_iter().zip(answers) {
+        let answer = answers.into_iter().next();
+        let confidence = answer.as_ref().map_or(0.0, |answer| answer.score);
+        let answered = document
+            .get(&field.name)
+            .and_then(|answered| answered["confidence"].as_f64());
+        if answered.is_some_and(|answered| answered >= confidence) {
+            continue;
+        }
+        let value = answer
+            .filter(|answer| answer.score >= min_score)
+            .map(|answer| answer.answer.trim().to_string())
+            .filter(|value| !value.is_empty());
+        document.insert(
+            field.name,
+            json!({ "value": value, "confidence": confidence, "category_id": category_id }),
+        );
+    }
+    Some(serde_json::Value::Object(document))
+}
+
+// Answer the extraction schemas of the categories the call is assigned now, replacing its
+// `extraction` document
+pub async fn refill_extraction(
     pool: &PgPool,
-    prev_title: Option<&str>,
-    category_title: &str,
-    candidate_labels: Vec<String>,
+    models: &AnalysisModels,
+    call_id: Uuid,
+    text: String,
+) -> Result<()> {
+    let schemas = sqlx::query_as::<_, (i32, Json<Vec<ExtractionField>>)>(
+        r#"
+    SELECT category.id, category.extraction_schema
+    FROM call_category
+    JOIN category ON category.id = call_category.category_id
+    WHERE call_category.call_id = $1 AND category.extraction_schema IS NOT NULL
+    ORDER BY call_category.score DESC
+    "#,
+    )
+    .bind(call_id)
+    .fetch_all(pool)
+    .await?;
+    let fields = schemas
+        .into_iter()
+        .flat_map(|(id, schema)| schema.0.into_iter().map(move |field| (id, field)))
+        .collect::<Vec<_>>();
+    let extraction = if fields.is_empty() {
+        None
+    } else {
+        models
+            .qa
+            .run(move |qa| extract_fields(&text, fields, qa))
+            .await?
+    };
+
+    sqlx::query("UPDATE call SET extraction = $1, updated_at = NOW() WHERE id = $2")
+        .bind(extraction.map(Json))
+        .bind(call_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Best matching category label for the text, if any scores above the threshold
+pub fn best_category_label(
+    text: &str,
+    candidate_labels: &[String],
     zero_shot: &ZeroShotClassificationModel,
+) -> Result<Option<(String, f64)>> {
+    let prediction = zero_shot.predict_multilabel(
+        &[text],
+        candidate_labels
+            .iter()
+            .map(|s| s.as_str())
+            .collect::<Vec<&str>>(),
+        None,
+        128,
+    )?;
+
+    Ok(prediction
+        .iter()
+        .flatten()
+        .filter(|label| label.score > CATEGORY_THRESHOLD)
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+        .map(|label| (label.text.clone(), label.score)))
+}
+
+// Match every transcribed call against the category, recording progress in the `reindex` row
+// of the job. In a dry run the calls are only classified, the gained and lost lists show what
+// a real run would change.
+pub async fn reindex_calls_for_category(
+    pool: &PgPool,
+    app_state: &AppState,
+    job_id: Uuid,
+    category: &Category,
+    dry_run: bool,
 ) -> Result<()> {
-    // Fetch all calls, regardless of categories
+    let candidate_labels = category.candidate_labels();
+    let has_schema = category
+        .extraction_schema
+        .as_ref()
+        .is_some_and(|schema| !schema.is_empty());
+
+    if reindex_cancelled(pool, job_id).await? {
+        return finish_reindex(pool, job_id, ReindexStatus::Cancelled).await;
+    }
 
+    // Fetch all calls, regardless of categories. A previewed new category has id 0, which no
+    // call is assigned to.
     let calls = sqlx::query_as::<_, CallReindex>(
         r#"
-    SELECT id, text, categories FROM call
+    SELECT id, text, translation, language, EXISTS (
+        SELECT 1 FROM call_category WHERE call_id = call.id AND category_id = $1
+    ) AS has_category, EXISTS (
+        SELECT 1 FROM jsonb_each(call.extraction) WHERE value->>'category_id' = $1::text
+    ) AS has_extraction
+    FROM call
+    WHERE text IS NOT NULL
     "#,
     )
+    .bind(category.id)
     .fetch_all(pool)
     .await?;
 
+    // A retried job starts over, so the counters are reset here
+    sqlx::query(
+        r#"
+    UPDATE reindex
+    SET status = $1, total = $2, processed = 0, changed = 0, gained = '{}', lost = '{}',
+        error = NULL, started_at = NOW(), finished_at = NULL
+    WHERE job_id = $3
+    "#,
+    )
+    .bind(ReindexStatus::Running.as_str())
+    .bind(calls.len() as i32)
+    .bind(job_id)
+    .execute(pool)
+    .await?;
+
     // Iterate through the calls and classify them
     for call in calls {
-        let prediction = zero_shot.predict_multilabel(
-            &[call.text.as_str()],
-            candidate_labels
-                .iter()
-                .map(|s| s.as_str())
-                .collect::<Vec<&str>>(),
-            None,
-            128,
-        )?;
-
-        let mut still_belongs = false;
-
-        // Determine if the call belongs to the category
-
-        for label in &prediction[0] {
-            if label.score > 0.89 {
-                still_belongs = true;
-                break;
-            }
+        if reindex_cancelled(pool, job_id).await? {
+            return finish_reindex(pool, job_id, ReindexStatus::Cancelled).await;
         }
 
-        let categories = call.categories.unwrap_or_default();
-        if still_belongs {
-            // If it now belongs, ensure the category is in the call's categories
-            if !categories.contains(&category_title.to_string()) {
-                let _result = sqlx::query(
-                    r#"
-        UPDATE call
-        SET categories = array_append(categories, $1)
-        WHERE id = $2
-        "#,
-                )
-                .bind(category_title)
-                .bind(call.id)
-                .execute(pool)
-                .await?;
-
-                let _category_title = match prev_title {
-                    Some(c) => {
-                        let _result = sqlx::query(
-                            r#"
-        UPDATE call
-        SET categories = array_remove(categories, $1)
-        WHERE id = $2
-        "#,
-                        )
-                        .bind(c)
-                        .bind(call.id)
-                        .execute(pool)
-                        .await?;
-                        c
-                    }
-                    None => category_title,
-                };
+        // Calls in a language no loaded model covers are classified through their
+        // translation, or keep their categories without one
+        let (models, text) = match (app_state.models(call.language.as_deref()), call.translation) {
+            (Some(models), _) => (models, call.text),
+            (None, Some(translation)) => (&app_state.english, translation),
+            (None, None) => {
+                sqlx::query("UPDATE reindex SET processed = processed + 1 WHERE job_id = $1")
+                    .bind(job_id)
+                    .execute(pool)
+                    .await?;
+                continue;
             }
-        } else {
-            let category_title = match prev_title {
-                Some(c) => c,
-                None => category_title,
-            };
-            // If it no longer belongs, remove the category from the call's categories
-            if categories.contains(&category_title.to_string()) {
-                let _result = sqlx::query(
-                    r#"
-        UPDATE call
-        SET categories = array_remove(categories, $1)
-        WHERE id = $2
+        };
+        let labels = candidate_labels.clone();
+        let label_text = text.clone();
+        let best_label = models
+            .zero_shot
+            .run(move |zero_shot| best_category_label(&label_text, &labels, zero_shot))
+            .await??;
+
+        let gained = best_label.is_some() && !call.has_category;
+        let lost = best_label.is_none() && call.has_category;
+
+        if !dry_run {
+            match &best_label {
+                // If it belongs, store the category with its current score
+                Some((matched_label, score)) => {
+                    sqlx::query(
+                        r#"
+        INSERT INTO call_category (call_id, category_id, score, matched_label, source, model_version)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (call_id, category_id) DO UPDATE
+        SET score = EXCLUDED.score, matched_label = EXCLUDED.matched_label,
+            source = EXCLUDED.source, model_version = EXCLUDED.model_version
         "#,
-                )
-                .bind(category_title)
-                .bind(call.id)
-                .execute(pool)
-                .await?;
+                    )
+                    .bind(call.id)
+                    .bind(category.id)
+                    .bind(score)
+                    .bind(matched_label)
+                    .bind(CategorySource::Reindex.as_str())
+                    .bind(models.zero_shot_name)
+                    .execute(pool)
+                    .await?;
+                }
+                // If it no longer belongs, remove the category from the call's categories
+                None if lost => {
+                    sqlx::query(
+                        "DELETE FROM call_category WHERE call_id = $1 AND category_id = $2",
+                    )
+                    .bind(call.id)
+                    .bind(category.id)
+                    .execute(pool)
+                    .await?;
+                }
+                None => {}
+            }
+            if gained || lost {
+                let data = serde_json::json!({
+                    "call_id": call.id,
+                    "category_id": category.id,
+                    "category_title": category.title,
+                    "change": if gained { "gained" } else { "lost" },
+                    "reindex_job_id": job_id,
+                });
+                dispatch(pool, WebhookEvent::CallCategoriesChanged, data).await?;
+            }
+            // The answers to the category's schema follow its assignment, and the schema
+            // itself may have changed since they were given
+            let assigned = best_label.is_some() || lost;
+            if (has_schema && assigned) || call.has_extraction {
+                refill_extraction(pool, models, call.id, text).await?;
             }
         }
+
+        sqlx::query(
+            r#"
+    UPDATE reindex
+    SET processed = processed + 1,
+        changed = changed + CASE WHEN $1 OR $2 THEN 1 ELSE 0 END,
+        gained = CASE WHEN $1 THEN array_append(gained, $3) ELSE gained END,
+        lost = CASE WHEN $2 THEN array_append(lost, $3) ELSE lost END
+    WHERE job_id = $4
+    "#,
+        )
+        .bind(gained)
+        .bind(lost)
+        .bind(call.id)
+        .bind(job_id)
+        .execute(pool)
+        .await?;
     }
 
+    finish_reindex(pool, job_id, ReindexStatus::Done).await
+}
+
+// A reindex stops when it was cancelled or its category was deleted
+pub async fn reindex_cancelled(pool: &PgPool, job_id: Uuid) -> Result<bool> {
+    let cancelled = sqlx::query_scalar::<_, bool>(
+        r#"
+    SELECT cancel_requested OR (NOT dry_run AND category_id IS NULL)
+    FROM reindex
+    WHERE job_id = $1
+    "#,
+    )
+    .bind(job_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(cancelled.unwrap_or(true))
+}
+
+pub async fn finish_reindex(pool: &PgPool, job_id: Uuid, status: ReindexStatus) -> Result<()> {
+    sqlx::query("UPDATE reindex SET status = $1, finished_at = NOW() WHERE job_id = $2")
+        .bind(status.as_str())
+        .bind(job_id)
+        .execute(pool)
+        .await?;
     Ok(())
 }
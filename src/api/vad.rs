@@ -0,0 +1,210 @@
+use std::ops::Range;
+
+use super::audio::SAMPLE_RATE;
+use crate::config::env_or;
+
+// 30 ms frames
+const FRAME_LEN: usize = SAMPLE_RATE as usize * 30 / 1000;
+// Quietest frames taken as the background noise level
+const NOISE_PERCENTILE: f64 = 0.1;
+// Frames below this are silence whatever the noise level
+const MIN_SPEECH_DB: f32 = -55.0;
+// Speech kept around every detected region so word edges aren't cut
+const PADDING_MS: usize = 200;
+// Longer pauses end a chunk, Whisper tends to make up text in long silences
+const MAX_GAP_MS: usize = 2000;
+
+pub struct VadConfig {
+    // Longest chunk handed to Whisper in one piece
+    pub max_chunk_ms: usize,
+    // Pauses shorter than this don't split speech
+    pub min_silence_ms: usize,
+    // How far above the noise floor a frame has to be to count as speech
+    pub threshold_db: f32,
+}
+
+impl VadConfig {
+    pub fn from_env() -> Self {
+        Self {
+            max_chunk_ms: env_or("CHUNK_MAX_SECS", 30usize).max(1) * 1000,
+            min_silence_ms: env_or("VAD_MIN_SILENCE_MS", 500),
+            threshold_db: env_or("VAD_THRESHOLD_DB", 9.0),
+        }
+    }
+}
+
+// Split audio at SAMPLE_RATE into ranges of speech no longer than `max_chunk_ms`. Silence
+// between the ranges is left out. Chunks are cut in pauses whenever possible and only split
+// mid-speech, at the quietest frame, when nobody pauses for too long.
+pub fn speech_chunks(samples: &[f32], config: &VadConfig) -> Vec<Range<usize>> {
+    let energies: Vec<f32> = samples
+        .chunks(FRAME_LEN)
+        .map(|frame| {
+            let power =
+                frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32;
+            10.0 * (power + 1e-12).log10()
+        })
+        .collect();
+    if energies.is_empty() {
+        return Vec::new();
+    }
+
+    let mut sorted = energies.clone();
+    sorted.sort_by(f32::total_cmp);
+    let noise_floor = sorted[((sorted.len() - 1) as f64 * NOISE_PERCENTILE) as usize];
+    let threshold = (noise_floor + config.threshold_db).max(MIN_SPEECH_DB);
+
+    // Speech regions in frames, pauses shorter than min_silence_ms are bridged
+    let min_silence = frames(config.min_silence_ms).max(1);
+    let mut regions: Vec<Range<usize>> = Vec::new();
+    for (frame, energy) in energies.iter().enumerate() {
+        if *energy < threshold {
+            continue;
+        }
+        match regions.last_mut() {
+            Some(region) if frame - region.end < min_silence => region.end = frame + 1,
+            _ => regions.push(frame..frame + 1),
+        }
+    }
+
+    let padding = frames(PADDING_MS);
+    let max_chunk = frames(config.max_chunk_ms).max(1);
+    let max_gap = frames(MAX_GAP_MS);
+    let mut chunks: Vec<Range<usize>> = Vec::new();
+    for region in regions {
+        // Padding must not reach back into the previous chunk, or the audio in between would
+        // be transcribed twice
+        let start = region
+            .start
+            .saturating_sub(padding)
+            .max(chunks.last().map_or(0, |chunk| chunk.end));
+        let end = (region.end + padding).min(energies.len());
+        match chunks.last_mut() {
+            // The next region fits into the current chunk, the pause between them goes along
+            Some(chunk) if end - chunk.start <= max_chunk && start <= chunk.end + max_gap => {
+                chunk.end = end
+            }
+            _ => split_region(start..end, max_chunk, &energies, &mut chunks),
+        }
+    }
+
+    chunks
+        .into_iter()
+        .map(|chunk| chunk.start * FRAME_LEN..(chunk.end * FRAME_LEN).min(samples.len()))
+        .collect()
+}
+
+// Add a region to the chunks, cut at the quietest frame of the second half of every
+// max_chunk window when it is too long
+fn split_region(
+    mut region: Range<usize>,
+    max_chunk: usize,
+    energies: &[f32],
+    chunks: &mut Vec<Range<usize>>,
+) {
+    while region.end - region.start > max_chunk {
+        let window = region.start + max_chunk / 2..region.start + max_chunk;
+        let cut = window
+            .clone()
+            .min_by(|a, b| energies[*a].total_cmp(&energies[*b]))
+            .unwrap_or(window.end);
+        chunks.push(region.start..cut);
+        region.start = cut;
+    }
+    chunks.push(region);
+}
+
+fn frames(ms: usize) -> usize {
+    ms * SAMPLE_RATE as usize / 1000 / FRAME_LEN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEECH: f32 = 0.3;
+    const NOISE: f32 = 0.001;
+
+    fn config(max_chunk_ms: usize, min_silence_ms: usize) -> VadConfig {
+        VadConfig {
+            max_chunk_ms,
+            min_silence_ms,
+            threshold_db: 9.0,
+        }
+    }
+
+    // A 440 Hz tone of the given amplitude
+    fn tone(amplitude: f32, ms: usize) -> impl Iterator<Item = f32> {
+        (0..ms * SAMPLE_RATE as usize / 1000).map(move |n| {
+            amplitude * (2.0 * std::f32::consts::PI * 440.0 * n as f32 / SAMPLE_RATE as f32).sin()
+        })
+    }
+
+    fn assert_valid(chunks: &[Range<usize>], samples: usize, max_chunk_ms: usize) {
+        let max_chunk = frames(max_chunk_ms) * FRAME_LEN;
+        for chunk in chunks {
+            assert!(chunk.start < chunk.end, "empty chunk {:?}", chunk);
+            assert!(chunk.end <= samples, "chunk {:?} past the end", chunk);
+            assert!(chunk.len() <= max_chunk, "chunk {:?} too long", chunk);
+        }
+        for pair in chunks.windows(2) {
+            assert!(pair[0].end <= pair[1].start, "{:?} overlaps", pair);
+        }
+    }
+
+    #[test]
+    fn test_speech_chunks_silence() {
+        let samples = vec![0.0; SAMPLE_RATE as usize * 5];
+        assert!(speech_chunks(&samples, &config(30_000, 500)).is_empty());
+    }
+
+    #[test]
+    fn test_speech_chunks_pause() {
+        let samples: Vec<f32> = tone(SPEECH, 2000)
+            .chain(tone(NOISE, 3000))
+            .chain(tone(SPEECH, 2000))
+            .collect();
+
+        let chunks = speech_chunks(&samples, &config(30_000, 500));
+        assert_valid(&chunks, samples.len(), 30_000);
+        // The pause is longer than MAX_GAP_MS, each turn is a chunk of its own, padded and
+        // rounded to frames
+        let padding = (frames(PADDING_MS) + 1) * FRAME_LEN;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].start, 0);
+        assert!(chunks[0].end >= 32_000 && chunks[0].end <= 32_000 + padding);
+        assert!(chunks[1].start >= 80_000 - padding && chunks[1].start <= 80_000);
+        assert_eq!(chunks[1].end, samples.len());
+    }
+
+    #[test]
+    fn test_speech_chunks_short_pauses_dont_overlap() {
+        // Pauses shorter than twice the padding, with bridging off
+        let samples: Vec<f32> = tone(SPEECH, 900)
+            .chain(tone(NOISE, 300))
+            .chain(tone(SPEECH, 900))
+            .chain(tone(NOISE, 300))
+            .chain(tone(SPEECH, 900))
+            .chain(tone(NOISE, 1000))
+            .collect();
+
+        let chunks = speech_chunks(&samples, &config(1000, 100));
+        assert!(chunks.len() > 1);
+        assert_valid(&chunks, samples.len(), 1000);
+    }
+
+    #[test]
+    fn test_speech_chunks_split_at_quietest_frame() {
+        // Continuous speech with one quieter frame, the 41st, then a second of background noise
+        let dip = 40 * FRAME_LEN;
+        let mut samples: Vec<f32> = tone(SPEECH, 2500).chain(tone(NOISE, 1000)).collect();
+        samples[dip..dip + FRAME_LEN]
+            .iter_mut()
+            .for_each(|sample| *sample /= 3.0);
+
+        let chunks = speech_chunks(&samples, &config(2000, 500));
+        assert_valid(&chunks, samples.len(), 2000);
+        assert_eq!(chunks[0], 0..dip);
+        assert_eq!(chunks[1].start, dip);
+    }
+}
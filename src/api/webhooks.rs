@@ -0,0 +1,596 @@
+use std::time::Duration;
+
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use super::download::{public_client, validate_public_url};
+use super::models::{
+    CreateWebhook, CreatedWebhook, DeliveryStatus, UpdateWebhook, Webhook, WebhookDelivery,
+    WebhookEvent,
+};
+use super::worker::{Task, Workers};
+use crate::config::env_or;
+use crate::db::establish_connection;
+use crate::db::queue;
+use crate::errors::{AppError, AppResult, DownloadError};
+
+const SELECT_WEBHOOK: &str = "SELECT id, url, secret, event_types, active, created_at FROM webhook";
+
+// Deliveries returned by GET /webhooks/{id}/deliveries, newest first
+const DELIVERY_PAGE: i64 = 100;
+
+#[derive(Clone, Copy)]
+pub struct WebhookConfig {
+    // Webhooks may point at private addresses only when the receivers run next to the server
+    pub allow_private: bool,
+    pub timeout: Duration,
+}
+
+impl WebhookConfig {
+    pub fn from_env() -> Self {
+        Self {
+            allow_private: env_or("WEBHOOK_ALLOW_PRIVATE", false),
+            timeout: Duration::from_secs(env_or("WEBHOOK_TIMEOUT_SECS", 10)),
+        }
+    }
+}
+
+// Get all webhooks
+#[get("/webhooks")]
+pub async fn get_webhooks(pool: web::Data<PgPool>) -> AppResult<impl Responder> {
+    let webhooks = sqlx::query_as::<_, Webhook>(&format!("{} ORDER BY id", SELECT_WEBHOOK))
+        .fetch_all(pool.get_ref())
+        .await?;
+
+    Ok(HttpResponse::Ok().json(webhooks))
+}
+
+// Subscribe a URL to events. The response holds the secret the payloads are signed with, it
+// isn't returned again.
+#[post("/webhooks")]
+pub async fn create_webhook(
+    pool: web::Data<PgPool>,
+    config: web::Data<WebhookConfig>,
+    new_webhook: web::Json<CreateWebhook>,
+) -> AppResult<impl Responder> {
+    let new_webhook = new_webhook.into_inner();
+    validate_webhook(
+        &config,
+        Some(&new_webhook.url),
+        Some(&new_webhook.event_types),
+    )
+    .await?;
+    let secret = new_webhook
+        .secret
+        .filter(|secret| !secret.is_empty())
+        .unwrap_or_else(|| format!("whsec_{}", Uuid::new_v4().simple()));
+
+    let webhook = sqlx::query_as::<_, Webhook>(
+        r#"
+    INSERT INTO webhook (url, secret, event_types, active)
+    VALUES ($1, $2, $3, COALESCE($4, TRUE))
+    RETURNING id, url, secret, event_types, active, created_at
+    "#,
+    )
+    .bind(&new_webhook.url)
+    .bind(&secret)
+    .bind(&new_webhook.event_types)
+    .bind(new_webhook.active)
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(CreatedWebhook { webhook, secret }))
+}
+
+#[get("/webhooks/{id}")]
+pub async fn get_webhook(pool: web::Data<PgPool>, id: web::Path<i32>) -> AppResult<impl Responder> {
+    match fetch_webhook(pool.get_ref(), *id).await? {
+        Some(webhook) => Ok(HttpResponse::Ok().json(webhook)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// Update a webhook, missing fields are left as they are
+#[put("/webhooks/{id}")]
+pub async fn update_webhook(
+    pool: web::Data<PgPool>,
+    config: web::Data<WebhookConfig>,
+    id: web::Path<i32>,
+    updated_webhook: web::Json<UpdateWebhook>,
+) -> AppResult<impl Responder> {
+    validate_webhook(
+        &config,
+        updated_webhook.url.as_deref(),
+        updated_webhook.event_types.as_deref(),
+    )
+    .await?;
+
+    let webhook = sqlx::query_as::<_, Webhook>(
+        r#"
+    UPDATE webhook
+    SET url = COALESCE($1, url), secret = COALESCE($2, secret),
+        event_types = COALESCE($3, event_types), active = COALESCE($4, active)
+    WHERE id = $5
+    RETURNING id, url, secret, event_types, active, created_at
+    "#,
+    )
+    .bind(&updated_webhook.url)
+    .bind(
+        updated_webhook
+            .secret
+            .as_ref()
+            .filter(|secret| !secret.is_empty()),
+    )
+    .bind(&updated_webhook.event_types)
+    .bind(updated_webhook.active)
+    .bind(*id)
+    .fetch_optional(pool.get_ref())
+    .await?;
+
+    match webhook {
+        Some(webhook) => Ok(HttpResponse::Ok().json(webhook)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// Delete a webhook together with its delivery log
+#[delete("/webhooks/{id}")]
+pub async fn delete_webhook(
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> AppResult<impl Responder> {
+    let result = sqlx::query("DELETE FROM webhook WHERE id = $1")
+        .bind(*id)
+        .execute(pool.get_ref())
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Latest deliveries of a webhook with the outcome of their last attempt
+#[get("/webhooks/{id}/deliveries")]
+pub async fn get_deliveries(
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> AppResult<impl Responder> {
+    if fetch_webhook(pool.get_ref(), *id).await?.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+    SELECT * FROM webhook_delivery
+    WHERE webhook_id = $1
+    ORDER BY created_at DESC
+    LIMIT $2
+    "#,
+    )
+    .bind(*id)
+    .bind(DELIVERY_PAGE)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(deliveries))
+}
+
+// Send an earlier event again, e.g. once a receiver is fixed. The redelivery is logged as a new
+// delivery with the same event id.
+#[post("/webhooks/{id}/deliveries/{delivery_id}/redeliver")]
+pub async fn redeliver(
+    workers: web::Data<Workers>,
+    pool: web::Data<PgPool>,
+    path: web::Path<(i32, Uuid)>,
+) -> AppResult<impl Responder> {
+    let (webhook_id, delivery_id) = path.into_inner();
+    let mut tx = pool.begin().await?;
+
+    let delivery = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+    INSERT INTO webhook_delivery (id, webhook_id, event_id, event_type, payload, status)
+    SELECT $1, webhook_id, event_id, event_type, payload, $2
+    FROM webhook_delivery
+    WHERE id = $3 AND webhook_id = $4
+    RETURNING *
+    "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(DeliveryStatus::Pending.as_str())
+    .bind(delivery_id)
+    .bind(webhook_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(delivery) = delivery else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    enqueue_delivery(&mut tx, delivery.id).await?;
+    tx.commit().await?;
+    workers.wake();
+
+    Ok(HttpResponse::Ok().json(delivery))
+}
+
+// Send a `ping` event to check the receiver and its signature verification. It goes out even
+// when the webhook is inactive or filters it.
+#[post("/webhooks/{id}/ping")]
+pub async fn ping_webhook(
+    workers: web::Data<Workers>,
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> AppResult<impl Responder> {
+    if fetch_webhook(pool.get_ref(), *id).await?.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let mut tx = pool.begin().await?;
+    let event_id = Uuid::new_v4();
+    let payload = event_payload(event_id, WebhookEvent::Ping, json!({ "webhook_id": *id }));
+    let delivery = insert_delivery(&mut tx, *id, event_id, WebhookEvent::Ping, &payload).await?;
+    enqueue_delivery(&mut tx, delivery.id).await?;
+    tx.commit().await?;
+    workers.wake();
+
+    Ok(HttpResponse::Ok().json(delivery))
+}
+
+async fn fetch_webhook(pool: &PgPool, id: i32) -> Result<Option<Webhook>, sqlx::Error> {
+    sqlx::query_as::<_, Webhook>(&format!("{} WHERE id = $1", SELECT_WEBHOOK))
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+// Event types are checked first, they don't need the network
+async fn validate_webhook(
+    config: &WebhookConfig,
+    url: Option<&str>,
+    event_types: Option<&[String]>,
+) -> AppResult<()> {
+    let unknown = event_types
+        .unwrap_or_default()
+        .iter()
+        .find(|event_type| WebhookEvent::parse(event_type).is_none());
+    if let Some(event_type) = unknown {
+        return Err(AppError::Invalid(format!(
+            "unknown event type {}, expected one of {}",
+            event_type,
+            WebhookEvent::ALL.map(|event| event.as_str()).join(", ")
+        )));
+    }
+    if let Some(url) = url {
+        validate_public_url(url, config.allow_private)
+            .await
+            .map_err(|err| {
+                let reason = match err {
+                    DownloadError::InvalidUrl(reason) | DownloadError::Blocked(reason) => reason,
+                    err => err.to_string(),
+                };
+                AppError::Invalid(format!("invalid webhook URL: {}", reason))
+            })?;
+    }
+    Ok(())
+}
+
+// Body sent to the receivers
+fn event_payload(
+    event_id: Uuid,
+    event: WebhookEvent,
+    data: serde_json::Value,
+) -> serde_json::Value {
+    json!({
+        "id": event_id,
+        "type": event.as_str(),
+        "created_at": Utc::now(),
+        "data": data,
+    })
+}
+
+// Queue an event for every active webhook subscribed to it. Deliveries and their jobs are
+// stored in one transaction, so an event reaches either all receivers or none.
+pub async fn dispatch(
+    pool: &PgPool,
+    event: WebhookEvent,
+    data: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let webhook_ids = sqlx::query_scalar::<_, i32>(
+        r#"
+    SELECT id FROM webhook
+    WHERE active AND (cardinality(event_types) = 0 OR $1 = ANY(event_types))
+    "#,
+    )
+    .bind(event.as_str())
+    .fetch_all(&mut *tx)
+    .await?;
+    if webhook_ids.is_empty() {
+        return Ok(());
+    }
+
+    let event_id = Uuid::new_v4();
+    let payload = event_payload(event_id, event, data);
+    for webhook_id in webhook_ids {
+        let delivery = insert_delivery(&mut tx, webhook_id, event_id, event, &payload).await?;
+        enqueue_delivery(&mut tx, delivery.id).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn insert_delivery(
+    conn: &mut PgConnection,
+    webhook_id: i32,
+    event_id: Uuid,
+    event: WebhookEvent,
+    payload: &serde_json::Value,
+) -> Result<WebhookDelivery, sqlx::Error> {
+    sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+    INSERT INTO webhook_delivery (id, webhook_id, event_id, event_type, payload, status)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    RETURNING *
+    "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(webhook_id)
+    .bind(event_id)
+    .bind(event.as_str())
+    .bind(payload)
+    .bind(DeliveryStatus::Pending.as_str())
+    .fetch_one(conn)
+    .await
+}
+
+// Deliveries are jobs of their own, so failed ones are retried with the queue's exponential
+// backoff
+async fn enqueue_delivery(conn: &mut PgConnection, delivery_id: Uuid) -> Result<Uuid, sqlx::Error> {
+    let task = Task::Webhook { delivery_id };
+    let max_attempts = env_or("WEBHOOK_MAX_ATTEMPTS", 8i32).max(1);
+    queue::enqueue(conn, task.kind(), &task, max_attempts).await
+}
+
+#[derive(sqlx::FromRow)]
+struct DeliveryTarget {
+    webhook_id: i32,
+    url: String,
+    secret: String,
+    event_type: String,
+    payload: serde_json::Value,
+}
+
+// Send a delivery once and log the outcome on its row. A failure is returned so the job is
+// retried, 4xx responses other than 408 and 429 aren't.
+pub async fn deliver(
+    pool: &PgPool,
+    config: &WebhookConfig,
+    delivery_id: Uuid,
+) -> anyhow::Result<()> {
+    let target = sqlx::query_as::<_, DeliveryTarget>(
+        r#"
+    SELECT webhook.id AS webhook_id, webhook.url, webhook.secret, delivery.event_type,
+        delivery.payload
+    FROM webhook_delivery delivery
+    JOIN webhook ON webhook.id = delivery.webhook_id
+    WHERE delivery.id = $1
+    "#,
+    )
+    .bind(delivery_id)
+    .fetch_optional(pool)
+    .await?;
+    // The webhook was deleted together with its deliveries
+    let Some(target) = target else {
+        return Ok(());
+    };
+
+    let result = send(config, delivery_id, &target).await;
+    let (status, response_status, error) = match &result {
+        Ok(response_status) => (DeliveryStatus::Delivered, Some(*response_status), None),
+        Err(err) => {
+            let response_status = match err {
+                DownloadError::Status(status) => Some(*status),
+                _ => None,
+            };
+            (
+                DeliveryStatus::Pending,
+                response_status,
+                Some(err.to_string()),
+            )
+        }
+    };
+    sqlx::query(
+        r#"
+    UPDATE webhook_delivery
+    SET status = $1, attempts = attempts + 1, response_status = $2, error = $3,
+        delivered_at = CASE WHEN $1 = 'delivered' THEN NOW() END
+    WHERE id = $4
+    "#,
+    )
+    .bind(status.as_str())
+    .bind(response_status.map(i32::from))
+    .bind(error)
+    .bind(delivery_id)
+    .execute(pool)
+    .await?;
+
+    result.map(|_| ()).map_err(Into::into)
+}
+
+// Mark a delivery failed once its job ran out of attempts
+pub async fn fail_delivery(pool: &PgPool, delivery_id: Uuid, reason: &str) -> anyhow::Result<()> {
+    sqlx::query("UPDATE webhook_delivery SET status = $1, error = $2 WHERE id = $3")
+        .bind(DeliveryStatus::Failed.as_str())
+        .bind(reason)
+        .bind(delivery_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// POST the payload, returns the response status
+async fn send(
+    config: &WebhookConfig,
+    delivery_id: Uuid,
+    target: &DeliveryTarget,
+) -> Result<u16, DownloadError> {
+    // Checked again, the host may resolve differently since the webhook was saved
+    let url = validate_public_url(&target.url, config.allow_private).await?;
+    let client = public_client(config.allow_private, config.timeout)?;
+
+    let body = serde_json::to_vec(&target.payload).unwrap_or_default();
+    let timestamp = Utc::now().timestamp();
+    let response = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Id", target.webhook_id.to_string())
+        .header("X-Webhook-Event", &target.event_type)
+        .header("X-Webhook-Delivery", delivery_id.to_string())
+        .header("X-Webhook-Timestamp", timestamp.to_string())
+        .header(
+            "X-Webhook-Signature",
+            signature(&target.secret, timestamp, &body),
+        )
+        .body(body)
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(DownloadError::Status(status.as_u16()));
+    }
+    Ok(status.as_u16())
+}
+
+// `sha256=` and the hex HMAC-SHA256 of `{timestamp}.{body}`, keyed with the webhook secret.
+// Signing the timestamp lets receivers reject replayed requests.
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+use actix_web::{http::StatusCode, test, App};
+use std::collections::HashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Test POST /webhooks with an event type that doesn't exist
+#[actix_web::test]
+async fn test_create_webhook_unknown_event() {
+    let pool = establish_connection().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(WebhookConfig::from_env()))
+            .service(create_webhook),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/webhooks")
+        .set_json(json!({ "url": "https://example.com/hook", "event_types": ["call.lost"] }))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error: String = test::read_body_json(resp).await;
+    assert!(error.contains("call.lost"), "{}", error);
+}
+
+// Test POST /webhooks/{id}/ping against a local receiver
+#[actix_web::test]
+async fn test_ping_webhook() {
+    let config = WebhookConfig {
+        allow_private: true,
+        ..WebhookConfig::from_env()
+    };
+    let pool = establish_connection().await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    // Accepts a single request and answers 200 with its lowercased headers and body
+    let receiver = actix_web::rt::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        let header_end = loop {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+
+        let headers = String::from_utf8_lossy(&request[..header_end])
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect::<HashMap<_, _>>();
+        let length = headers["content-length"].parse::<usize>().unwrap();
+        while request.len() < header_end + length {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        (headers, request[header_end..header_end + length].to_vec())
+    });
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Workers::new()))
+            .app_data(web::Data::new(config))
+            .service(create_webhook)
+            .service(ping_webhook),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/webhooks")
+        .set_json(json!({ "url": url, "secret": "test-secret", "event_types": ["call.done"] }))
+        .to_request();
+    let webhook: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let webhook_id = webhook["id"].as_i64().unwrap();
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/webhooks/{}/ping", webhook_id))
+        .to_request();
+    let delivery: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let delivery_id = delivery["id"].as_str().unwrap().parse::<Uuid>().unwrap();
+
+    deliver(&pool, &config, delivery_id).await.unwrap();
+    let (headers, body) = receiver.await.unwrap();
+    let timestamp = headers["x-webhook-timestamp"].parse::<i64>().unwrap();
+    assert_eq!(headers["x-webhook-event"], "ping");
+    assert_eq!(
+        headers["x-webhook-signature"],
+        signature("test-secret", timestamp, &body)
+    );
+
+    let status =
+        sqlx::query_scalar::<_, String>("SELECT status FROM webhook_delivery WHERE id = $1")
+            .bind(delivery_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(status, DeliveryStatus::Delivered.as_str());
+
+    sqlx::query("DELETE FROM webhook WHERE id = $1")
+        .bind(webhook_id as i32)
+        .execute(&pool)
+        .await
+        .unwrap();
+}
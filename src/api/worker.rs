@@ -0,0 +1,1284 @@
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{PgPool, Postgres};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use super::audio::{decode_audio, DecodedAudio, SAMPLE_RATE};
+use super::call::fetch_call;
+use super::diarization::{diarize, DiarizationMethod};
+use super::download::{download_audio_file, max_duration};
+use super::entities::{extract_entities, summarize, EntitySpan};
+use super::events::{publish, CallEvent};
+use super::gazetteer::Place;
+use super::identifiers::{self, classify, find_identifiers};
+use super::models::{
+    CallStatus, Category, CategoryMatch, CategorySource, CreateCategory, EntityType, ReindexStatus,
+    WebhookEvent,
+};
+use super::registry::link_entities;
+use super::stream::fail_abandoned;
+use super::utils::{
+    categories, detect_language, emotional_tone, extract_fields, finish_reindex,
+    reindex_calls_for_category, transcribe_audio, Segment, Transcript,
+};
+use super::vad::{speech_chunks, VadConfig};
+use super::webhooks::{deliver, dispatch, fail_delivery, WebhookConfig};
+use crate::ai_config::AppState;
+use crate::config::env_or;
+use crate::db::queue::{self, Job, JobStatus};
+use crate::errors::{DecodeError, DownloadError, PanicError};
+
+// Work stored in the `job` table. The `kind` column mirrors the tag of the payload.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Task {
+    // Download, transcribe and analyze a call submitted through POST /api/call
+    Call {
+        call_id: Uuid,
+        audio_url: String,
+        // Whisper language code requested on submission, detected when missing
+        #[serde(default)]
+        language: Option<String>,
+    },
+    // Re-run zero-shot classification of every call against a created or updated category.
+    // A proposed category makes it a dry run, see `api::reindex`.
+    Reindex {
+        category_id: Option<i32>,
+        #[serde(default)]
+        proposed: Option<CreateCategory>,
+    },
+    // Send an event to a webhook, see `api::webhooks`
+    Webhook {
+        delivery_id: Uuid,
+    },
+}
+
+impl Task {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Call { .. } => "call",
+            Self::Reindex { .. } => "reindex",
+            Self::Webhook { .. } => "webhook",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct WorkerConfig {
+    workers: usize,
+    poll_interval: Duration,
+    visibility_timeout: Duration,
+    max_attempts: i32,
+    backoff_base: Duration,
+    backoff_max: Duration,
+}
+
+impl WorkerConfig {
+    fn from_env() -> Self {
+        Self {
+            workers: env_or("JOB_WORKERS", 2usize).max(1),
+            poll_interval: Duration::from_millis(env_or("JOB_POLL_INTERVAL_MS", 1000)),
+            visibility_timeout: Duration::from_secs(env_or("JOB_VISIBILITY_TIMEOUT_SECS", 300)),
+            max_attempts: env_or("JOB_MAX_ATTEMPTS", 5i32).max(1),
+            backoff_base: Duration::from_secs(env_or("JOB_BACKOFF_BASE_SECS", 5)),
+            backoff_max: Duration::from_secs(env_or("JOB_BACKOFF_MAX_SECS", 3600)),
+        }
+    }
+
+    // Exponential backoff: base, 2 * base, 4 * base, ... capped at backoff_max
+    fn backoff(&self, attempts: i32) -> Duration {
+        let exponent = attempts.saturating_sub(1).clamp(0, 20) as u32;
+        self.backoff_base
+            .saturating_mul(2u32.pow(exponent))
+            .min(self.backoff_max)
+    }
+}
+
+// Handle to the background workers polling the `job` table. Every instance sharing the
+// database runs its own workers, jobs are split between them by the queue.
+#[derive(Clone)]
+pub struct Workers {
+    config: WorkerConfig,
+    notify: Arc<Notify>,
+}
+
+impl Workers {
+    // Handle that only enqueues jobs, they are run by `run_now` or by a server's workers
+    pub fn new() -> Self {
+        Self {
+            config: WorkerConfig::from_env(),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn start(pool: PgPool, app_state: AppState) -> Self {
+        let workers = Self::new();
+        let instance = Uuid::new_v4();
+
+        for worker in 0..workers.config.workers {
+            let worker_id = format!("{}-{}", instance, worker);
+            let pool = pool.clone();
+            let app_state = app_state.clone();
+            let config = workers.config.clone();
+            let notify = workers.notify.clone();
+            actix_web::rt::spawn(async move {
+                loop {
+                    if let Err(err) = sweep_expired(&pool).await {
+                        log::error!(
+                            "worker {} failed to sweep expired jobs: {:?}",
+                            worker_id,
+                            err
+                        );
+                    }
+                    if let Err(err) = fail_abandoned(&pool).await {
+                        log::error!("worker {} failed to sweep streams: {:?}", worker_id, err);
+                    }
+                    match queue::claim(&pool, &worker_id, config.visibility_timeout).await {
+                        Ok(Some(job)) => {
+                            // Failures are logged and recorded by run_job
+                            let _ = run_job(&pool, &app_state, &config, &worker_id, job).await;
+                        }
+                        // Nothing to do, wait for a local submission or the next poll
+                        Ok(None) => {
+                            tokio::select! {
+                                _ = notify.notified() => {}
+                                _ = tokio::time::sleep(config.poll_interval) => {}
+                            }
+                        }
+                        Err(err) => {
+                            log::error!("worker {} failed to claim a job: {:?}", worker_id, err);
+                            tokio::time::sleep(config.poll_interval).await;
+                        }
+                    }
+                }
+            });
+        }
+
+        workers
+    }
+
+    // Persist a task. Call `wake` once the surrounding transaction is committed.
+    pub async fn enqueue<'e, E>(&self, executor: E, task: &Task) -> Result<Uuid, sqlx::Error>
+    where
+        E: sqlx::Executor<'e, Database = Postgres>,
+    {
+        queue::enqueue(executor, task.kind(), task, self.config.max_attempts).await
+    }
+
+    // Let an idle local worker pick up a freshly enqueued job without waiting for the poll
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    // Claim an enqueued job and run it on the current task instead of waiting for a worker to
+    // poll it. Returns None when another worker took the job first.
+    pub async fn run_now(
+        &self,
+        pool: &PgPool,
+        app_state: &AppState,
+        job_id: Uuid,
+    ) -> Result<Option<JobStatus>> {
+        let worker_id = format!("inline-{}", Uuid::new_v4());
+        let job =
+            queue::claim_job(pool, job_id, &worker_id, self.config.visibility_timeout).await?;
+        match job {
+            Some(job) => Ok(Some(
+                run_job(pool, app_state, &self.config, &worker_id, job).await?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Returns the status the job ended up in
+async fn run_job(
+    pool: &PgPool,
+    app_state: &AppState,
+    config: &WorkerConfig,
+    worker_id: &str,
+    job: Job,
+) -> Result<JobStatus, sqlx::Error> {
+    log::info!(
+        "worker {} running {} job {} (attempt {}/{})",
+        worker_id,
+        job.kind,
+        job.id,
+        job.attempts,
+        job.max_attempts
+    );
+
+    // Keep the lock alive while the job runs so other instances don't take it over
+    let heartbeat = {
+        let job_id = job.id;
+        let pool = pool.clone();
+        let worker_id = worker_id.to_string();
+        let visibility_timeout = config.visibility_timeout;
+        actix_web::rt::spawn(async move {
+            loop {
+                tokio::time::sleep(visibility_timeout / 3).await;
+                if let Err(err) =
+                    queue::heartbeat(&pool, job_id, &worker_id, visibility_timeout).await
+                {
+                    log::warn!("failed to extend lock of job {}: {:?}", job_id, err);
+                }
+            }
+        })
+    };
+
+    let task = serde_json::from_value::<Task>(job.payload.clone());
+    let result = match &task {
+        Ok(task) => {
+            // The task runs on its own tokio task so a panic only fails this job
+            let handle = actix_web::rt::spawn(execute(
+                pool.clone(),
+                app_state.clone(),
+                job.id,
+                task.clone(),
+            ));
+            match handle.await {
+                Ok(result) => result,
+                Err(err) if err.is_panic() => {
+                    let panic = err.into_panic();
+                    Err(PanicError::new(format!("{} job", job.kind), panic.as_ref()).into())
+                }
+                Err(err) => Err(anyhow!("{} job was cancelled: {}", job.kind, err)),
+            }
+        }
+        Err(err) => Err(anyhow!("invalid {} job payload: {}", job.kind, err)),
+    };
+    heartbeat.abort();
+
+    let outcome = match result {
+        Ok(()) => queue::complete(pool, job.id, worker_id)
+            .await
+            .map(|_| JobStatus::Done),
+        Err(err) => {
+            log::error!("{} job {} failed: {:?}", job.kind, job.id, err);
+            let reason = err.to_string();
+            // Panics are deterministic for a given input, retrying would only panic again. The
+            // same goes for audio that was refused, doesn't exist or can't be decoded.
+            let permanent = err.is::<PanicError>()
+                || err.is::<DecodeError>()
+                || err
+                    .downcast_ref::<DownloadError>()
+                    .is_some_and(|err| !err.is_retryable());
+            let backoff = (!permanent).then(|| config.backoff(job.attempts));
+            let status = queue::fail(pool, job.id, worker_id, &reason, backoff).await;
+            if let (Ok(status), Ok(task)) = (&status, &task) {
+                record_failure(pool, job.id, task, *status, &reason).await;
+            }
+            status
+        }
+    };
+
+    match &outcome {
+        Ok(JobStatus::Dead) => log::error!(
+            "{} job {} moved to dead-letter after {} attempt(s)",
+            job.kind,
+            job.id,
+            job.attempts
+        ),
+        Ok(_) => {}
+        Err(err) => log::error!("failed to update job {}: {:?}", job.id, err),
+    }
+    outcome
+}
+
+// Store the error of a failed job on the row it worked on
+async fn record_failure(pool: &PgPool, job_id: Uuid, task: &Task, status: JobStatus, reason: &str) {
+    match task {
+        Task::Call { call_id, .. } => {
+            if let Err(err) = record_call_failure(pool, *call_id, status, reason).await {
+                log::error!("failed to store error of call {}: {:?}", call_id, err);
+            }
+        }
+        Task::Reindex { .. } => {
+            if let Err(err) = record_reindex_failure(pool, job_id, status, reason).await {
+                log::error!("failed to store error of reindex {}: {:?}", job_id, err);
+            }
+        }
+        Task::Webhook { delivery_id } if status == JobStatus::Dead => {
+            if let Err(err) = fail_delivery(pool, *delivery_id, reason).await {
+                log::error!(
+                    "failed to store error of delivery {}: {:?}",
+                    delivery_id,
+                    err
+                );
+            }
+        }
+        Task::Webhook { .. } => {}
+    }
+}
+
+// Dead-letter the jobs whose worker died during their last attempt and fail their rows
+async fn sweep_expired(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let reason = "the worker running the last attempt stopped before finishing it";
+    for job in queue::dead_letter_expired(pool, reason).await? {
+        log::error!(
+            "{} job {} moved to dead-letter after {} attempt(s): {}",
+            job.kind,
+            job.id,
+            job.attempts,
+            reason
+        );
+        match serde_json::from_value::<Task>(job.payload) {
+            Ok(task) => record_failure(pool, job.id, &task, JobStatus::Dead, reason).await,
+            Err(err) => log::error!("invalid {} job payload: {}", job.kind, err),
+        }
+    }
+    Ok(())
+}
+
+async fn execute(pool: PgPool, app_state: AppState, job_id: Uuid, task: Task) -> Result<()> {
+    match task {
+        Task::Call {
+            call_id,
+            audio_url,
+            language,
+        } => process_call(&pool, &app_state, call_id, &audio_url, language.as_deref()).await,
+        Task::Reindex {
+            category_id,
+            proposed,
+        } => process_reindex(&pool, &app_state, job_id, category_id, proposed).await,
+        Task::Webhook { delivery_id } => {
+            deliver(&pool, &WebhookConfig::from_env(), delivery_id).await
+        }
+    }
+}
+
+// Run the whole pipeline for a single call, recording each stage in the `status` column
+async fn process_call(
+    pool: &PgPool,
+    app_state: &AppState,
+    call_id: Uuid,
+    audio_url: &str,
+    language: Option<&str>,
+) -> Result<()> {
+    set_status(pool, call_id, CallStatus::Downloading).await?;
+    // Uploaded audio and files ingested by the CLI are already on disk and kept
+    let (audio_path, downloaded) = match reqwest::Url::parse(audio_url)?.to_file_path() {
+        Ok(path) => (path, false),
+        Err(()) => (download_audio_file(audio_url).await?, true),
+    };
+
+    set_status(pool, call_id, CallStatus::Decoding).await?;
+    let audio = decode(audio_path.clone()).await;
+    // A retry downloads the audio again, the recording isn't left on disk
+    if downloaded {
+        if let Err(err) = tokio::fs::remove_file(&audio_path).await {
+            log::warn!("failed to remove {}: {}", audio_path.display(), err);
+        }
+    }
+    let audio = audio?;
+    sqlx::query(
+        r#"
+    UPDATE call
+    SET codec = $1, sample_rate = $2, channels = $3, duration_secs = $4, updated_at = NOW()
+    WHERE id = $5
+    "#,
+    )
+    .bind(&audio.info.codec)
+    .bind(audio.info.sample_rate as i32)
+    .bind(audio.info.channels as i32)
+    .bind(audio.info.duration_secs)
+    .bind(call_id)
+    .execute(pool)
+    .await?;
+
+    set_status(pool, call_id, CallStatus::Transcribing).await?;
+    let samples = Arc::new(audio.samples);
+    // Silence is skipped and long calls are split so they are transcribed piece by piece
+    let chunks = speech_chunks(&samples, &VadConfig::from_env());
+    let (language, language_probability) = match language {
+        Some(language) => (language.to_string(), None),
+        None => {
+            // Whisper only looks at the first 30 seconds, silence left out
+            let first = chunks.first().cloned().unwrap_or(0..samples.len());
+            let detection_samples = samples.clone();
+            let (language, probability) = app_state
+                .transcriber
+                .run(move |whisper| detect_language(&detection_samples[first], whisper))
+                .await??;
+            let event = CallEvent::Language {
+                call_id,
+                language: language.clone(),
+                probability: Some(probability),
+            };
+            publish(pool, &event).await?;
+            (language, Some(probability))
+        }
+    };
+    let transcript = transcribe_chunks(
+        pool, app_state, call_id, &samples, &chunks, &language, false,
+    )
+    .await?;
+
+    let transcribed = TranscribedAudio {
+        samples,
+        channel_energy: audio.channel_energy,
+        chunks,
+        transcript,
+        language,
+        language_probability,
+    };
+    finish_call(pool, app_state, call_id, transcribed).await
+}
+
+// A transcribed call on its way to diarization, translation and analysis
+struct TranscribedAudio {
+    // Mono at SAMPLE_RATE
+    samples: Arc<Vec<f32>>,
+    channel_energy: Vec<Vec<f32>>,
+    // Ranges of `samples` that were transcribed, the translation covers the same ones
+    chunks: Vec<Range<usize>>,
+    transcript: Transcript,
+    language: String,
+    language_probability: Option<f64>,
+}
+
+// Everything after transcription: diarize, translate when needed, analyze and store the call
+async fn finish_call(
+    pool: &PgPool,
+    app_state: &AppState,
+    call_id: Uuid,
+    transcribed: TranscribedAudio,
+) -> Result<()> {
+    let TranscribedAudio {
+        samples,
+        channel_energy,
+        chunks,
+        transcript,
+        language,
+        language_probability,
+    } = transcribed;
+
+    set_status(pool, call_id, CallStatus::Diarizing).await?;
+    let (diarization, segments) =
+        diarize_segments(samples.clone(), channel_energy, transcript.segments).await?;
+
+    let mut processed = ProcessedCall {
+        text: transcript.text,
+        translation: None,
+        language,
+        language_probability,
+        diarization,
+        speakers: speaker_turns(&segments),
+        segments,
+        emotional_tone: None,
+        name: None,
+        location: None,
+        entities: Vec::new(),
+        categories: Vec::new(),
+        extraction: None,
+    };
+    let models = app_state.models(Some(&processed.language));
+
+    let mut translated_segments = Vec::new();
+    let translate = processed.language != "en"
+        && match env_or("TRANSLATE_TRANSCRIPTS", "unsupported".to_string()).as_str() {
+            "always" => true,
+            "never" => false,
+            _ => models.is_none(),
+        };
+    if translate {
+        set_status(pool, call_id, CallStatus::Translating).await?;
+        let translation = transcribe_chunks(
+            pool,
+            app_state,
+            call_id,
+            &samples,
+            &chunks,
+            &processed.language,
+            true,
+        )
+        .await?;
+        processed.translation = Some(translation.text);
+        translated_segments = translation.segments;
+    }
+
+    set_status(pool, call_id, CallStatus::Analyzing).await?;
+    // Languages without models are analyzed through their English translation. Entities are
+    // extracted segment by segment, each one kept with the stored segment it belongs to.
+    let (models, text, speaker_texts, entity_segments, translation) =
+        match (models, processed.translation.clone()) {
+            (Some(models), _) => {
+                let speaker_texts = processed
+                    .speakers
+                    .iter()
+                    .map(|speaker| speaker.text.clone())
+                    .collect();
+                let entity_segments = processed
+                    .segments
+                    .iter()
+                    .enumerate()
+                    .map(|(position, segment)| (Some(position), segment.text.clone()))
+                    .collect::<Vec<_>>();
+                (
+                    models,
+                    processed.text.clone(),
+                    speaker_texts,
+                    entity_segments,
+                    false,
+                )
+            }
+            (None, Some(translation)) => {
+                let speaker_texts = translated_speaker_texts(
+                    &processed.segments,
+                    &translated_segments,
+                    &processed.speakers,
+                );
+                let entity_segments = translated_segments
+                    .iter()
+                    .map(|translated| {
+                        let position = overlapping_segment(&processed.segments, translated);
+                        (position, translated.text.clone())
+                    })
+                    .collect::<Vec<_>>();
+                (
+                    &app_state.english,
+                    translation,
+                    speaker_texts,
+                    entity_segments,
+                    true,
+                )
+            }
+            (None, None) => {
+                log::warn!(
+                    "no models analyze {} transcripts, call {} is stored without analysis",
+                    processed.language,
+                    call_id
+                );
+                return store_call(pool, call_id, processed, None).await;
+            }
+        };
+    let category = sqlx::query_as::<_, Category>("SELECT * FROM category")
+        .fetch_all(pool)
+        .await?;
+    let schemas = category
+        .iter()
+        .filter_map(|category| Some((category.id, category.extraction_schema.clone()?.0)))
+        .collect::<Vec<_>>();
+    let extraction_text = text.clone();
+
+    // Document numbers, phones, emails and dates are found by rules. Relative dates are
+    // resolved against the day the call was created.
+    let created_at =
+        sqlx::query_scalar::<_, DateTime<Utc>>("SELECT created_at FROM call WHERE id = $1")
+            .bind(call_id)
+            .fetch_one(pool)
+            .await?;
+    let mut identifiers = Vec::new();
+    let mut ambiguous = Vec::new();
+    let mut ambiguous_positions = Vec::new();
+    let mut contexts = Vec::new();
+    for (position, text) in &entity_segments {
+        for candidate in find_identifiers(text, created_at.date_naive()) {
+            if candidate.is_ambiguous() {
+                contexts.push(identifiers::context(text, &candidate.span));
+                ambiguous_positions.push(*position);
+                ambiguous.push(candidate);
+            } else {
+                identifiers.push((*position, candidate.span));
+            }
+        }
+    }
+
+    // The models live on separate threads, so the three analyses run in parallel
+    let sentiment_text = text.clone();
+    let sentiment = models
+        .sentiment
+        .run(move |sentiment| emotional_tone(sentiment_text, sentiment));
+    let entity_texts = entity_segments
+        .iter()
+        .map(|(_, text)| text.clone())
+        .collect::<Vec<_>>();
+    let ner = models
+        .ner
+        .run(move |ner| extract_entities(&entity_texts, ner));
+    let zero_shot = models
+        .zero_shot
+        .run(move |zero_shot| categories(text, category, zero_shot));
+    // Numbers the words around them don't type are left to the zero-shot model
+    let classified = models
+        .zero_shot
+        .run(move |zero_shot| classify(&contexts, ambiguous, zero_shot));
+    let (tone, spans, categories, classified) =
+        tokio::try_join!(sentiment, ner, zero_shot, classified)?;
+    // Define emotional tone
+    processed.emotional_tone = tone?;
+    // Entities found by NER, the call keeps a summary of the names and locations
+    processed.entities = entity_segments
+        .iter()
+        .zip(spans)
+        .flat_map(|((position, _), spans)| {
+            spans.into_iter().map(move |span| SegmentEntity {
+                position: *position,
+                translation,
+                span,
+                place: None,
+            })
+        })
+        .collect();
+    let classified = ambiguous_positions
+        .into_iter()
+        .zip(classified?)
+        .filter_map(|(position, span)| Some((position, span?)));
+    processed.entities.extend(
+        identifiers
+            .into_iter()
+            .chain(classified)
+            .map(|(position, span)| SegmentEntity {
+                position,
+                translation,
+                span,
+                place: None,
+            }),
+    );
+    // Locations are geocoded against the offline gazetteer
+    let locations = processed
+        .entities
+        .iter()
+        .filter(|entity| entity.span.entity_type == EntityType::Location)
+        .map(|entity| entity.span.text.as_str())
+        .collect::<Vec<_>>();
+    let places = app_state
+        .gazetteer
+        .resolve_all(&locations)
+        .into_iter()
+        .map(|place| place.cloned())
+        .collect::<Vec<_>>();
+    for (entity, place) in processed
+        .entities
+        .iter_mut()
+        .filter(|entity| entity.span.entity_type == EntityType::Location)
+        .zip(places)
+    {
+        entity.place = place;
+    }
+    processed.name = summarize(
+        processed.entities.iter().map(|entity| &entity.span),
+        EntityType::Person,
+    );
+    processed.location = summarize(
+        processed.entities.iter().map(|entity| &entity.span),
+        EntityType::Location,
+    );
+    // Parse categories based on text
+    processed.categories = categories?;
+    // Fill the extraction schemas of the matched categories
+    let fields = processed
+        .categories
+        .iter()
+        .filter_map(|matched| schemas.iter().find(|(id, _)| *id == matched.category_id))
+        .flat_map(|(id, schema)| schema.iter().map(|field| (*id, field.clone())))
+        .collect::<Vec<_>>();
+    if !fields.is_empty() {
+        processed.extraction = models
+            .qa
+            .run(move |qa| extract_fields(&extraction_text, fields, qa))
+            .await?;
+    }
+
+    for speaker in processed.speakers.iter_mut() {
+        let spans = processed
+            .entities
+            .iter()
+            .filter(|entity| {
+                entity
+                    .position
+                    .and_then(|position| processed.segments[position].speaker)
+                    == Some(speaker.speaker)
+            })
+            .map(|entity| &entity.span)
+            .collect::<Vec<_>>();
+        speaker.name = summarize(spans.iter().copied(), EntityType::Person);
+        speaker.location = summarize(spans, EntityType::Location);
+    }
+    if let [speaker] = processed.speakers.as_mut_slice() {
+        // A single speaker said the whole transcript, the call's tone is theirs
+        speaker.emotional_tone = processed.emotional_tone.clone();
+    } else {
+        let tones = speaker_texts.into_iter().map(|text| {
+            models
+                .sentiment
+                .run(move |sentiment| emotional_tone(text, sentiment))
+        });
+        let tones = futures_util::future::try_join_all(tones).await?;
+        for (speaker, tone) in processed.speakers.iter_mut().zip(tones) {
+            speaker.emotional_tone = tone?;
+        }
+    }
+
+    store_call(pool, call_id, processed, Some(models.zero_shot_name)).await
+}
+
+// Everything the pipeline found out about a call
+struct ProcessedCall {
+    text: String,
+    // English translation of a transcript in another language
+    translation: Option<String>,
+    language: String,
+    language_probability: Option<f64>,
+    diarization: DiarizationMethod,
+    segments: Vec<Segment>,
+    speakers: Vec<SpeakerTurns>,
+    emotional_tone: Option<String>,
+    name: Option<String>,
+    location: Option<String>,
+    entities: Vec<SegmentEntity>,
+    categories: Vec<CategoryMatch>,
+    // Answers to the extraction schemas of the categories
+    extraction: Option<serde_json::Value>,
+}
+
+// Entity with the position of the segment it was found in
+struct SegmentEntity {
+    position: Option<usize>,
+    // Found in the English translation of the segment
+    translation: bool,
+    span: EntitySpan,
+    // Gazetteer place of a location
+    place: Option<Place>,
+}
+
+// Store the results in one transaction and mark the call done. `zero_shot_model` produced
+// the categories.
+async fn store_call(
+    pool: &PgPool,
+    call_id: Uuid,
+    processed: ProcessedCall,
+    zero_shot_model: Option<&str>,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"
+    UPDATE call
+    SET name = $1, location = $2, emotional_tone = $3, text = $4, translation = $5,
+        language = $6,
+        -- A stream passes on the language it detected, the probability stored then is kept
+        language_probability = CASE
+            WHEN $7::float8 IS NULL AND language = $6 THEN language_probability ELSE $7
+        END,
+        diarization = $8, status = $9, error = NULL,
+        extraction = $10, updated_at = NOW()
+    WHERE id = $11
+    "#,
+    )
+    .bind(processed.name)
+    .bind(processed.location)
+    .bind(processed.emotional_tone)
+    .bind(processed.text)
+    .bind(processed.translation)
+    .bind(processed.language)
+    .bind(processed.language_probability)
+    .bind(processed.diarization.as_str())
+    .bind(CallStatus::Done.as_str())
+    .bind(processed.extraction.map(Json))
+    .bind(call_id)
+    .execute(&mut *tx)
+    .await?;
+
+    // A retried job replaces what an earlier attempt stored
+    sqlx::query("DELETE FROM call_category WHERE call_id = $1")
+        .bind(call_id)
+        .execute(&mut *tx)
+        .await?;
+    for category in processed.categories {
+        sqlx::query(
+            r#"
+    INSERT INTO call_category (call_id, category_id, score, matched_label, source, model_version)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    "#,
+        )
+        .bind(call_id)
+        .bind(category.category_id)
+        .bind(category.score)
+        .bind(category.matched_label)
+        .bind(CategorySource::Pipeline.as_str())
+        .bind(zero_shot_model)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    sqlx::query("DELETE FROM call_segment WHERE call_id = $1")
+        .bind(call_id)
+        .execute(&mut *tx)
+        .await?;
+    let mut segment_ids = Vec::with_capacity(processed.segments.len());
+    for (position, segment) in processed.segments.into_iter().enumerate() {
+        let segment_id = sqlx::query_scalar::<_, i32>(
+            r#"
+    INSERT INTO call_segment (call_id, position, start_ms, end_ms, speaker, text, avg_probability)
+    VALUES ($1, $2, $3, $4, $5, $6, $7)
+    RETURNING id
+    "#,
+        )
+        .bind(call_id)
+        .bind(position as i32)
+        .bind(segment.start_ms)
+        .bind(segment.end_ms)
+        .bind(segment.speaker)
+        .bind(segment.text)
+        .bind(segment.avg_probability)
+        .fetch_one(&mut *tx)
+        .await?;
+        segment_ids.push(segment_id);
+    }
+
+    // Segments were replaced above, their entities went with them
+    sqlx::query("DELETE FROM call_entity WHERE call_id = $1")
+        .bind(call_id)
+        .execute(&mut *tx)
+        .await?;
+    for entity in processed.entities {
+        sqlx::query(
+            r#"
+    INSERT INTO call_entity (call_id, segment_id, entity_type, text, normalized, start_char,
+        end_char, translation, score, geoname_id, place, country_code, admin_region, latitude,
+        longitude)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+    "#,
+        )
+        .bind(call_id)
+        .bind(entity.position.map(|position| segment_ids[position]))
+        .bind(entity.span.entity_type.as_str())
+        .bind(entity.span.text)
+        .bind(entity.span.normalized)
+        .bind(entity.span.start_char as i32)
+        .bind(entity.span.end_char as i32)
+        .bind(entity.translation)
+        .bind(entity.span.score)
+        .bind(entity.place.as_ref().map(|place| place.geoname_id))
+        .bind(entity.place.as_ref().map(|place| &place.name))
+        .bind(entity.place.as_ref().map(|place| &place.country_code))
+        .bind(
+            entity
+                .place
+                .as_ref()
+                .and_then(|place| place.admin_region.as_ref()),
+        )
+        .bind(entity.place.as_ref().map(|place| place.latitude))
+        .bind(entity.place.as_ref().map(|place| place.longitude))
+        .execute(&mut *tx)
+        .await?;
+    }
+    link_entities(&mut tx, call_id).await?;
+
+    sqlx::query("DELETE FROM call_speaker WHERE call_id = $1")
+        .bind(call_id)
+        .execute(&mut *tx)
+        .await?;
+    for speaker in processed.speakers {
+        sqlx::query(
+            r#"
+    INSERT INTO call_speaker (call_id, speaker, talk_time_ms, emotional_tone, name, location)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    "#,
+        )
+        .bind(call_id)
+        .bind(speaker.speaker)
+        .bind(speaker.talk_time_ms)
+        .bind(speaker.emotional_tone)
+        .bind(speaker.name)
+        .bind(speaker.location)
+        .execute(&mut *tx)
+        .await?;
+    }
+    publish(
+        &mut *tx,
+        &CallEvent::Done {
+            call_id,
+            call: None,
+        },
+    )
+    .await?;
+    tx.commit().await?;
+
+    // The call is stored, failing to queue the webhooks must not run the pipeline again
+    if let Err(err) = dispatch_call_done(pool, call_id).await {
+        log::error!("failed to queue webhooks for call {}: {:?}", call_id, err);
+    }
+    Ok(())
+}
+
+async fn dispatch_call_done(pool: &PgPool, call_id: Uuid) -> Result<(), sqlx::Error> {
+    if let Some(call) = fetch_call(pool, call_id).await? {
+        let data = serde_json::to_value(call).unwrap_or_default();
+        dispatch(pool, WebhookEvent::CallDone, data).await?;
+    }
+    Ok(())
+}
+
+async fn process_reindex(
+    pool: &PgPool,
+    app_state: &AppState,
+    job_id: Uuid,
+    category_id: Option<i32>,
+    proposed: Option<CreateCategory>,
+) -> Result<()> {
+    let dry_run = proposed.is_some();
+    let category = match proposed {
+        Some(proposed) => Some(Category {
+            id: category_id.unwrap_or_default(),
+            title: proposed.title,
+            points: proposed.points,
+            extraction_schema: proposed.extraction_schema,
+        }),
+        None => {
+            sqlx::query_as::<_, Category>(
+                "SELECT id, title, points, extraction_schema FROM category WHERE id = $1",
+            )
+            .bind(category_id)
+            .fetch_optional(pool)
+            .await?
+        }
+    };
+
+    // The category was deleted before the job ran, nothing left to index
+    let Some(category) = category else {
+        return finish_reindex(pool, job_id, ReindexStatus::Cancelled).await;
+    };
+
+    reindex_calls_for_category(pool, app_state, job_id, &category, dry_run).await
+}
+
+// Decoding and resampling are CPU heavy, keep them off the async workers
+async fn decode(path: PathBuf) -> Result<DecodedAudio> {
+    let max_duration = max_duration();
+    match tokio::task::spawn_blocking(move || decode_audio(&path, max_duration)).await {
+        Ok(audio) => Ok(audio?),
+        Err(err) if err.is_panic() => {
+            Err(PanicError::new("decoding", err.into_panic().as_ref()).into())
+        }
+        Err(err) => Err(anyhow!("decoding was cancelled: {}", err)),
+    }
+}
+
+// Diarization runs FFTs over the whole call, keep it off the async workers too
+async fn diarize_segments(
+    samples: Arc<Vec<f32>>,
+    channel_energy: Vec<Vec<f32>>,
+    mut segments: Vec<Segment>,
+) -> Result<(DiarizationMethod, Vec<Segment>)> {
+    let diarization = tokio::task::spawn_blocking(move || {
+        let method = diarize(&samples, &channel_energy, &mut segments);
+        (method, segments)
+    });
+    match diarization.await {
+        Ok(diarization) => Ok(diarization),
+        Err(err) if err.is_panic() => {
+            Err(PanicError::new("diarization", err.into_panic().as_ref()).into())
+        }
+        Err(err) => Err(anyhow!("diarization was cancelled: {}", err)),
+    }
+}
+
+// What one speaker said over the call, analyzed like the whole transcript
+struct SpeakerTurns {
+    speaker: i32,
+    talk_time_ms: i32,
+    text: String,
+    emotional_tone: Option<String>,
+    name: Option<String>,
+    location: Option<String>,
+}
+
+fn speaker_turns(segments: &[Segment]) -> Vec<SpeakerTurns> {
+    let mut speakers: Vec<SpeakerTurns> = Vec::new();
+    for segment in segments {
+        let Some(speaker) = segment.speaker else {
+            continue;
+        };
+        let index = match speakers.iter().position(|turns| turns.speaker == speaker) {
+            Some(index) => index,
+            None => {
+                speakers.push(SpeakerTurns {
+                    speaker,
+                    talk_time_ms: 0,
+                    text: String::new(),
+                    emotional_tone: None,
+                    name: None,
+                    location: None,
+                });
+                speakers.len() - 1
+            }
+        };
+        let turns = &mut speakers[index];
+        turns.talk_time_ms += (segment.end_ms - segment.start_ms).max(0);
+        if !turns.text.is_empty() {
+            turns.text.push(' ');
+        }
+        turns.text.push_str(&segment.text);
+    }
+    speakers.sort_by_key(|turns| turns.speaker);
+    speakers
+}
+
+// What each speaker said in the translation. Whisper splits the translation differently, so
+// every translated segment goes to the speaker of the original segment it overlaps most.
+fn translated_speaker_texts(
+    segments: &[Segment],
+    translated: &[Segment],
+    speakers: &[SpeakerTurns],
+) -> Vec<String> {
+    let mut texts = vec![String::new(); speakers.len()];
+    for translated in translated {
+        let speaker = overlapping_segment(segments, translated)
+            .and_then(|position| segments[position].speaker);
+        let Some(index) = speakers
+            .iter()
+            .position(|turns| Some(turns.speaker) == speaker)
+        else {
+            continue;
+        };
+        if !texts[index].is_empty() {
+            texts[index].push(' ');
+        }
+        texts[index].push_str(&translated.text);
+    }
+    texts
+}
+
+// Position of the original segment a translated one overlaps most, None when it overlaps none
+fn overlapping_segment(segments: &[Segment], translated: &Segment) -> Option<usize> {
+    segments
+        .iter()
+        .enumerate()
+        .map(|(position, segment)| {
+            let overlap =
+                segment.end_ms.min(translated.end_ms) - segment.start_ms.max(translated.start_ms);
+            (position, overlap)
+        })
+        .filter(|(_, overlap)| *overlap > 0)
+        .max_by_key(|(_, overlap)| *overlap)
+        .map(|(position, _)| position)
+}
+
+// Transcribe (or translate) chunks of speech on every transcriber replica at once, counting
+// finished chunks on the call. Segment times are shifted back to the position of their chunk.
+async fn transcribe_chunks(
+    pool: &PgPool,
+    app_state: &AppState,
+    call_id: Uuid,
+    samples: &Arc<Vec<f32>>,
+    chunks: &[Range<usize>],
+    language: &str,
+    translate: bool,
+) -> Result<Transcript> {
+    set_progress(pool, call_id, 0, chunks.len()).await?;
+    let mut results = futures_util::stream::iter(chunks.iter().cloned())
+        .map(|chunk| {
+            let samples = samples.clone();
+            let language = language.to_string();
+            let offset_ms = (chunk.start * 1000 / SAMPLE_RATE as usize) as i32;
+            async move {
+                let transcript = app_state
+                    .transcriber
+                    .run(move |whisper| {
+                        transcribe_audio(&samples[chunk], &language, translate, whisper)
+                    })
+                    .await??;
+                anyhow::Ok((offset_ms, transcript))
+            }
+        })
+        .buffered(app_state.transcriber.replicas());
+
+    let mut transcript = Transcript {
+        text: String::new(),
+        segments: Vec::new(),
+    };
+    let mut done = 0;
+    while let Some(result) = results.next().await {
+        let (offset_ms, mut chunk) = result?;
+        for segment in &mut chunk.segments {
+            segment.start_ms += offset_ms;
+            segment.end_ms += offset_ms;
+        }
+        let event = CallEvent::Segments {
+            call_id,
+            translation: translate,
+            segments: chunk.segments.iter().map(Into::into).collect(),
+        };
+        publish(pool, &event).await?;
+        transcript.text.push_str(&chunk.text);
+        transcript.segments.extend(chunk.segments);
+        done += 1;
+        set_progress(pool, call_id, done, chunks.len()).await?;
+    }
+    Ok(transcript)
+}
+
+async fn set_progress(pool: &PgPool, call_id: Uuid, done: usize, total: usize) -> Result<()> {
+    sqlx::query(
+        "UPDATE call SET chunks_done = $1, chunks_total = $2, updated_at = NOW() WHERE id = $3",
+    )
+    .bind(done as i32)
+    .bind(total as i32)
+    .bind(call_id)
+    .execute(pool)
+    .await?;
+    let event = CallEvent::Progress {
+        call_id,
+        chunks_done: done as i32,
+        chunks_total: total as i32,
+    };
+    publish(pool, &event).await?;
+    Ok(())
+}
+
+pub async fn set_status(pool: &PgPool, call_id: Uuid, status: CallStatus) -> Result<()> {
+    sqlx::query("UPDATE call SET status = $1, updated_at = NOW() WHERE id = $2")
+        .bind(status.as_str())
+        .bind(call_id)
+        .execute(pool)
+        .await?;
+    let event = CallEvent::Status {
+        call_id,
+        status: status.as_str().to_string(),
+    };
+    publish(pool, &event).await?;
+    Ok(())
+}
+
+// A call goes back to `queued` while its job waits for a retry and to `failed` once the job
+// is dead-lettered
+async fn record_call_failure(
+    pool: &PgPool,
+    call_id: Uuid,
+    job_status: JobStatus,
+    reason: &str,
+) -> Result<()> {
+    let status = match job_status {
+        JobStatus::Dead => CallStatus::Failed,
+        JobStatus::Pending => CallStatus::Queued,
+        // Another worker owns the job now and reports its own progress
+        JobStatus::Running | JobStatus::Done => return Ok(()),
+    };
+
+    sqlx::query("UPDATE call SET status = $1, error = $2, updated_at = NOW() WHERE id = $3")
+        .bind(status.as_str())
+        .bind(reason)
+        .bind(call_id)
+        .execute(pool)
+        .await?;
+    let event = CallEvent::Failed {
+        call_id,
+        status: status.as_str().to_string(),
+        error: reason.to_string(),
+    };
+    publish(pool, &event).await?;
+    if status == CallStatus::Failed {
+        let data = serde_json::json!({ "call_id": call_id, "error": reason });
+        dispatch(pool, WebhookEvent::CallFailed, data).await?;
+    }
+    Ok(())
+}
+
+// Same as `record_call_failure` for the progress row of a reindex job
+async fn record_reindex_failure(
+    pool: &PgPool,
+    job_id: Uuid,
+    job_status: JobStatus,
+    reason: &str,
+) -> Result<()> {
+    let status = match job_status {
+        JobStatus::Dead => ReindexStatus::Failed,
+        JobStatus::Pending => ReindexStatus::Queued,
+        JobStatus::Running | JobStatus::Done => return Ok(()),
+    };
+
+    sqlx::query(
+        r#"
+    UPDATE reindex
+    SET status = $1, error = $2,
+        finished_at = CASE WHEN $1 = 'failed' THEN NOW() ELSE NULL END
+    WHERE job_id = $3
+    "#,
+    )
+    .bind(status.as_str())
+    .bind(reason)
+    .bind(job_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(start_ms: i32, end_ms: i32, speaker: i32) -> Segment {
+        Segment {
+            start_ms,
+            end_ms,
+            text: format!("{}-{}", start_ms, end_ms),
+            avg_probability: None,
+            speaker: Some(speaker),
+        }
+    }
+
+    #[test]
+    fn test_overlapping_segment() {
+        let segments = [
+            segment(0, 1000, 1),
+            segment(1000, 3000, 2),
+            segment(5000, 6000, 1),
+        ];
+        assert_eq!(
+            overlapping_segment(&segments, &segment(800, 2000, 0)),
+            Some(1)
+        );
+        assert_eq!(overlapping_segment(&segments, &segment(0, 900, 0)), Some(0));
+        // In the gap between segments, or only touching one
+        assert_eq!(
+            overlapping_segment(&segments, &segment(3200, 4800, 0)),
+            None
+        );
+        assert_eq!(
+            overlapping_segment(&segments, &segment(3000, 5000, 0)),
+            None
+        );
+        assert_eq!(overlapping_segment(&[], &segment(0, 1000, 0)), None);
+    }
+
+    #[test]
+    fn test_translated_speaker_texts() {
+        let segments = [segment(0, 1000, 1), segment(1000, 3000, 2)];
+        let translated = [
+            segment(0, 1200, 0),
+            segment(1200, 2900, 0),
+            // Overlaps no original segment, so it has no speaker
+            segment(4000, 5000, 0),
+        ];
+        let speakers = [1, 2]
+            .into_iter()
+            .map(|speaker| SpeakerTurns {
+                speaker,
+                talk_time_ms: 0,
+                text: String::new(),
+                emotional_tone: None,
+                name: None,
+                location: None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            translated_speaker_texts(&segments, &translated, &speakers),
+            vec!["0-1200".to_string(), "1200-2900".to_string()]
+        );
+    }
+}
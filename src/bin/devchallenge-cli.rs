@@ -0,0 +1,397 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use devchallenge::ai_config::AppState;
+use devchallenge::api::call::{fetch_call, insert_call, requested_language};
+use devchallenge::api::download::validate_audio_url;
+use devchallenge::api::models::CallStatus;
+use devchallenge::api::reindex::{enqueue_reindex, fetch_reindex};
+use devchallenge::api::worker::{Task, Workers};
+use devchallenge::db::{self, queue::JobStatus};
+use env_logger::Env;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const USAGE: &str = "usage:
+    devchallenge-cli ingest [--queue] [--language <code>] <audio file>...
+    devchallenge-cli ingest-urls [--queue] [--language <code>] <url list file | ->
+    devchallenge-cli reindex [--queue] (--all | <category id>...)
+    devchallenge-cli analyze [--queue] <call id>...
+    devchallenge-cli export [--status <status>] [--output <file>]
+    devchallenge-cli purge-tmp [--older-than <seconds>]
+    devchallenge-cli migrate <up | down [version] | status>
+
+Jobs run in this process unless --queue is given, which leaves them to the server's workers.";
+
+// Temp audio younger than this may still be in use by a running job
+const DEFAULT_PURGE_AGE_SECS: u64 = 24 * 60 * 60;
+
+#[actix_web::main]
+async fn main() -> Result<()> {
+    env_logger::init_from_env(Env::default().default_filter_or("warn"));
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, args)) = args.split_first() else {
+        bail!(USAGE);
+    };
+
+    let pool = db::establish_connection().await;
+    match command.as_str() {
+        "ingest" => ingest_files(&pool, Options::parse(args)?).await,
+        "ingest-urls" => ingest_urls(&pool, Options::parse(args)?).await,
+        "reindex" => reindex(&pool, Options::parse(args)?).await,
+        "analyze" => analyze(&pool, Options::parse(args)?).await,
+        "export" => export(&pool, Options::parse(args)?).await,
+        "purge-tmp" => purge_tmp(Options::parse(args)?),
+        "migrate" => db::migrate::run_command(&pool, args).await,
+        _ => bail!(USAGE),
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    queue: bool,
+    all: bool,
+    status: Option<String>,
+    output: Option<String>,
+    older_than: Option<u64>,
+    language: Option<String>,
+    positional: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| anyhow!("{} expects a value", arg))
+            };
+            match arg.as_str() {
+                "--queue" => options.queue = true,
+                "--all" => options.all = true,
+                "--status" => options.status = Some(value()?),
+                "--output" => options.output = Some(value()?),
+                "--language" => options.language = requested_language(Some(&value()?))?,
+                "--older-than" => {
+                    let secs = value()?;
+                    options.older_than = Some(
+                        secs.parse()
+                            .map_err(|_| anyhow!("--older-than expects seconds, got {}", secs))?,
+                    );
+                }
+                flag if flag.starts_with("--") => bail!("unknown option {}\n\n{}", flag, USAGE),
+                _ => options.positional.push(arg.clone()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+// Runs enqueued jobs in this process, or only reports them with --queue
+struct Runner {
+    pool: PgPool,
+    workers: Workers,
+    // Models are only loaded when jobs run here
+    app_state: Option<AppState>,
+    failed: usize,
+}
+
+impl Runner {
+    async fn new(pool: &PgPool, options: &Options) -> Self {
+        let app_state = if options.queue {
+            None
+        } else {
+            Some(AppState::new().await)
+        };
+        Self {
+            pool: pool.clone(),
+            workers: Workers::new(),
+            app_state,
+            failed: 0,
+        }
+    }
+
+    // Run the job unless it is left to the server. Returns the status of the job.
+    async fn run(&mut self, job_id: Uuid) -> Result<&'static str> {
+        let Some(app_state) = &self.app_state else {
+            return Ok("queued");
+        };
+        let status = self.workers.run_now(&self.pool, app_state, job_id).await?;
+        Ok(match status {
+            Some(JobStatus::Done) => "done",
+            Some(JobStatus::Pending) => {
+                self.failed += 1;
+                "failed, retry scheduled"
+            }
+            Some(JobStatus::Dead) => {
+                self.failed += 1;
+                "failed"
+            }
+            Some(JobStatus::Running) | None => "taken by another worker",
+        })
+    }
+
+    fn error(&mut self, subject: &str, err: anyhow::Error) {
+        self.failed += 1;
+        eprintln!("{}: {:#}", subject, err);
+    }
+
+    fn finish(self, total: usize) -> Result<()> {
+        match self.failed {
+            0 => Ok(()),
+            failed => Err(anyhow!("{} of {} failed", failed, total)),
+        }
+    }
+}
+
+// Create a call for every local audio file. The file is read when its job runs, so it has to
+// stay in place until then.
+async fn ingest_files(pool: &PgPool, options: Options) -> Result<()> {
+    if options.positional.is_empty() {
+        bail!(USAGE);
+    }
+
+    let mut runner = Runner::new(pool, &options).await;
+    for path in &options.positional {
+        let audio_url = std::fs::canonicalize(path)
+            .with_context(|| format!("failed to read {}", path))
+            .and_then(|path| {
+                reqwest::Url::from_file_path(&path)
+                    .map_err(|()| anyhow!("{} is not a valid file path", path.display()))
+            });
+        match audio_url {
+            Ok(audio_url) => ingest(&mut runner, path, audio_url.as_str(), &options).await,
+            Err(err) => runner.error(path, err),
+        }
+    }
+    runner.finish(options.positional.len())
+}
+
+// Create a call for every audio URL of a list, one per line. Blank lines and lines starting
+// with `#` are skipped.
+async fn ingest_urls(pool: &PgPool, options: Options) -> Result<()> {
+    let [list] = options.positional.as_slice() else {
+        bail!(USAGE);
+    };
+    let reader: Box<dyn BufRead> = match list.as_str() {
+        "-" => Box::new(BufReader::new(io::stdin())),
+        path => Box::new(BufReader::new(
+            File::open(path).with_context(|| format!("failed to open {}", path))?,
+        )),
+    };
+
+    let mut runner = Runner::new(pool, &options).await;
+    let mut total = 0;
+    for line in reader.lines() {
+        let line = line?;
+        let audio_url = line.trim();
+        if audio_url.is_empty() || audio_url.starts_with('#') {
+            continue;
+        }
+        total += 1;
+        // Same checks as POST /api/call
+        match validate_audio_url(audio_url).await {
+            Ok(()) => ingest(&mut runner, audio_url, audio_url, &options).await,
+            Err(err) => runner.error(audio_url, err.into()),
+        }
+    }
+    runner.finish(total)
+}
+
+// Prints `<source>\t<call id>\t<job status>`
+async fn ingest(runner: &mut Runner, source: &str, audio_url: &str, options: &Options) {
+    let result = async {
+        let mut tx = runner.pool.begin().await?;
+        let language = options.language.as_deref();
+        let (call_id, job_id) = insert_call(&mut tx, &runner.workers, audio_url, language).await?;
+        tx.commit().await?;
+        let status = runner.run(job_id).await?;
+        anyhow::Ok((call_id, status))
+    }
+    .await;
+
+    match result {
+        Ok((call_id, status)) => println!("{}\t{}\t{}", source, call_id, status),
+        Err(err) => runner.error(source, err),
+    }
+}
+
+// Re-run the whole pipeline for existing calls, replacing their stored analysis
+async fn analyze(pool: &PgPool, options: Options) -> Result<()> {
+    if options.positional.is_empty() {
+        bail!(USAGE);
+    }
+
+    let mut runner = Runner::new(pool, &options).await;
+    for id in &options.positional {
+        let result = async {
+            let call_id = Uuid::parse_str(id).map_err(|_| anyhow!("not a call id"))?;
+            // A language without probability was requested and stays so
+            let (audio_url, language) = sqlx::query_as::<_, (Option<String>, Option<String>)>(
+                r#"
+    SELECT audio_url, CASE WHEN language_probability IS NULL THEN language END
+    FROM call
+    WHERE id = $1
+    "#,
+            )
+            .bind(call_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| anyhow!("call not found"))?;
+            let audio_url = audio_url.ok_or_else(|| anyhow!("call has no audio to analyze"))?;
+
+            let mut tx = pool.begin().await?;
+            sqlx::query(
+                "UPDATE call SET status = $1, error = NULL, updated_at = NOW() WHERE id = $2",
+            )
+            .bind(CallStatus::Queued.as_str())
+            .bind(call_id)
+            .execute(&mut *tx)
+            .await?;
+            let task = Task::Call {
+                call_id,
+                audio_url,
+                language,
+            };
+            let job_id = runner.workers.enqueue(&mut *tx, &task).await?;
+            tx.commit().await?;
+            runner.run(job_id).await
+        }
+        .await;
+
+        match result {
+            Ok(status) => println!("{}\t{}", id, status),
+            Err(err) => runner.error(id, err),
+        }
+    }
+    runner.finish(options.positional.len())
+}
+
+// Reindex the given categories, or all of them, one after another
+async fn reindex(pool: &PgPool, options: Options) -> Result<()> {
+    let category_ids = match (options.all, options.positional.as_slice()) {
+        (true, []) => {
+            sqlx::query_scalar::<_, i32>("SELECT id FROM category ORDER BY id")
+                .fetch_all(pool)
+                .await?
+        }
+        (false, ids) if !ids.is_empty() => ids
+            .iter()
+            .map(|id| {
+                id.parse()
+                    .map_err(|_| anyhow!("{} is not a category id", id))
+            })
+            .collect::<Result<_>>()?,
+        _ => bail!(USAGE),
+    };
+
+    let mut runner = Runner::new(pool, &options).await;
+    for category_id in &category_ids {
+        let result = async {
+            let exists = sqlx::query_scalar::<_, bool>(
+                "SELECT EXISTS(SELECT 1 FROM category WHERE id = $1)",
+            )
+            .bind(category_id)
+            .fetch_one(pool)
+            .await?;
+            if !exists {
+                bail!("category not found");
+            }
+
+            let mut tx = pool.begin().await?;
+            let job_id =
+                enqueue_reindex(&mut tx, &runner.workers, Some(*category_id), None).await?;
+            tx.commit().await?;
+            runner.run(job_id).await?;
+            fetch_reindex(pool, job_id)
+                .await?
+                .ok_or_else(|| anyhow!("reindex {} disappeared", job_id))
+        }
+        .await;
+
+        match result {
+            // `<category id>\t<job id>\t<status>\t<processed>/<total>\t<changed>`
+            Ok(reindex) => println!(
+                "{}\t{}\t{}\t{}/{}\t{}",
+                category_id,
+                reindex.job_id,
+                reindex.status,
+                reindex.processed,
+                reindex.total,
+                reindex.changed
+            ),
+            Err(err) => runner.error(&category_id.to_string(), err),
+        }
+    }
+    runner.finish(category_ids.len())
+}
+
+// Write calls as JSON lines, in the shape returned by GET /api/call/{id}
+async fn export(pool: &PgPool, options: Options) -> Result<()> {
+    if !options.positional.is_empty() {
+        bail!(USAGE);
+    }
+    let mut out: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).with_context(|| format!("failed to create {}", path))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let ids = sqlx::query_scalar::<_, Uuid>(
+        "SELECT id FROM call WHERE $1::text IS NULL OR status = $1 ORDER BY created_at, id",
+    )
+    .bind(&options.status)
+    .fetch_all(pool)
+    .await?;
+
+    for id in ids {
+        // Deleted since the ids were listed
+        let Some(call) = fetch_call(pool, id).await? else {
+            continue;
+        };
+        serde_json::to_writer(&mut out, &call)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Delete downloaded audio left in the tmp folder by a process that died while decoding it
+fn purge_tmp(options: Options) -> Result<()> {
+    if !options.positional.is_empty() {
+        bail!(USAGE);
+    }
+    let max_age = Duration::from_secs(options.older_than.unwrap_or(DEFAULT_PURGE_AGE_SECS));
+    let dir = Path::new("./tmp");
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    let now = SystemTime::now();
+    let (mut removed, mut bytes) = (0, 0);
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or_default();
+        if !metadata.is_file() || age < max_age {
+            continue;
+        }
+        std::fs::remove_file(entry.path())
+            .with_context(|| format!("failed to remove {}", entry.path().display()))?;
+        removed += 1;
+        bytes += metadata.len();
+    }
+    println!("removed {} file(s), {} bytes", removed, bytes);
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use anyhow::{anyhow, Result};
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::PgPool;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// `devchallenge migrate <up|down|status>`
+pub async fn run_command(pool: &PgPool, args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("up") => up(pool).await,
+        Some("down") => {
+            let target = args
+                .get(1)
+                .map(|version| version.parse::<i64>())
+                .transpose()
+                .map_err(|_| anyhow!("target version must be a number"))?;
+            down(pool, target).await
+        }
+        Some("status") => status(pool).await,
+        _ => Err(anyhow!(
+            "usage: devchallenge migrate <up | down [version] | status>"
+        )),
+    }
+}
+
+// Apply every pending migration, the first run also seeds the default categories
+async fn up(pool: &PgPool) -> Result<()> {
+    MIGRATOR.run(pool).await?;
+    println!("database is up to date");
+    Ok(())
+}
+
+// Revert migrations newer than `target`, or only the latest one without a target
+async fn down(pool: &PgPool, target: Option<i64>) -> Result<()> {
+    let mut applied = applied_versions(pool).await?;
+    applied.sort_unstable();
+
+    let Some(&latest) = applied.last() else {
+        println!("no migration to revert");
+        return Ok(());
+    };
+    let target = target.unwrap_or_else(|| applied.iter().rev().nth(1).copied().unwrap_or(0));
+    if target >= latest {
+        println!("nothing to revert, latest applied migration is {}", latest);
+        return Ok(());
+    }
+
+    MIGRATOR.undo(pool, target).await?;
+    println!("reverted migrations after {}", target);
+    Ok(())
+}
+
+async fn status(pool: &PgPool) -> Result<()> {
+    let applied = applied_versions(pool).await?;
+    for migration in MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+    {
+        let state = if applied.contains(&migration.version) {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!(
+            "{:<16} {:<8} {}",
+            migration.version, state, migration.description
+        );
+    }
+    Ok(())
+}
+
+async fn applied_versions(pool: &PgPool) -> Result<Vec<i64>> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied = conn.list_applied_migrations().await?;
+    Ok(applied
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect())
+}
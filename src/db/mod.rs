@@ -1,5 +1,8 @@
+pub mod migrate;
+pub mod queue;
+
 use dotenv::dotenv;
-use sqlx::{postgres::PgPoolOptions, Executor};
+use sqlx::postgres::PgPoolOptions;
 use std::env;
 
 pub async fn establish_connection() -> sqlx::PgPool {
@@ -12,7 +15,9 @@ pub async fn establish_connection() -> sqlx::PgPool {
         .expect("Failed to connect to the database")
 }
 
-pub async fn prepare_db(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
-    pool.execute(include_str!("schema.sql")).await?;
+// Apply pending migrations. The default categories and their extraction schema are seeded by
+// migrations, so they are only inserted once.
+pub async fn prepare_db(pool: &sqlx::PgPool) -> anyhow::Result<()> {
+    migrate::MIGRATOR.run(pool).await?;
     Ok(())
 }
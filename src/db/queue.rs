@@ -0,0 +1,212 @@
+use serde::Serialize;
+use sqlx::types::Json;
+use sqlx::{PgPool, Postgres};
+use std::time::Duration;
+use uuid::Uuid;
+
+// Lifecycle of a row in the `job` table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Dead,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub max_attempts: i32,
+}
+
+// Insert a new job, usually inside the same transaction as the row it works on
+pub async fn enqueue<'e, E, T>(
+    executor: E,
+    kind: &str,
+    payload: &T,
+    max_attempts: i32,
+) -> Result<Uuid, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+    T: Serialize + Sync,
+{
+    sqlx::query_scalar::<_, Uuid>(
+        r#"
+    INSERT INTO job (id, kind, payload, status, max_attempts)
+    VALUES ($1, $2, $3, $4, $5)
+    RETURNING id
+    "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(kind)
+    .bind(Json(payload))
+    .bind(JobStatus::Pending.as_str())
+    .bind(max_attempts)
+    .fetch_one(executor)
+    .await
+}
+
+// Take the next runnable job. Jobs whose lock expired (the worker holding them died) are
+// picked up again while they have attempts left, and SKIP LOCKED lets several instances poll
+// the same table.
+pub async fn claim(
+    pool: &PgPool,
+    worker_id: &str,
+    visibility_timeout: Duration,
+) -> Result<Option<Job>, sqlx::Error> {
+    sqlx::query_as::<_, Job>(
+        r#"
+    UPDATE job
+    SET status = $1, attempts = attempts + 1, locked_by = $2,
+        locked_until = NOW() + make_interval(secs => $3), updated_at = NOW()
+    WHERE id = (
+        SELECT id FROM job
+        WHERE (status = $4 AND run_at <= NOW())
+           OR (status = $1 AND locked_until < NOW() AND attempts < max_attempts)
+        ORDER BY run_at
+        FOR UPDATE SKIP LOCKED
+        LIMIT 1
+    )
+    RETURNING id, kind, payload, attempts, max_attempts
+    "#,
+    )
+    .bind(JobStatus::Running.as_str())
+    .bind(worker_id)
+    .bind(visibility_timeout.as_secs_f64())
+    .bind(JobStatus::Pending.as_str())
+    .fetch_optional(pool)
+    .await
+}
+
+// Take a specific pending job regardless of its `run_at`. Returns None when another worker
+// holds it or it already finished.
+pub async fn claim_job(
+    pool: &PgPool,
+    job_id: Uuid,
+    worker_id: &str,
+    visibility_timeout: Duration,
+) -> Result<Option<Job>, sqlx::Error> {
+    sqlx::query_as::<_, Job>(
+        r#"
+    UPDATE job
+    SET status = $1, attempts = attempts + 1, locked_by = $2,
+        locked_until = NOW() + make_interval(secs => $3), updated_at = NOW()
+    WHERE id = $4 AND status = $5
+    RETURNING id, kind, payload, attempts, max_attempts
+    "#,
+    )
+    .bind(JobStatus::Running.as_str())
+    .bind(worker_id)
+    .bind(visibility_timeout.as_secs_f64())
+    .bind(job_id)
+    .bind(JobStatus::Pending.as_str())
+    .fetch_optional(pool)
+    .await
+}
+
+// Dead-letter the jobs whose lock expired on their last attempt. A job that kills the worker
+// running it (an abort in libtorch, running out of memory) would otherwise stay `running`.
+pub async fn dead_letter_expired(pool: &PgPool, error: &str) -> Result<Vec<Job>, sqlx::Error> {
+    sqlx::query_as::<_, Job>(
+        r#"
+    UPDATE job
+    SET status = $1, last_error = $2, locked_by = NULL, locked_until = NULL, updated_at = NOW()
+    WHERE status = $3 AND locked_until < NOW() AND attempts >= max_attempts
+    RETURNING id, kind, payload, attempts, max_attempts
+    "#,
+    )
+    .bind(JobStatus::Dead.as_str())
+    .bind(error)
+    .bind(JobStatus::Running.as_str())
+    .fetch_all(pool)
+    .await
+}
+
+// Extend the lock of a job that is still being worked on
+pub async fn heartbeat(
+    pool: &PgPool,
+    job_id: Uuid,
+    worker_id: &str,
+    visibility_timeout: Duration,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+    UPDATE job
+    SET locked_until = NOW() + make_interval(secs => $1), updated_at = NOW()
+    WHERE id = $2 AND locked_by = $3 AND status = $4
+    "#,
+    )
+    .bind(visibility_timeout.as_secs_f64())
+    .bind(job_id)
+    .bind(worker_id)
+    .bind(JobStatus::Running.as_str())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn complete(pool: &PgPool, job_id: Uuid, worker_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+    UPDATE job
+    SET status = $1, locked_by = NULL, locked_until = NULL, last_error = NULL, updated_at = NOW()
+    WHERE id = $2 AND locked_by = $3
+    "#,
+    )
+    .bind(JobStatus::Done.as_str())
+    .bind(job_id)
+    .bind(worker_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Schedule a retry after `backoff`, or move the job to the dead-letter state once it ran out
+// of attempts. Without a backoff the job is dead-lettered right away. Returns the new status.
+pub async fn fail(
+    pool: &PgPool,
+    job_id: Uuid,
+    worker_id: &str,
+    error: &str,
+    backoff: Option<Duration>,
+) -> Result<JobStatus, sqlx::Error> {
+    let dead = sqlx::query_scalar::<_, bool>(
+        r#"
+    UPDATE job
+    SET status = CASE WHEN $3 IS NULL OR attempts >= max_attempts THEN $1 ELSE $2 END,
+        run_at = NOW() + make_interval(secs => COALESCE($3, 0)),
+        last_error = $4, locked_by = NULL, locked_until = NULL, updated_at = NOW()
+    WHERE id = $5 AND locked_by = $6
+    RETURNING status = $1
+    "#,
+    )
+    .bind(JobStatus::Dead.as_str())
+    .bind(JobStatus::Pending.as_str())
+    .bind(backoff.map(|backoff| backoff.as_secs_f64()))
+    .bind(error)
+    .bind(job_id)
+    .bind(worker_id)
+    .fetch_optional(pool)
+    .await?;
+
+    match dead {
+        Some(true) => Ok(JobStatus::Dead),
+        Some(false) => Ok(JobStatus::Pending),
+        // Lock was lost to another worker, which now owns the job
+        None => Ok(JobStatus::Running),
+    }
+}
@@ -1,4 +1,5 @@
 use actix_web::{error::ResponseError, HttpResponse};
+use std::any::Any;
 
 pub type AppResult<T> = std::result::Result<T, AppError>;
 
@@ -8,6 +9,13 @@ pub enum AppError {
     SqlxError(#[from] sqlx::Error),
     #[error("Any error: {0:?}")]
     Anyhow(#[from] anyhow::Error),
+    #[error(transparent)]
+    Upload(#[from] UploadError),
+    #[error(transparent)]
+    Download(#[from] DownloadError),
+    // A request field with an unusable value
+    #[error("{0}")]
+    Invalid(String),
 }
 
 impl ResponseError for AppError {
@@ -15,6 +23,124 @@ impl ResponseError for AppError {
         match self {
             Self::SqlxError(err) => HttpResponse::InternalServerError().json(err.to_string()),
             Self::Anyhow(err) => HttpResponse::InternalServerError().json(err.to_string()),
+            Self::Upload(err @ UploadError::TooLarge(_)) => {
+                HttpResponse::PayloadTooLarge().json(err.to_string())
+            }
+            Self::Upload(err @ UploadError::Invalid(_)) => {
+                HttpResponse::UnprocessableEntity().json(err.to_string())
+            }
+            Self::Upload(err @ UploadError::Io(_)) => {
+                HttpResponse::InternalServerError().json(err.to_string())
+            }
+            // Only raised while validating a submitted URL
+            Self::Download(err) => HttpResponse::UnprocessableEntity().json(err.to_string()),
+            Self::Invalid(err) => HttpResponse::UnprocessableEntity().json(err),
+        }
+    }
+}
+
+// Audio uploaded with POST /api/call that couldn't be stored
+#[derive(thiserror::Error, Debug)]
+pub enum UploadError {
+    #[error("audio is larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("failed to store audio: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// Why an audio URL was refused or its download failed
+#[derive(thiserror::Error, Debug)]
+pub enum DownloadError {
+    #[error("invalid audio URL: {0}")]
+    InvalidUrl(String),
+    #[error("audio URL is blocked: {0}")]
+    Blocked(String),
+    #[error("more than {0} redirects")]
+    TooManyRedirects(usize),
+    #[error("server responded with status {0}")]
+    Status(u16),
+    #[error("unsupported content type {0}")]
+    ContentType(String),
+    #[error("not a WAV, MP3, OGG, FLAC or M4A file")]
+    UnknownFormat,
+    #[error("audio is larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("download took longer than {0} seconds")]
+    TimedOut(u64),
+    #[error("request failed: {0}")]
+    Request(reqwest::Error),
+    #[error("failed to store audio: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl DownloadError {
+    // Failures that may go away on their own
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Status(status) => *status >= 500 || *status == 408 || *status == 429,
+            Self::TimedOut(_) | Self::Request(_) => true,
+            _ => false,
+        }
+    }
+}
+
+// The redirect policy and the DNS resolver report their errors through reqwest
+impl From<reqwest::Error> for DownloadError {
+    fn from(err: reqwest::Error) -> Self {
+        let mut source = std::error::Error::source(&err);
+        while let Some(inner) = source {
+            match inner.downcast_ref::<DownloadError>() {
+                Some(Self::InvalidUrl(reason)) => return Self::InvalidUrl(reason.clone()),
+                Some(Self::Blocked(reason)) => return Self::Blocked(reason.clone()),
+                Some(Self::TooManyRedirects(max)) => return Self::TooManyRedirects(*max),
+                _ => {}
+            }
+            source = inner.source();
+        }
+        Self::Request(err)
+    }
+}
+
+// Audio that couldn't be turned into samples for transcription
+#[derive(thiserror::Error, Debug)]
+pub enum DecodeError {
+    #[error("unrecognized audio container, supported are WAV, MP3, OGG, FLAC and M4A")]
+    UnknownFormat,
+    #[error("file has no audio track")]
+    NoAudioTrack,
+    #[error("unsupported audio codec {0}")]
+    UnsupportedCodec(String),
+    #[error("audio is corrupt: {0}")]
+    Corrupt(String),
+    #[error("audio contains no samples")]
+    Empty,
+    #[error("audio is longer than {0} seconds")]
+    TooLong(u64),
+    #[error("failed to read audio: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// A panic caught while running a model or a job. The same input would panic again, so jobs
+// failing with it are not retried.
+#[derive(thiserror::Error, Debug)]
+#[error("{context} panicked: {message}")]
+pub struct PanicError {
+    pub context: String,
+    pub message: String,
+}
+
+impl PanicError {
+    pub fn new(context: impl Into<String>, panic: &(dyn Any + Send)) -> Self {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+        Self {
+            context: context.into(),
+            message,
         }
     }
 }
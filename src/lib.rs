@@ -0,0 +1,5 @@
+pub mod ai_config;
+pub mod api;
+pub mod config;
+pub mod db;
+pub mod errors;
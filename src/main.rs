@@ -1,25 +1,36 @@
-mod ai_config;
-mod api;
-mod db;
-mod errors;
-
 use actix_files::Files;
 use actix_web::{middleware, web, App, HttpServer};
+use devchallenge::{ai_config, api, config, db};
 use env_logger::Env;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(Env::default().default_filter_or("debug"));
     let pool = db::establish_connection().await;
-    db::prepare_db(&pool).await;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        return db::migrate::run_command(&pool, &args[1..])
+            .await
+            .map_err(std::io::Error::other);
+    }
+
+    // Deployments rolling out schema changes separately run `devchallenge migrate up` instead
+    if config::env_or("AUTO_MIGRATE", true) {
+        db::prepare_db(&pool).await.map_err(std::io::Error::other)?;
+    }
     let app_state = ai_config::AppState::new().await;
+    let workers = api::worker::Workers::start(pool.clone(), app_state.clone());
+    let events = api::events::EventHub::start(pool.clone());
     let application = move || {
         App::new()
             .wrap(middleware::Logger::default())
-            .service(Files::new("/tmp", "./tmp").show_files_listing())
             .service(Files::new("/models", "./models").show_files_listing())
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(app_state.clone()))
+            .app_data(web::Data::new(workers.clone()))
+            .app_data(web::Data::new(events.clone()))
+            .app_data(web::Data::new(api::webhooks::WebhookConfig::from_env()))
             .configure(api::config)
     };
 